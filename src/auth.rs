@@ -0,0 +1,404 @@
+//! Client authentication and role based authorization
+//!
+//! Clients authenticate with `AUTH <token>`, or over TLS with a client certificate when
+//! the server verifies them. Both are read from a yaml file:
+//! ```yaml
+//! tokens:
+//!   - name: alice
+//!     token: "s3cr3t-of-16-chars-or-more"
+//!     role: admin
+//!   - name: ci
+//!     # SHA-256 fingerprint, `openssl x509 -noout -fingerprint -sha256 -in ci.pem`
+//!     certificate: "3A:9F:...:C2"
+//!     role: operator
+//! ```
+//! A client with a known certificate is authenticated as soon as it connects, it may
+//! still switch to another principal with `AUTH`.
+
+use std::fmt;
+
+use ring::digest;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::command::ClientCommand;
+use crate::Result;
+
+/// Permission level of a client, each role includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Read tasks, runs and logs
+    Viewer,
+    /// Trigger and drain existing tasks
+    Operator,
+    /// Add and kill tasks
+    Admin,
+}
+
+/// Authenticated client
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Token and certificate of a client, at least one of them
+struct Credentials {
+    token: Option<String>,
+    /// SHA-256 of the certificate
+    certificate: Option<Vec<u8>>,
+    principal: Principal,
+}
+
+/// Known client tokens and certificates
+pub struct Auth {
+    clients: Vec<Credentials>,
+}
+
+impl Role {
+    pub(crate) fn from_str(data: &str) -> Result<Self> {
+        match data.trim() {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            r => Err(format!("invalid role: {} (viewer | operator | admin)", r).into()),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Operator => write!(f, "operator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl Principal {
+    /// Principal of clients when authentication is disabled
+    pub fn anonymous() -> Self {
+        Principal {
+            name: "anonymous".to_string(),
+            role: Role::Admin,
+        }
+    }
+}
+
+impl Auth {
+    /// Load tokens from a yaml file, see module docs for the format
+    pub fn from_file(path: &str) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read tokens file {}: {}", path, e))?;
+
+        Auth::from_yaml_str(&data)
+    }
+
+    pub fn from_yaml_str(data: &str) -> Result<Self> {
+        let docs = YamlLoader::load_from_str(data)?;
+
+        let entries = match docs.first().map(|d| &d["tokens"]) {
+            Some(t) => t.as_vec().ok_or("tokens must be a list")?,
+            None => return Err("no tokens provided".into()),
+        };
+
+        let mut clients: Vec<Credentials> = Vec::new();
+
+        for entry in entries {
+            let name = entry["name"].as_str().ok_or("token without name")?;
+            let role = Role::from_str(entry["role"].as_str().unwrap_or_default())?;
+
+            let token = match &entry["token"] {
+                Yaml::BadValue => None,
+                Yaml::String(token) if token.len() >= 16 => Some(token.clone()),
+                Yaml::String(_) => {
+                    return Err(format!("token of '{}' is shorter than 16 characters", name).into())
+                }
+                t => return Err(format!("invalid token of '{}': {:?}", name, t).into()),
+            };
+
+            let certificate = match &entry["certificate"] {
+                Yaml::BadValue => None,
+                Yaml::String(fingerprint) => {
+                    Some(parse_fingerprint(fingerprint).ok_or(format!(
+                        "invalid certificate of '{}', expected a SHA-256 fingerprint",
+                        name
+                    ))?)
+                }
+                c => return Err(format!("invalid certificate of '{}': {:?}", name, c).into()),
+            };
+
+            if token.is_none() && certificate.is_none() {
+                return Err(format!("no token or certificate provided for '{}'", name).into());
+            }
+
+            if token.is_some() && clients.iter().any(|c| c.token == token) {
+                return Err(format!("token of '{}' is not unique", name).into());
+            }
+
+            if certificate.is_some() && clients.iter().any(|c| c.certificate == certificate) {
+                return Err(format!("certificate of '{}' is not unique", name).into());
+            }
+
+            clients.push(Credentials {
+                token,
+                certificate,
+                principal: Principal {
+                    name: name.to_string(),
+                    role,
+                },
+            });
+        }
+
+        Ok(Auth { clients })
+    }
+
+    /// Find the principal owning `token`
+    pub fn authenticate(&self, token: &str) -> Option<Principal> {
+        self.clients
+            .iter()
+            .find(|c| {
+                c.token
+                    .as_ref()
+                    .is_some_and(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+            })
+            .map(|c| c.principal.clone())
+    }
+
+    /// Find the principal owning the certificate `der`, verified by the TLS handshake
+    pub fn authenticate_certificate(&self, der: &[u8]) -> Option<Principal> {
+        let fingerprint = digest::digest(&digest::SHA256, der);
+
+        self.clients
+            .iter()
+            .find(|c| c.certificate.as_deref() == Some(fingerprint.as_ref()))
+            .map(|c| c.principal.clone())
+    }
+}
+
+/// Check that `principal` may run `command`
+pub fn authorize(principal: Option<&Principal>, command: &ClientCommand) -> Result<()> {
+    let required = match command.required_role() {
+        Some(role) => role,
+        None => return Ok(()),
+    };
+
+    match principal {
+        Some(p) if p.role >= required => Ok(()),
+        Some(p) => Err(format!(
+            "permission denied: '{}' has role {}, {} required",
+            p.name, p.role, required
+        )
+        .into()),
+        None => Err("authentication required, use AUTH {token}".into()),
+    }
+}
+
+/// Bytes of a hex SHA-256 fingerprint, with or without `:` separators
+fn parse_fingerprint(data: &str) -> Option<Vec<u8>> {
+    let hex: Vec<u8> = data.bytes().filter(|b| *b != b':').collect();
+
+    if hex.len() != 64 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Compare without exiting early, so the time taken does not leak the secret
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl ClientCommand {
+    /// Minimal role needed to run the command, `None` if allowed unauthenticated
+    pub fn required_role(&self) -> Option<Role> {
+        match self {
+            ClientCommand::Auth(_)
+            | ClientCommand::Help
+            | ClientCommand::Noop
+            | ClientCommand::Error(_)
+            | ClientCommand::Exit => None,
+            ClientCommand::List
+            | ClientCommand::Show(_)
+            | ClientCommand::Logs(_)
+            | ClientCommand::History(_, _)
+            | ClientCommand::Datasets
+            | ClientCommand::Validate(_) => Some(Role::Viewer),
+            ClientCommand::Drain(_)
+            | ClientCommand::Pause(_)
+            | ClientCommand::Resume(..)
+            | ClientCommand::Trigger(..)
+            | ClientCommand::Backfill(..) => Some(Role::Operator),
+            ClientCommand::Add(_) | ClientCommand::Kill(_) => Some(Role::Admin),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKENS: &str = "
+tokens:
+  - name: alice
+    token: alice-0123456789abcdef
+    role: admin
+  - name: bob
+    token: bob-0123456789abcdef
+    role: viewer
+  - name: ci
+    certificate: 3a:9f00112233445566778899aabbccddeeff00112233445566778899AABBCCDD
+    role: operator
+";
+
+    fn command(line: &str) -> ClientCommand {
+        ClientCommand::parse(line, None).unwrap()
+    }
+
+    #[test]
+    fn tokens() {
+        let auth = Auth::from_yaml_str(TOKENS).unwrap();
+
+        let alice = auth.authenticate("alice-0123456789abcdef").unwrap();
+        assert_eq!((alice.name.as_str(), alice.role), ("alice", Role::Admin));
+        let bob = auth.authenticate("bob-0123456789abcdef").unwrap();
+        assert_eq!((bob.name.as_str(), bob.role), ("bob", Role::Viewer));
+
+        for token in [
+            "",
+            "alice",
+            "alice-0123456789abcdeF",
+            "alice-0123456789abcdef ",
+        ] {
+            assert!(auth.authenticate(token).is_none(), "{}", token);
+        }
+    }
+
+    #[test]
+    fn certificates() {
+        let der = b"not really a certificate";
+        let fingerprint: String = digest::digest(&digest::SHA256, der)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+
+        let auth = Auth::from_yaml_str(&format!(
+            "tokens:\n- name: ci\n  certificate: \"{}\"\n  role: operator",
+            fingerprint
+        ))
+        .unwrap();
+
+        let ci = auth.authenticate_certificate(der).unwrap();
+        assert_eq!((ci.name.as_str(), ci.role), ("ci", Role::Operator));
+        assert!(auth
+            .authenticate_certificate(b"another certificate")
+            .is_none());
+        // Certificates are no tokens
+        assert!(auth.authenticate(&fingerprint).is_none());
+
+        assert_eq!(
+            parse_fingerprint(&fingerprint.to_lowercase().replace(':', "")).unwrap(),
+            digest::digest(&digest::SHA256, der).as_ref()
+        );
+    }
+
+    #[test]
+    fn invalid_tokens() {
+        for data in [
+            "",
+            "tokens: alice",
+            "tokens:\n- token: alice-0123456789abcdef\n  role: admin",
+            "tokens:\n- name: alice\n  role: admin",
+            "tokens:\n- name: alice\n  token: short\n  role: admin",
+            "tokens:\n- name: alice\n  token: 1234567890123456789\n  role: admin",
+            "tokens:\n- name: alice\n  token: alice-0123456789abcdef\n  role: root",
+            "tokens:\n- name: alice\n  token: alice-0123456789abcdef",
+            "tokens:\n- name: alice\n  token: alice-0123456789abcdef\n  role: admin\n\
+             - name: eve\n  token: alice-0123456789abcdef\n  role: viewer",
+            "tokens:\n- name: ci\n  certificate: 3a:9f\n  role: admin",
+            "tokens:\n- name: ci\n  certificate: +a9f00112233445566778899aabbccddeeff00112233445566778899aabbccdd\n  role: admin",
+        ] {
+            assert!(Auth::from_yaml_str(data).is_err(), "{}", data);
+        }
+
+        let duplicate = format!(
+            "{}  - name: eve\n    certificate: 3A9F00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDD\n    role: admin\n",
+            TOKENS
+        );
+        assert!(Auth::from_yaml_str(&duplicate).is_err());
+    }
+
+    #[test]
+    fn constant_time_compare() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn roles() {
+        assert!(Role::Viewer < Role::Operator && Role::Operator < Role::Admin);
+        for role in [Role::Viewer, Role::Operator, Role::Admin] {
+            assert_eq!(Role::from_str(&role.to_string()).unwrap(), role);
+        }
+        assert!(Role::from_str("Admin").is_err());
+
+        for (line, role) in [
+            ("AUTH token", None),
+            ("HELP", None),
+            ("", None),
+            ("LIST", Some(Role::Viewer)),
+            ("SHOW load", Some(Role::Viewer)),
+            ("LOGS load", Some(Role::Viewer)),
+            ("DATASETS", Some(Role::Viewer)),
+            ("PAUSE load", Some(Role::Operator)),
+            ("RESUME load", Some(Role::Operator)),
+            ("DRAIN load", Some(Role::Operator)),
+            ("TRIGGER load", Some(Role::Operator)),
+            ("BACKFILL load 2024-01-01 2024-01-02", Some(Role::Operator)),
+            ("ADD load interval:1h true", Some(Role::Admin)),
+            ("KILL load", Some(Role::Admin)),
+        ] {
+            assert_eq!(command(line).required_role(), role, "{}", line);
+        }
+    }
+
+    #[test]
+    fn authorization() {
+        let principal = |role| Principal {
+            name: "p".to_string(),
+            role,
+        };
+
+        assert!(authorize(None, &command("HELP")).is_ok());
+        assert!(authorize(None, &command("LIST"))
+            .unwrap_err()
+            .to_string()
+            .starts_with("authentication required"));
+
+        let viewer = principal(Role::Viewer);
+        assert!(authorize(Some(&viewer), &command("LIST")).is_ok());
+        assert_eq!(
+            authorize(Some(&viewer), &command("TRIGGER load"))
+                .unwrap_err()
+                .to_string(),
+            "permission denied: 'p' has role viewer, operator required"
+        );
+
+        let operator = principal(Role::Operator);
+        assert!(authorize(Some(&operator), &command("TRIGGER load")).is_ok());
+        assert!(authorize(Some(&operator), &command("KILL load")).is_err());
+        assert!(authorize(Some(&principal(Role::Admin)), &command("KILL load")).is_ok());
+    }
+}
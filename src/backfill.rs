@@ -0,0 +1,144 @@
+//! Re-running a task for the points of its schedule in a past date range
+
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::task::{ScheduleType, Task, TaskId, TaskInstance};
+use crate::Result;
+
+/// How to run a backfill, see [`crate::scheduler::Scheduler::backfill_task`]
+#[derive(Debug, Clone)]
+pub struct BackfillOptions {
+    /// First logical date, inclusive
+    pub from: SystemTime,
+    /// Last logical date, inclusive
+    pub to: SystemTime,
+    /// Maximum number of runs of the backfill queued or running at a time
+    pub parallelism: usize,
+    /// Also run the down-stream chain of each run, for the same logical date
+    pub downstream: bool,
+}
+
+/// Backfill in progress
+#[derive(Debug, Clone)]
+pub struct Backfill {
+    pub id: String,
+    pub task_id: TaskId,
+    pub parallelism: usize,
+    /// Runs not released to the queue yet, oldest logical date first
+    pub pending: VecDeque<TaskInstance>,
+    /// Runs released to the queue that have not finished
+    pub active: usize,
+}
+
+/// Upper bound of runs a single backfill may create
+pub const MAX_RUNS: usize = 10_000;
+
+/// Points of the schedule of `task` between `from` and `to`, both inclusive.
+/// Interval schedules are aligned to the task's `origin` or `start_time`, or to `from` without
+/// either. Cron and business day schedules run at their points in the task's time zone.
+/// Points on days skipped by the task's calendar are left out.
+pub fn logical_dates(task: &Task, from: SystemTime, to: SystemTime) -> Result<Vec<SystemTime>> {
+    let mut dates = schedule_points(task, from, to)?;
+    dates.retain(|d| !task.skips(*d));
+    Ok(dates)
+}
+
+fn schedule_points(task: &Task, from: SystemTime, to: SystemTime) -> Result<Vec<SystemTime>> {
+    if from > to {
+        return Err("start of the range is after its end".into());
+    }
+
+    let too_many = || format!("backfill would create more than {} runs", MAX_RUNS).into();
+
+    let interval = match &task.schedule {
+        ScheduleType::Interval(d) if !d.is_zero() => *d,
+        ScheduleType::Cron(cron) => {
+            let dates = cron.points(from, to + Duration::from_nanos(1), task.timezone);
+
+            return match dates.len() > MAX_RUNS {
+                true => Err(too_many()),
+                false => Ok(dates),
+            };
+        }
+        ScheduleType::BusinessDay(..) => {
+            let mut dates = Vec::new();
+            let mut date = task.align(from);
+
+            while let Some(d) = date.filter(|d| *d <= to) {
+                if dates.len() >= MAX_RUNS {
+                    return Err(too_many());
+                }
+                dates.push(d);
+                date = task.next_point(d, d);
+            }
+
+            return Ok(dates);
+        }
+        _ => {
+            return Err(format!(
+                "task '{}' has no periodic schedule to backfill",
+                task.task_id
+            )
+            .into())
+        }
+    };
+
+    let anchor = task.origin.or(task.start_time).unwrap_or(from);
+
+    // First point at or after `from`, points before `start_time` keep its phase
+    let slots = |d: Duration, round_up: bool| -> Result<u32> {
+        let n = match round_up {
+            true => d.as_nanos().div_ceil(interval.as_nanos()),
+            false => d.as_nanos() / interval.as_nanos(),
+        };
+        u32::try_from(n).map_err(|_| "range too far from start_time".into())
+    };
+
+    let mut date = match from.duration_since(anchor) {
+        Ok(behind) => anchor + interval * slots(behind, true)?,
+        Err(e) => anchor - interval * slots(e.duration(), false)?,
+    };
+
+    let mut dates = Vec::new();
+
+    while date <= to {
+        if dates.len() >= MAX_RUNS {
+            return Err(too_many());
+        }
+
+        dates.push(date);
+        date += interval;
+    }
+
+    Ok(dates)
+}
+
+impl Backfill {
+    pub fn to_yaml(&self) -> Yaml {
+        let mut h = Hash::new();
+
+        h.insert(Yaml::String("id".into()), Yaml::String(self.id.clone()));
+        h.insert(
+            Yaml::String("task_id".into()),
+            Yaml::String(self.task_id.clone()),
+        );
+        h.insert(
+            Yaml::String("parallelism".into()),
+            Yaml::Integer(self.parallelism as i64),
+        );
+        h.insert(
+            Yaml::String("active".into()),
+            Yaml::Integer(self.active as i64),
+        );
+        h.insert(
+            Yaml::String("pending".into()),
+            Yaml::Integer(self.pending.len() as i64),
+        );
+
+        Yaml::Hash(h)
+    }
+}
@@ -1,42 +1,322 @@
-use tokio::{
-    io::{stdin, AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, ToSocketAddrs},
-};
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-pub struct Client {
-    connection: TcpStream,
+use chainz::client::{Client, ClientConfig};
+use chainz::command::expects_body;
+use chainz::output::{render, OutputFormat};
+use chainz::protocol::Request;
+use chainz::repl::Repl;
+use chainz::scheduler::ResumePolicy;
+use chainz::task::validate_param_name;
+use chainz::tls::ClientTlsConfig;
+use chainz::Result;
+use clap::{Parser, Subcommand};
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+
+/// Control a running chainz server.
+/// Without a subcommand an interactive shell is started, or commands are
+/// read from stdin if it is not a terminal.
+#[derive(Parser)]
+#[command(name = "chainz_cli")]
+struct Cli {
+    /// Address of the chainz server, `host:port` or `unix:<path>`
+    #[arg(
+        short,
+        long,
+        env = "CHAINZ_SERVER",
+        default_value = "127.0.0.1:3333",
+        global = true
+    )]
+    server: String,
+
+    /// Token to authenticate with
+    #[arg(
+        short,
+        long,
+        env = "CHAINZ_TOKEN",
+        hide_env_values = true,
+        global = true
+    )]
+    token: Option<String>,
+
+    /// Connect over TLS, trusting server certificates signed by this ca (PEM)
+    #[arg(long, env = "CHAINZ_TLS_CA", global = true)]
+    tls_ca: Option<PathBuf>,
+
+    /// Client certificate (PEM), for servers verifying clients
+    #[arg(long, env = "CHAINZ_TLS_CERT", requires_all = ["tls_ca", "tls_key"], global = true)]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of the client certificate (PEM)
+    #[arg(long, env = "CHAINZ_TLS_KEY", requires = "tls_cert", global = true)]
+    tls_key: Option<PathBuf>,
+
+    /// Name to verify the server certificate against, defaults to the server host
+    #[arg(long, requires = "tls_ca", global = true)]
+    tls_server_name: Option<String>,
+
+    /// Format of listings: table | json | yaml
+    #[arg(short, long, default_value_t = OutputFormat::Table, global = true)]
+    output: OutputFormat,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Cli {
+    fn client_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new(&self.server);
+
+        config.token = self.token.clone();
+        config.tls = self.tls_ca.as_ref().map(|ca| ClientTlsConfig {
+            ca: ca.clone(),
+            identity: self.tls_cert.clone().zip(self.tls_key.clone()),
+            server_name: self.tls_server_name.clone(),
+        });
+
+        config
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Add and schedule a new task
+    Add {
+        /// Yaml task definition, instead of <TASK_ID> <SCHEDULE> <CMD>
+        #[arg(short, long, conflicts_with_all = ["task_id", "schedule", "cmd"])]
+        file: Option<PathBuf>,
+        #[arg(required_unless_present = "file")]
+        task_id: Option<String>,
+        /// once | dstream:<task_id> | interval:<Xn|s|m|h|d> | @hourly | @daily | @weekly |
+        /// @monthly | @yearly | datasets:<name>[,<name>...], full cron expressions need --file
+        #[arg(required_unless_present = "file")]
+        schedule: Option<String>,
+        #[arg(required_unless_present = "file", trailing_var_arg = true)]
+        cmd: Vec<String>,
+    },
+    /// List tasks
+    List,
+    /// Show a task and its queued instances
+    Show { task_id: String },
+    /// Stop scheduling a task after its next successful run
+    Drain { task_id: String },
+    /// Kill and remove a task from the schedule
+    Kill { task_id: String },
+    /// Stop running scheduled instances of a task, keeping it
+    Pause { task_id: String },
+    /// Resume a paused task
+    Resume {
+        task_id: String,
+        /// What to do with missed runs: skip | catchup
+        #[arg(long, default_value_t = ResumePolicy::Skip)]
+        policy: ResumePolicy,
+    },
+    /// Run a task now, outside of its schedule
+    Trigger {
+        task_id: String,
+        /// Override a task parameter, repeatable
+        #[arg(short, long = "param", value_name = "KEY=VALUE", value_parser = param)]
+        params: Vec<String>,
+        /// Also trigger the down-stream chain once the task succeeds
+        #[arg(long)]
+        downstream: bool,
+    },
+    /// Run a task for each point of its schedule in a date range
+    Backfill {
+        task_id: String,
+        /// First logical date, e.g. `2023-01-01`, inclusive
+        from: String,
+        /// Last logical date, inclusive
+        to: String,
+        /// Maximum number of runs at a time
+        #[arg(short = 'j', long, default_value_t = 1)]
+        parallelism: usize,
+        /// Also run the down-stream chain of each run
+        #[arg(long)]
+        downstream: bool,
+    },
+    /// Show the output of a task run
+    Logs { instance_id: String },
+    /// Show past task runs, newest first
+    History {
+        task_id: Option<String>,
+        /// Maximum number of runs to show
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+    },
+    /// List datasets, their latest update and the tasks consuming them
+    Datasets,
+    /// Validate a yaml task definition without adding it
+    Validate { file: PathBuf },
+}
+
+impl Command {
+    fn to_request(&self) -> Result<Request> {
+        let request = match self {
+            Command::Add {
+                file: Some(file), ..
+            } => Request::with_body("ADD", &std::fs::read_to_string(file)?),
+            Command::Add {
+                task_id,
+                schedule,
+                cmd,
+                ..
+            } => Request::new(&format!(
+                "ADD {} {} {}",
+                task_id.as_deref().unwrap_or_default(),
+                schedule.as_deref().unwrap_or_default(),
+                cmd.join(" ")
+            )),
+            Command::List => Request::new("LIST"),
+            Command::Show { task_id } => Request::new(&format!("SHOW {}", task_id)),
+            Command::Drain { task_id } => Request::new(&format!("DRAIN {}", task_id)),
+            Command::Kill { task_id } => Request::new(&format!("KILL {}", task_id)),
+            Command::Pause { task_id } => Request::new(&format!("PAUSE {}", task_id)),
+            Command::Resume { task_id, policy } => {
+                Request::new(&format!("RESUME {} {}", task_id, policy))
+            }
+            Command::Trigger {
+                task_id,
+                params,
+                downstream,
+            } => {
+                let mut line = format!("TRIGGER {}", task_id);
+                if *downstream {
+                    line.push_str(" --downstream");
+                }
+                for p in params {
+                    line.push_str(&format!(" {}", p));
+                }
+                Request::new(&line)
+            }
+            Command::Backfill {
+                task_id,
+                from,
+                to,
+                parallelism,
+                downstream,
+            } => {
+                let mut line = format!(
+                    "BACKFILL {} {} {} --parallelism {}",
+                    task_id, from, to, parallelism
+                );
+                if *downstream {
+                    line.push_str(" --downstream");
+                }
+                Request::new(&line)
+            }
+            Command::Logs { instance_id } => Request::new(&format!("LOGS {}", instance_id)),
+            Command::History { task_id, limit } => {
+                let mut line = format!("HISTORY {}", task_id.as_deref().unwrap_or("*"));
+                if let Some(n) = limit {
+                    line.push_str(&format!(" {}", n));
+                }
+                Request::new(&line)
+            }
+            Command::Datasets => Request::new("DATASETS"),
+            Command::Validate { file } => {
+                Request::with_body("VALIDATE", &std::fs::read_to_string(file)?)
+            }
+        };
+
+        Ok(request)
+    }
+
+    /// Whether the response body is a yaml listing
+    fn is_listing(&self) -> bool {
+        matches!(
+            self,
+            Command::List | Command::Show { .. } | Command::History { .. } | Command::Datasets
+        )
+    }
 }
 
-impl Client {
-    async fn new<A: ToSocketAddrs>(address: A) -> Self {
-        let connection = TcpStream::connect(address).await.unwrap();
+/// Send a single command, print its response and exit with 0 on success
+/// `key=value`, sent on the command line so the value may not contain whitespace
+fn param(data: &str) -> std::result::Result<String, String> {
+    match data.split_once('=') {
+        Some((name, value)) => {
+            validate_param_name(name).map_err(|e| e.to_string())?;
+            if value.contains(char::is_whitespace) {
+                return Err("values may not contain whitespace".to_string());
+            }
+            Ok(data.to_string())
+        }
+        None => Err("expected key=value".to_string()),
+    }
+}
+
+async fn run_command(client: &mut Client, command: &Command, format: OutputFormat) -> Result<bool> {
+    let response = client.request(&command.to_request()?).await?;
 
-        Client { connection }
+    if !response.is_ok() {
+        eprintln!("error: {}", response.body());
+    } else if command.is_listing() {
+        print!("{}", render(response.body(), format)?);
+    } else {
+        println!("{}", response.body());
     }
 
-    async fn run(&mut self) {
-        loop {
-            let mut stdin = stdin();
-            let mut buf: [u8; 1024] = [0; 1024];
+    Ok(response.is_ok())
+}
+
+/// Read commands from piped stdin and print responses until EXIT or end of input
+async fn run_stdin(client: &mut Client) -> Result<()> {
+    let mut lines = BufReader::new(stdin()).lines();
 
-            // let mut buf = String::new();
-            let _ = stdin.read(&mut buf).await.unwrap();
+    while let Some(line) = lines.next_line().await? {
+        let request = if expects_body(&line) {
+            let mut body = String::new();
 
-            // println!("{}", String::from_utf8(buf.to_vec()).unwrap());
+            while let Some(body_line) = lines.next_line().await? {
+                if body_line == "." {
+                    break;
+                }
+                body.push_str(&body_line);
+                body.push('\n');
+            }
 
-            self.connection.write(&buf).await.unwrap();
-            self.connection.flush().await.unwrap();
-            buf.fill(0);
-            self.connection.read(&mut buf).await.unwrap();
+            Request::with_body(&line, &body)
+        } else {
+            Request::new(&line)
+        };
 
-            println!("{}", String::from_utf8(buf.to_vec()).unwrap());
+        let response = client.request(&request).await?;
+
+        println!("{}", response.body());
+
+        if line.trim().eq_ignore_ascii_case("EXIT") {
+            break;
         }
     }
+
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() {
-    let mut client = Client::new("127.0.0.1:3333").await;
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
 
-    client.run().await;
+    let result = async {
+        let mut client = Client::connect(cli.client_config())
+            .await
+            .map_err(|e| format!("failed to connect to {}: {}", cli.server, e))?;
+
+        match &cli.command {
+            Some(command) => run_command(&mut client, command, cli.output).await,
+            None if std::io::stdin().is_terminal() => Repl::new(client)?.run().await.map(|()| true),
+            None => run_stdin(&mut client).await.map(|()| true),
+        }
+    }
+    .await;
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(2)
+        }
+    }
 }
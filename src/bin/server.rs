@@ -1,19 +1,297 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use chainz::config::{parse_level, parse_mode, LogFormat, ServerConfig};
 use chainz::server::Server;
+use chainz::time::parse_duration;
+use chainz::tls::ServerTlsConfig;
 use chainz::Result;
-use tracing::Level;
+use clap::Parser;
+use tracing::{event, Level};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let subscriber = tracing_subscriber::fmt()
-        .pretty()
-        .with_max_level(Level::TRACE)
-        .finish();
+/// Run the chainz scheduler and serve clients.
+/// Options override the config file, defaults apply to what neither sets.
+#[derive(Parser)]
+#[command(name = "chainz_server")]
+struct Cli {
+    /// Config file (YAML)
+    #[arg(short, long, env = "CHAINZ_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, `host:port` or `unix:<path>`, repeatable
+    /// [default: 127.0.0.1:3333]
+    #[arg(short, long, env = "CHAINZ_LISTEN", value_delimiter = ',')]
+    listen: Vec<String>,
+
+    /// Additionally listen on a unix socket at this path
+    #[arg(long, env = "CHAINZ_SOCKET")]
+    socket: Option<PathBuf>,
+
+    /// Permissions of unix sockets, in octal [default: 0660]
+    #[arg(long, env = "CHAINZ_SOCKET_MODE", value_parser = mode)]
+    socket_mode: Option<u32>,
+
+    /// Tokens and client certificates of the principals, without one authentication
+    /// is disabled and only loopback addresses may be listened on
+    #[arg(long, env = "CHAINZ_TOKENS")]
+    tokens_file: Option<PathBuf>,
+
+    /// Serve webhooks (`POST /hooks/<name>`) on this address, `host:port`
+    #[arg(long, env = "CHAINZ_HOOKS_LISTEN")]
+    hooks_listen: Option<String>,
+
+    /// Hooks file, required to serve webhooks
+    #[arg(long, env = "CHAINZ_HOOKS_FILE")]
+    hooks_file: Option<PathBuf>,
+
+    /// Serve clients over TLS with this certificate (PEM)
+    #[arg(long, env = "CHAINZ_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of the server certificate (PEM)
+    #[arg(long, env = "CHAINZ_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Require client certificates signed by this ca (PEM)
+    #[arg(long, env = "CHAINZ_TLS_CLIENT_CA")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Add the task definitions in this directory at startup
+    #[arg(long, env = "CHAINZ_TASKS_DIR")]
+    tasks_dir: Option<PathBuf>,
+
+    /// Load the business day calendars in this directory, named after their file
+    #[arg(long, env = "CHAINZ_CALENDARS_DIR")]
+    calendars_dir: Option<PathBuf>,
+
+    /// Read the secrets of tasks from the files in this directory, named after them
+    #[arg(long, env = "CHAINZ_SECRETS_DIR")]
+    secrets_dir: Option<PathBuf>,
+
+    /// Read the secrets of tasks from environment variables with this prefix,
+    /// e.g. `CHAINZ_SECRET_`
+    #[arg(long, env = "CHAINZ_SECRETS_ENV_PREFIX")]
+    secrets_env_prefix: Option<String>,
+
+    /// Where the scheduler state is stored
+    #[arg(long, env = "CHAINZ_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// How long running tasks may take to finish on shutdown before they are
+    /// killed, `0s` kills them immediately [default: 30s]
+    #[arg(long, env = "CHAINZ_SHUTDOWN_GRACE")]
+    shutdown_grace: Option<String>,
+
+    /// Maximum number of tasks running at the same time [default: 4]
+    #[arg(long, env = "CHAINZ_MAX_CONCURRENT_TASKS")]
+    max_concurrent_tasks: Option<usize>,
+
+    /// Timeout of tasks that do not set one, e.g. `30m` [default: none]
+    #[arg(long, env = "CHAINZ_DEFAULT_TIMEOUT")]
+    default_timeout: Option<String>,
+
+    /// Retries of tasks that do not set them [default: 0]
+    #[arg(long, env = "CHAINZ_DEFAULT_RETRIES")]
+    default_retries: Option<u16>,
+
+    /// Searched for task executables before `PATH`, repeatable
+    #[arg(long = "plugin-dir", env = "CHAINZ_PLUGIN_DIRS", value_delimiter = ':')]
+    plugin_dirs: Vec<PathBuf>,
+
+    /// Enforce the resource limits of tasks in cgroups created in this cgroup v2
+    /// directory, rlimits are used without one
+    #[arg(long, env = "CHAINZ_CGROUP_ROOT")]
+    cgroup_root: Option<PathBuf>,
+
+    /// pretty | compact | full | json [default: pretty]
+    #[arg(long, env = "CHAINZ_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
 
-    tracing::subscriber::set_global_default(subscriber)?;
+    /// trace | debug | info | warn | error [default: info]
+    #[arg(long, env = "CHAINZ_LOG_LEVEL", value_parser = level)]
+    log_level: Option<Level>,
+}
+
+impl Cli {
+    /// Config file overridden by options
+    fn server_config(&self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::from_file(path)?,
+            None => ServerConfig::default(),
+        };
+
+        if !self.listen.is_empty() {
+            config.listen = self.listen.clone();
+        }
+        if let Some(path) = &self.socket {
+            config.listen.push(format!("unix:{}", path.display()));
+        }
+        if let Some(mode) = self.socket_mode {
+            config.unix_socket_mode = mode;
+        }
+        if let Some(path) = &self.tokens_file {
+            config.tokens_file = Some(path.clone());
+        }
+        if let Some(address) = &self.hooks_listen {
+            config.hooks_listen = Some(address.clone());
+        }
+        if let Some(path) = &self.hooks_file {
+            config.hooks_file = Some(path.clone());
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            config.tls = Some(ServerTlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: None,
+            });
+        }
+        if let Some(ca) = &self.tls_client_ca {
+            match &mut config.tls {
+                Some(tls) => tls.client_ca = Some(ca.clone()),
+                None => return Err("--tls-client-ca requires tls to be enabled".into()),
+            }
+        }
+        if let Some(path) = &self.tasks_dir {
+            config.tasks_dir = Some(path.clone());
+        }
+        if let Some(path) = &self.calendars_dir {
+            config.calendars_dir = Some(path.clone());
+        }
+        if let Some(path) = &self.secrets_dir {
+            config.secrets_dir = Some(path.clone());
+        }
+        if let Some(prefix) = &self.secrets_env_prefix {
+            config.secrets_env_prefix = Some(prefix.clone());
+        }
+        if let Some(path) = &self.state_file {
+            config.state_file = Some(path.clone());
+        }
+        if let Some(t) = &self.shutdown_grace {
+            config.shutdown_grace =
+                parse_duration(t).map_err(|e| format!("--shutdown-grace: {}", e))?;
+        }
+        if let Some(n) = self.max_concurrent_tasks {
+            config.scheduler.max_concurrent_tasks = n;
+        }
+        if let Some(t) = &self.default_timeout {
+            config.scheduler.default_timeout =
+                Some(parse_duration(t).map_err(|e| format!("--default-timeout: {}", e))?);
+        }
+        if let Some(n) = self.default_retries {
+            config.scheduler.default_retries = n;
+        }
+        if !self.plugin_dirs.is_empty() {
+            config.scheduler.plugin_dirs = self.plugin_dirs.clone();
+        }
+        if let Some(path) = &self.cgroup_root {
+            config.scheduler.cgroup_root = Some(path.clone());
+        }
+        if let Some(format) = self.log_format {
+            config.log_format = format;
+        }
+        if let Some(level) = self.log_level {
+            config.log_level = level;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn mode(data: &str) -> std::result::Result<u32, String> {
+    parse_mode(data).map_err(|e| e.to_string())
+}
+
+fn level(data: &str) -> std::result::Result<Level, String> {
+    parse_level(data).map_err(|e| e.to_string())
+}
 
-    let mut server = Server::new("0.0.0.0:3333").await;
+fn init_logging(config: &ServerConfig) -> Result<()> {
+    let builder = tracing_subscriber::fmt().with_max_level(config.log_level);
 
-    server.run().await?;
+    match config.log_format {
+        LogFormat::Pretty => tracing::subscriber::set_global_default(builder.pretty().finish())?,
+        LogFormat::Compact => tracing::subscriber::set_global_default(builder.compact().finish())?,
+        LogFormat::Full => tracing::subscriber::set_global_default(builder.finish())?,
+        LogFormat::Json => tracing::subscriber::set_global_default(builder.json().finish())?,
+    }
 
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let config = match cli.server_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("invalid configuration:\n{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    if let Err(e) = init_logging(&config) {
+        eprintln!("failed to set up logging: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let mut server = match Server::from_config(&config).await {
+        Ok(s) => s,
+        Err(e) => {
+            event!(Level::ERROR, err = e.to_string(), "failed to start server");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match server.run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            event!(Level::ERROR, err = e.to_string(), "server failed");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_override_the_config_file() {
+        let path = std::env::temp_dir().join(format!("chainz-cli-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "listen: 127.0.0.1:4000\nmax_concurrent_tasks: 8\ndefault_retries: 2\nlog_level: debug\n",
+        )
+        .unwrap();
+
+        let cli = Cli::try_parse_from([
+            "chainz_server",
+            "--config",
+            &path.to_string_lossy(),
+            "--listen",
+            "127.0.0.1:5000,127.0.0.1:5001",
+            "--default-retries",
+            "5",
+        ])
+        .unwrap();
+        let config = cli.server_config();
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(config.listen, ["127.0.0.1:5000", "127.0.0.1:5001"]);
+        assert_eq!(config.scheduler.default_retries, 5);
+        // Kept from the file
+        assert_eq!(config.scheduler.max_concurrent_tasks, 8);
+        assert_eq!(config.log_level, Level::DEBUG);
+        // Set by neither
+        assert_eq!(config.log_format, LogFormat::Pretty);
+
+        let cli = Cli::try_parse_from(["chainz_server", "--max-concurrent-tasks", "0"]).unwrap();
+        assert!(cli.server_config().is_err());
+
+        let cli = Cli::try_parse_from(["chainz_server", "--tls-client-ca", "ca.pem"]).unwrap();
+        assert!(cli.server_config().is_err());
+    }
+}
@@ -0,0 +1,412 @@
+//! Business day calendars, loaded from the calendars directory of the server
+//!
+//! A calendar is named after its file and is either YAML:
+//! ```yaml
+//! weekend: [sat, sun]   # the default
+//! holidays:
+//!   - 2024-03-29
+//!   - date: 2024-04-01
+//!     name: Easter Monday
+//!   - 12-25             # every year
+//! ```
+//! or iCalendar (`.ics`), where every event is a holiday. Events last from `DTSTART` to
+//! `DTEND` (exclusive) and may recur with `RRULE:FREQ=YEARLY`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::{Datelike, Months, NaiveDate};
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::Result;
+
+/// Days that are not business days
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Calendar {
+    pub name: String,
+    /// Days of the week, bit 0 is sunday
+    weekend: u8,
+    holidays: BTreeSet<NaiveDate>,
+    /// Holidays on the same day every year, as `(month, day)`
+    yearly: BTreeSet<(u32, u32)>,
+}
+
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Longest run of days without a business day searched through
+const MAX_DAYS: u32 = 366;
+
+impl Calendar {
+    const KEYS: [&'static str; 2] = ["weekend", "holidays"];
+
+    /// Load every `*.yaml`, `*.yml` and `*.ics` file of `dir`, by name
+    pub fn load_dir(dir: &Path) -> Result<HashMap<String, Arc<Calendar>>> {
+        let mut paths = Vec::new();
+
+        for entry in std::fs::read_dir(dir)
+            .map_err(|e| format!("failed to read calendars {}: {}", dir.display(), e))?
+        {
+            let path = entry?.path();
+            if matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("yaml" | "yml" | "ics")
+            ) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut calendars = HashMap::new();
+
+        for path in paths {
+            let calendar = Calendar::from_file(&path)?;
+
+            if calendars.contains_key(&calendar.name) {
+                return Err(format!("duplicate calendar '{}'", calendar.name).into());
+            }
+            calendars.insert(calendar.name.clone(), Arc::new(calendar));
+        }
+
+        Ok(calendars)
+    }
+
+    /// Load a calendar named after the file, YAML or iCalendar depending on its extension
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| format!("invalid calendar file name: {}", path.display()))?;
+
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read calendar {}: {}", path.display(), e))?;
+
+        let calendar = match path.extension().and_then(|e| e.to_str()) {
+            Some("ics") => Calendar::from_ics(name, &data),
+            _ => Calendar::from_yaml_str(name, &data),
+        };
+
+        calendar.map_err(|e| format!("invalid calendar {}: {}", path.display(), e).into())
+    }
+
+    pub fn from_yaml_str(name: &str, data: &str) -> Result<Self> {
+        let docs = YamlLoader::load_from_str(data)?;
+        let doc = match docs.as_slice() {
+            [doc] => doc,
+            _ => return Err("expected a single calendar document".into()),
+        };
+
+        let hash = doc.as_hash().ok_or("calendar must be a mapping")?;
+        for key in hash.keys() {
+            match key.as_str() {
+                Some(k) if Calendar::KEYS.contains(&k) => {}
+                _ => return Err(format!("unknown calendar key: {:?}", key).into()),
+            }
+        }
+
+        let mut calendar = Calendar::new(name);
+
+        if let Some(days) = doc["weekend"].as_vec() {
+            calendar.weekend = 0;
+
+            for day in days {
+                let i = day
+                    .as_str()
+                    .and_then(|d| WEEKDAYS.iter().position(|w| w.eq_ignore_ascii_case(d)))
+                    .ok_or_else(|| format!("invalid weekend day: {:?}", day))?;
+                calendar.weekend |= 1 << i;
+            }
+
+            if calendar.weekend == 0x7f {
+                return Err("weekend covers the whole week".into());
+            }
+        } else if !doc["weekend"].is_badvalue() {
+            return Err("weekend must be a list of days".into());
+        }
+
+        for holiday in doc["holidays"].as_vec().map_or(&[][..], |v| v.as_slice()) {
+            let date = match holiday {
+                Yaml::String(s) => s.as_str(),
+                Yaml::Hash(_) => holiday["date"]
+                    .as_str()
+                    .ok_or_else(|| format!("holiday without date: {:?}", holiday))?,
+                h => return Err(format!("invalid holiday: {:?}", h).into()),
+            };
+
+            if let Ok(d) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+                calendar.holidays.insert(d);
+                continue;
+            }
+
+            // Any leap year, to accept 02-29
+            match NaiveDate::parse_from_str(&format!("2000-{}", date), "%Y-%m-%d") {
+                Ok(d) => calendar.yearly.insert((d.month(), d.day())),
+                Err(_) => return Err(format!("invalid holiday date: {}", date).into()),
+            };
+        }
+
+        Ok(calendar)
+    }
+
+    /// Every event is a holiday, times of day are ignored
+    pub fn from_ics(name: &str, data: &str) -> Result<Self> {
+        let mut calendar = Calendar::new(name);
+
+        // Long lines are folded by starting continuations with whitespace
+        let mut lines: Vec<String> = Vec::new();
+        for line in data.lines() {
+            match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+                (Some(rest), Some(last)) => last.push_str(rest),
+                _ => lines.push(line.to_string()),
+            }
+        }
+
+        let mut event: Option<(Option<NaiveDate>, Option<NaiveDate>, bool)> = None;
+
+        for line in &lines {
+            let (property, value) = match line.split_once(':') {
+                Some((p, v)) => (p.split(';').next().unwrap_or_default(), v.trim()),
+                None => continue,
+            };
+
+            match (property.to_ascii_uppercase().as_str(), &mut event) {
+                ("BEGIN", _) if value.eq_ignore_ascii_case("VEVENT") => {
+                    event = Some((None, None, false))
+                }
+                ("DTSTART", Some(e)) => e.0 = Some(ics_date(value)?),
+                ("DTEND", Some(e)) => e.1 = Some(ics_date(value)?),
+                ("RRULE", Some(e)) => {
+                    let rule = value.to_ascii_uppercase();
+                    match rule
+                        .split(';')
+                        .all(|p| p == "FREQ=YEARLY" || p == "INTERVAL=1")
+                    {
+                        true if rule.contains("FREQ=YEARLY") => e.2 = true,
+                        _ => return Err(format!("unsupported RRULE: {}", value).into()),
+                    }
+                }
+                ("END", Some((start, end, yearly))) if value.eq_ignore_ascii_case("VEVENT") => {
+                    let start = start.ok_or("event without DTSTART")?;
+                    let end = end.unwrap_or(start + chrono::Days::new(1));
+
+                    for day in start.iter_days().take_while(|d| *d < end) {
+                        match yearly {
+                            true => calendar.yearly.insert((day.month(), day.day())),
+                            false => calendar.holidays.insert(day),
+                        };
+                    }
+
+                    event = None;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(calendar)
+    }
+
+    /// Calendar without holidays, saturday and sunday are the weekend
+    pub fn new(name: &str) -> Self {
+        Calendar {
+            name: name.to_string(),
+            weekend: 1 << 0 | 1 << 6,
+            holidays: BTreeSet::new(),
+            yearly: BTreeSet::new(),
+        }
+    }
+
+    pub fn is_business_day(&self, date: NaiveDate) -> bool {
+        self.weekend & (1 << date.weekday().num_days_from_sunday()) == 0
+            && !self.holidays.contains(&date)
+            && !self.yearly.contains(&(date.month(), date.day()))
+    }
+
+    /// Closest business day after `date`, or before it if not `forward`
+    pub fn shift(&self, date: NaiveDate, forward: bool) -> Option<NaiveDate> {
+        let mut day = date;
+
+        for _ in 0..MAX_DAYS {
+            day = match forward {
+                true => day.succ_opt()?,
+                false => day.pred_opt()?,
+            };
+            if self.is_business_day(day) {
+                return Some(day);
+            }
+        }
+
+        None
+    }
+
+    /// `n`th business day of a month, counting from its end for negative `n`
+    pub fn nth_business_day(&self, year: i32, month: u32, n: i32) -> Option<NaiveDate> {
+        let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+        let next_month = first.checked_add_months(Months::new(1))?;
+
+        let business: Vec<NaiveDate> = first
+            .iter_days()
+            .take_while(|d| *d < next_month)
+            .filter(|d| self.is_business_day(*d))
+            .collect();
+
+        match n {
+            0 => None,
+            n if n > 0 => business.get(n as usize - 1).copied(),
+            n => business
+                .iter()
+                .rev()
+                .nth(n.unsigned_abs() as usize - 1)
+                .copied(),
+        }
+    }
+}
+
+impl Default for Calendar {
+    fn default() -> Self {
+        Calendar::new("weekdays")
+    }
+}
+
+/// Date of a `DATE` or `DATE-TIME` value, `20241225` or `20241225T090000Z`
+fn ics_date(value: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(value.get(..8).unwrap_or(value), "%Y%m%d")
+        .map_err(|_| format!("invalid date in calendar: {}", value).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(data: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(data, "%Y-%m-%d").unwrap()
+    }
+
+    /// Good Friday and Easter Monday 2024, Christmas every year
+    fn easter() -> Calendar {
+        Calendar::from_yaml_str(
+            "easter",
+            "holidays:\n\
+             \x20 - 2024-03-29\n\
+             \x20 - date: 2024-04-01\n\
+             \x20   name: Easter Monday\n\
+             \x20 - 12-25\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn yaml() {
+        let calendar = easter();
+        assert!(!calendar.is_business_day(date("2024-03-29")));
+        assert!(!calendar.is_business_day(date("2024-04-01")));
+        assert!(!calendar.is_business_day(date("2024-03-30")));
+        assert!(calendar.is_business_day(date("2024-03-28")));
+        assert!(calendar.is_business_day(date("2025-03-28")));
+        assert!(!calendar.is_business_day(date("2024-12-25")));
+        assert!(!calendar.is_business_day(date("2030-12-25")));
+
+        let calendar = Calendar::from_yaml_str("gulf", "weekend: [fri, SAT]").unwrap();
+        assert!(!calendar.is_business_day(date("2024-03-08")));
+        assert!(calendar.is_business_day(date("2024-03-10")));
+
+        for data in [
+            "holidays: [2024-02-30]",
+            "holidays: [{name: x}]",
+            "weekend: [sun, funday]",
+            "weekend: sun",
+            "weekend: [sun, mon, tue, wed, thu, fri, sat]",
+            "workdays: [mon]",
+        ] {
+            assert!(Calendar::from_yaml_str("c", data).is_err(), "{}", data);
+        }
+    }
+
+    #[test]
+    fn ics() {
+        let data = "BEGIN:VCALENDAR\r\n\
+                    BEGIN:VEVENT\r\n\
+                    SUMMARY:Christmas\r\n\
+                    DTSTART;VALUE=DATE:20201225\r\n\
+                    DTEND;VALUE=DATE:20201227\r\n\
+                    RRULE:FREQ=YEARLY;\r\n INTERVAL=1\r\n\
+                    END:VEVENT\r\n\
+                    BEGIN:VEVENT\r\n\
+                    SUMMARY:Closed\r\n\
+                    DTSTART:20240314T090000Z\r\n\
+                    END:VEVENT\r\n\
+                    END:VCALENDAR\r\n";
+        let calendar = Calendar::from_ics("bank", data).unwrap();
+
+        // Until DTEND, exclusive, every year
+        assert!(!calendar.is_business_day(date("2025-12-25")));
+        assert!(!calendar.is_business_day(date("2025-12-26")));
+        assert!(calendar.is_business_day(date("2025-12-24")));
+        assert!(calendar.is_business_day(date("2024-12-27")));
+
+        // A day without DTEND
+        assert!(!calendar.is_business_day(date("2024-03-14")));
+        assert!(calendar.is_business_day(date("2024-03-15")));
+        assert!(calendar.is_business_day(date("2025-03-14")));
+
+        for rule in ["FREQ=MONTHLY", "FREQ=YEARLY;COUNT=3", "INTERVAL=1"] {
+            let data = format!(
+                "BEGIN:VEVENT\nDTSTART:20240101\nRRULE:{}\nEND:VEVENT\n",
+                rule
+            );
+            assert!(Calendar::from_ics("c", &data).is_err(), "{}", rule);
+        }
+        assert!(Calendar::from_ics("c", "BEGIN:VEVENT\nDTSTART:2024\nEND:VEVENT").is_err());
+        assert!(Calendar::from_ics("c", "BEGIN:VEVENT\nEND:VEVENT").is_err());
+    }
+
+    #[test]
+    fn nth_business_day() {
+        let calendar = easter();
+        let nth = |n| calendar.nth_business_day(2024, 3, n);
+
+        // March 2024 has 21 weekdays, one of them Good Friday
+        assert_eq!(nth(1), Some(date("2024-03-01")));
+        assert_eq!(nth(2), Some(date("2024-03-04")));
+        assert_eq!(nth(20), Some(date("2024-03-28")));
+        assert_eq!(nth(21), None);
+        assert_eq!(nth(-1), Some(date("2024-03-28")));
+        assert_eq!(nth(-2), Some(date("2024-03-27")));
+        assert_eq!(nth(-20), Some(date("2024-03-01")));
+        assert_eq!(nth(-21), None);
+        assert_eq!(nth(0), None);
+
+        // Easter Monday is the first day of April
+        assert_eq!(
+            calendar.nth_business_day(2024, 4, 1),
+            Some(date("2024-04-02"))
+        );
+        assert_eq!(
+            Calendar::default().nth_business_day(2024, 4, 1),
+            Some(date("2024-04-01"))
+        );
+    }
+
+    #[test]
+    fn shift() {
+        let calendar = easter();
+
+        // Over the Easter weekend
+        assert_eq!(
+            calendar.shift(date("2024-03-29"), true),
+            Some(date("2024-04-02"))
+        );
+        assert_eq!(
+            calendar.shift(date("2024-04-01"), false),
+            Some(date("2024-03-28"))
+        );
+        assert_eq!(
+            calendar.shift(date("2024-12-25"), true),
+            Some(date("2024-12-26"))
+        );
+        // Strictly after and before
+        assert_eq!(
+            calendar.shift(date("2024-03-27"), true),
+            Some(date("2024-03-28"))
+        );
+    }
+}
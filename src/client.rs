@@ -0,0 +1,129 @@
+use tokio::io::{split, AsyncRead, AsyncWrite, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Duration};
+
+use crate::protocol::{Request, Response};
+use crate::server::UNIX_PREFIX;
+use crate::tls::ClientTlsConfig;
+use crate::Result;
+
+type Reader = BufReader<Box<dyn AsyncRead + Unpin + Send>>;
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Where and how to connect to a [`crate::server::Server`]
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// `host:port` or `unix:<path>`
+    pub address: String,
+    /// Token to authenticate with
+    pub token: Option<String>,
+    pub tls: Option<ClientTlsConfig>,
+}
+
+/// Connection to a [`crate::server::Server`]
+pub struct Client {
+    config: ClientConfig,
+    reader: Reader,
+    writer: Writer,
+}
+
+impl ClientConfig {
+    pub fn new(address: &str) -> Self {
+        ClientConfig {
+            address: address.to_string(),
+            token: None,
+            tls: None,
+        }
+    }
+}
+
+impl Client {
+    const RECONNECT_ATTEMPTS: u32 = 5;
+
+    /// Connect to the server, authenticating with the configured token if any
+    pub async fn connect(config: ClientConfig) -> Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = config.address.strip_prefix(UNIX_PREFIX) {
+            if config.tls.is_some() {
+                return Err("tls is not supported over unix sockets".into());
+            }
+
+            let (r, w) = tokio::net::UnixStream::connect(path).await?.into_split();
+            return Client::with_stream(config, Box::new(r), Box::new(w)).await;
+        }
+
+        let stream = TcpStream::connect(&config.address).await?;
+
+        let (reader, writer): (Box<dyn AsyncRead + Unpin + Send>, Writer) = match &config.tls {
+            Some(tls) => {
+                let (connector, name) = tls.connector(&config.address)?;
+                let (r, w) = split(connector.connect(name, stream).await?);
+                (Box::new(r), Box::new(w))
+            }
+            None => {
+                let (r, w) = stream.into_split();
+                (Box::new(r), Box::new(w))
+            }
+        };
+
+        Client::with_stream(config, reader, writer).await
+    }
+
+    async fn with_stream(
+        config: ClientConfig,
+        reader: Box<dyn AsyncRead + Unpin + Send>,
+        writer: Writer,
+    ) -> Result<Self> {
+        let mut client = Client {
+            config,
+            reader: BufReader::new(reader),
+            writer,
+        };
+
+        if let Some(token) = client.config.token.clone() {
+            match client
+                .request(&Request::new(&format!("AUTH {}", token)))
+                .await?
+            {
+                Response::Ok(_) => {}
+                Response::Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(client)
+    }
+
+    pub fn address(&self) -> &str {
+        &self.config.address
+    }
+
+    /// Send a request and wait for its response
+    pub async fn request(&mut self, request: &Request) -> Result<Response> {
+        request.write_to(&mut self.writer).await?;
+
+        match Response::read_from(&mut self.reader).await? {
+            Some(response) => Ok(response),
+            None => Err("connection closed by server".into()),
+        }
+    }
+
+    /// Replace the connection with a new one to the same server,
+    /// retrying with backoff while the server is unavailable
+    pub async fn reconnect(&mut self) -> Result<()> {
+        let mut attempt = 0;
+
+        loop {
+            match Client::connect(self.config.clone()).await {
+                Ok(client) => {
+                    *self = client;
+                    return Ok(());
+                }
+                Err(e) if attempt + 1 >= Client::RECONNECT_ATTEMPTS => return Err(e),
+                Err(_) => {
+                    sleep(Duration::from_millis(250 * 2u64.pow(attempt))).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
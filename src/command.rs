@@ -1,87 +1,282 @@
-use crate::task::{ScheduleType, Task, TaskId};
+use crate::backfill::BackfillOptions;
+use crate::scheduler::{ResumePolicy, TriggerOptions};
+use crate::task::{validate_param_name, ScheduleType, Task, TaskId};
+use crate::time::parse_time;
 use crate::Result;
 
-pub const HELP: &'static str = "
-usage: 
-    add {task}    add and schedule new task
-    list          list tasks
-    drain         stop scheduling of task
-    kill          kill and remove task from schedule
-    EXIT          exit and close client";
+pub const HELP: &str = "
+usage:
+    auth {token}            authenticate, required when the server has tokens
+    add {task}              add and schedule new task, {task} is either
+                            '<task_id> <schedule> <cmd>' or, on the following
+                            lines, a yaml task definition ending with a '.' line
+    list                    list tasks
+    show {task_id}          show task and its queued instances
+    drain {task_id}         stop scheduling of task
+    kill {task_id}          kill and remove task from schedule
+    pause {task_id}         stop running scheduled instances of task, keeping it
+    resume {task_id} [skip|catchup]
+                            resume paused task, skipping the runs it missed
+                            (default) or running once to catch up
+    trigger {task_id} [--downstream] [key=value ...]
+                            run task now, outside of its schedule, with
+                            parameter overrides, optionally continuing with
+                            its down-stream chain
+    backfill {task_id} {from} {to} [--parallelism n] [--downstream]
+                            run task for each point of its schedule from
+                            {from} to {to}, both inclusive, n at a time
+    logs {instance_id}      show output of a task run
+    history [task_id|*] [n] show last n task runs
+    datasets                list datasets, their latest update and consumers
+    validate                validate the yaml task definition on the following
+                            lines, ending with a '.' line
+    EXIT                    exit and close client";
+
+pub const COMMANDS: [&str; 16] = [
+    "AUTH", "ADD", "LIST", "SHOW", "DRAIN", "KILL", "PAUSE", "RESUME", "TRIGGER", "BACKFILL",
+    "LOGS", "HISTORY", "DATASETS", "VALIDATE", "HELP", "EXIT",
+];
 
 pub enum ClientCommand {
+    Auth(String),
     Add(Task),
     Help,
     List,
+    Show(TaskId),
     Drain(TaskId),
     Kill(TaskId),
+    Pause(TaskId),
+    Resume(TaskId, ResumePolicy),
+    Trigger(TaskId, TriggerOptions),
+    Backfill(TaskId, BackfillOptions),
+    Logs(String),
+    History(Option<TaskId>, Option<usize>),
+    Datasets,
+    Validate(Task),
     Noop,
     Error(String),
     Exit,
 }
 
-impl ClientCommand {
-    pub fn from_str(data: &str) -> Result<Self> {
-        let mut parts = data.split(" ");
+/// Whether the command on `line` is followed by a document, see [`crate::protocol`]
+pub fn expects_body(line: &str) -> bool {
+    let mut parts = line.split_whitespace();
+
+    match parts.next().map(str::to_uppercase).as_deref() {
+        Some("ADD") => parts.next().is_none(),
+        Some("VALIDATE") => true,
+        _ => false,
+    }
+}
 
-        println!("{:?}", data);
+impl ClientCommand {
+    /// Parse a command line and its document, if any
+    pub fn parse(line: &str, body: Option<&str>) -> Result<Self> {
+        let mut parts = line.split_whitespace();
 
         // Match main command
-        match parts.next() {
-            Some(cmd) => match cmd {
-                "ADD" => {
-                    let task_id = match parts.next() {
-                        Some(id) => id,
-                        None => {
-                            return Err("no task_id provided".into());
-                        }
-                    };
+        let cmd = match parts.next() {
+            Some(cmd) => cmd.to_uppercase(),
+            None => return Ok(ClientCommand::Noop),
+        };
 
-                    let schedule = match parts.next() {
-                        Some(part) => ScheduleType::from_str(part)?,
-                        None => {
-                            return Err("no schedule provided".into());
-                        }
-                    };
+        let mut task_id = || match parts.next() {
+            Some(tid) => Ok(tid.to_string()),
+            None => Err("no task id provided"),
+        };
 
-                    let cmd: Vec<&str> = parts.collect();
+        match cmd.as_str() {
+            "AUTH" => match parts.next() {
+                Some(token) => Ok(ClientCommand::Auth(token.to_string())),
+                None => Err("no token provided".into()),
+            },
+            "ADD" => {
+                if let Some(body) = body {
+                    return Ok(ClientCommand::Add(Task::from_yaml_str(body)?));
+                }
 
-                    // .ok_or("no cmd provided")?;
+                let task_id = task_id()?;
 
-                    // let retries = parts.next().unwrap_or("1").parse()?;
+                let schedule = match parts.next() {
+                    Some(part) => ScheduleType::from_str(part)?,
+                    None => {
+                        return Err("no schedule provided".into());
+                    }
+                };
 
-                    let task = Task::new(task_id, schedule, cmd.join(" ").as_str(), 0);
+                let cmd: Vec<&str> = parts.collect();
 
-                    return Ok::<ClientCommand, _>(ClientCommand::Add(task));
-                }
-                "LIST" => return Ok(ClientCommand::List),
-                "DRAIN" => {
-                    let task_id = match parts.next() {
-                        Some(tid) => tid,
-                        None => return Err("no task id provided".into()),
-                    };
-
-                    return Ok(ClientCommand::Drain(task_id.to_string()));
+                if cmd.is_empty() {
+                    return Err("no cmd provided".into());
                 }
-                "KILL" => {
-                    let task_id = match parts.next() {
-                        Some(tid) => tid,
-                        None => return Err("no task id provided".into()),
-                    };
 
-                    return Ok(ClientCommand::Kill(task_id.to_string()));
-                }
-                "HELP" => {
-                    return Ok(ClientCommand::Help);
+                let mut task = Task::new(&task_id, schedule, cmd.join(" ").as_str(), 0)?;
+                task.retries = None;
+
+                Ok(ClientCommand::Add(task))
+            }
+            "VALIDATE" => match body {
+                Some(body) => Ok(ClientCommand::Validate(Task::from_yaml_str(body)?)),
+                None => Err("no task definition provided".into()),
+            },
+            "LIST" => Ok(ClientCommand::List),
+            "DATASETS" => Ok(ClientCommand::Datasets),
+            "SHOW" => Ok(ClientCommand::Show(task_id()?)),
+            "DRAIN" => Ok(ClientCommand::Drain(task_id()?)),
+            "KILL" => Ok(ClientCommand::Kill(task_id()?)),
+            "PAUSE" => Ok(ClientCommand::Pause(task_id()?)),
+            "RESUME" => {
+                let task_id = task_id()?;
+
+                let policy = match parts.next() {
+                    Some(p) => p.parse()?,
+                    None => ResumePolicy::Skip,
+                };
+
+                Ok(ClientCommand::Resume(task_id, policy))
+            }
+            "TRIGGER" => {
+                let task_id = task_id()?;
+                let mut options = TriggerOptions::default();
+
+                for part in parts {
+                    if part == "--downstream" {
+                        options.downstream = true;
+                        continue;
+                    }
+
+                    match part.split_once('=') {
+                        Some((name, value)) => {
+                            validate_param_name(name)?;
+                            options.params.insert(name.to_string(), value.to_string());
+                        }
+                        None => {
+                            return Err(format!(
+                                "expected key=value or --downstream, got '{}'",
+                                part
+                            )
+                            .into())
+                        }
+                    }
                 }
-                "EXIT" => {
-                    return Ok(ClientCommand::Exit);
+
+                Ok(ClientCommand::Trigger(task_id, options))
+            }
+            "BACKFILL" => {
+                let task_id = task_id()?;
+
+                let from = parts.next().ok_or("expected a date range: {from} {to}")?;
+                let to = parts.next().ok_or("expected a date range: {from} {to}")?;
+
+                let mut options = BackfillOptions {
+                    from: parse_time(from)?,
+                    to: parse_time(to)?,
+                    parallelism: 1,
+                    downstream: false,
+                };
+
+                while let Some(part) = parts.next() {
+                    match part {
+                        "--downstream" => options.downstream = true,
+                        "--parallelism" => {
+                            let n = parts.next().ok_or("no parallelism provided")?;
+                            options.parallelism = match n.parse() {
+                                Ok(n) if n > 0 => n,
+                                _ => {
+                                    return Err(format!(
+                                        "invalid parallelism '{}', expected at least 1",
+                                        n
+                                    )
+                                    .into())
+                                }
+                            };
+                        }
+                        p => return Err(format!("unexpected argument '{}'", p).into()),
+                    }
                 }
-                _ => Err(format!("Invalid Command {}", cmd)),
+
+                Ok(ClientCommand::Backfill(task_id, options))
+            }
+            "LOGS" => match parts.next() {
+                Some(id) => Ok(ClientCommand::Logs(id.to_string())),
+                None => Err("no instance id provided".into()),
             },
-            None => Err("No cmd provided".into()),
-        }?;
+            "HISTORY" => {
+                let task_id = match parts.next() {
+                    Some("*") | None => None,
+                    Some(tid) => Some(tid.to_string()),
+                };
+
+                let limit = match parts.next() {
+                    Some(n) => Some(n.parse()?),
+                    None => None,
+                };
+
+                Ok(ClientCommand::History(task_id, limit))
+            }
+            "HELP" => Ok(ClientCommand::Help),
+            "EXIT" => Ok(ClientCommand::Exit),
+            _ => Err(format!("Invalid Command {}", cmd).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backfill(line: &str) -> Result<BackfillOptions> {
+        match ClientCommand::parse(line, None)? {
+            ClientCommand::Backfill(_, options) => Ok(options),
+            _ => panic!("not a backfill: {}", line),
+        }
+    }
+
+    #[test]
+    fn parse_backfill() {
+        let options =
+            backfill("BACKFILL load 2024-01-01 2024-01-31 --parallelism 4 --downstream").unwrap();
+        assert_eq!(options.from, parse_time("2024-01-01").unwrap());
+        assert_eq!(options.to, parse_time("2024-01-31").unwrap());
+        assert_eq!(options.parallelism, 4);
+        assert!(options.downstream);
+
+        let options = backfill("BACKFILL load 2024-01-01 2024-01-31").unwrap();
+        assert_eq!(options.parallelism, 1);
+        assert!(!options.downstream);
+
+        for line in [
+            "BACKFILL load",
+            "BACKFILL load 2024-01-01",
+            "BACKFILL load 2024-01-01 tomorrow",
+            "BACKFILL load 2024-01-01 2024-01-31 --parallelism",
+            "BACKFILL load 2024-01-01 2024-01-31 --parallelism 0",
+            "BACKFILL load 2024-01-01 2024-01-31 --parallelism -1",
+            "BACKFILL load 2024-01-01 2024-01-31 --all",
+        ] {
+            assert!(backfill(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parse_one_line_add() {
+        match ClientCommand::parse("ADD load interval:1h load {{ ds }}", None).unwrap() {
+            ClientCommand::Add(task) => {
+                assert_eq!(task.task_id, "load");
+                assert_eq!(task.cmd, "load {{ ds }}");
+            }
+            _ => panic!("not an add"),
+        }
 
-        Err("somethin".into())
+        for line in [
+            "ADD ../x interval:1h true",
+            "ADD .. interval:1h true",
+            "ADD a/b interval:1h true",
+            "ADD load interval:1h load {{ ds",
+            "ADD load interval:1h load {{ ds | nope }}",
+            "ADD load interval:1h",
+        ] {
+            assert!(ClientCommand::parse(line, None).is_err(), "{}", line);
+        }
     }
 }
@@ -0,0 +1,764 @@
+//! Configuration of `chainz_server`
+//!
+//! Read from a YAML file, see `chainz.yaml` for an example. Every key is
+//! optional, command-line options and `CHAINZ_*` environment variables of
+//! `chainz_server` take precedence over the file.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tracing::Level;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::scheduler::SchedulerConfig;
+use crate::server::{Server, UNIX_PREFIX};
+use crate::time::parse_duration;
+use crate::tls::ServerTlsConfig;
+use crate::Result;
+
+/// Format of log lines written by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    Compact,
+    Full,
+    Json,
+}
+
+/// Settings of the server, see [`ServerConfig::validate`] for the checks done at startup
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Addresses to listen on, `host:port` or `unix:<path>`
+    pub listen: Vec<String>,
+    /// Permissions of unix sockets
+    pub unix_socket_mode: u32,
+    /// Tokens and client certificates of the principals, see [`crate::auth`].
+    /// Authentication is disabled without one and only loopback addresses may be
+    /// listened on
+    pub tokens_file: Option<PathBuf>,
+    /// Address `POST /hooks/<name>` is served on, `host:port`
+    pub hooks_listen: Option<String>,
+    /// Hooks served on `hooks_listen`, see [`crate::webhook`]
+    pub hooks_file: Option<PathBuf>,
+    pub tls: Option<ServerTlsConfig>,
+    /// Task definitions (`*.yaml`, `*.yml`) added at startup
+    pub tasks_dir: Option<PathBuf>,
+    /// Calendars (`*.yaml`, `*.yml`, `*.ics`) tasks may refer to, named after their file
+    pub calendars_dir: Option<PathBuf>,
+    /// Secrets tasks may refer to, a file per secret named after it
+    pub secrets_dir: Option<PathBuf>,
+    /// Prefix of the environment variables of the server holding secrets, see
+    /// [`crate::secrets`]
+    pub secrets_env_prefix: Option<String>,
+    /// Where the scheduler state is saved on shutdown and restored from on startup
+    pub state_file: Option<PathBuf>,
+    /// How long running tasks may take to finish on shutdown before they are killed
+    pub shutdown_grace: Duration,
+    pub scheduler: SchedulerConfig,
+    pub log_format: LogFormat,
+    pub log_level: Level,
+}
+
+impl ServerConfig {
+    const KEYS: [&'static str; 19] = [
+        "listen",
+        "unix_socket_mode",
+        "tokens_file",
+        "hooks_listen",
+        "hooks_file",
+        "tls",
+        "tasks_dir",
+        "calendars_dir",
+        "secrets_dir",
+        "secrets_env_prefix",
+        "state_file",
+        "shutdown_grace",
+        "max_concurrent_tasks",
+        "default_timeout",
+        "default_retries",
+        "plugin_dirs",
+        "cgroup_root",
+        "log_format",
+        "log_level",
+    ];
+
+    const TLS_KEYS: [&'static str; 3] = ["cert", "key", "client_ca"];
+
+    /// Read the config from the YAML file at `path`, keys not set keep their defaults
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read config {}: {}", path.display(), e))?;
+
+        ServerConfig::from_yaml_str(&data)
+            .map_err(|e| format!("invalid config {}: {}", path.display(), e).into())
+    }
+
+    pub fn from_yaml_str(data: &str) -> Result<Self> {
+        let docs = YamlLoader::load_from_str(data)?;
+
+        match docs.as_slice() {
+            [] => Ok(ServerConfig::default()),
+            [doc] => ServerConfig::from_yaml(doc),
+            _ => Err("expected a single config document".into()),
+        }
+    }
+
+    pub fn from_yaml(doc: &Yaml) -> Result<Self> {
+        let mut config = ServerConfig::default();
+
+        let hash = match doc {
+            Yaml::Hash(h) => h,
+            Yaml::Null => return Ok(config),
+            _ => return Err("config must be a mapping".into()),
+        };
+
+        let mut errors = Vec::new();
+
+        for key in hash.keys() {
+            match key.as_str() {
+                Some(k) if ServerConfig::KEYS.contains(&k) => {}
+                _ => errors.push(format!("unknown key: {:?}", key)),
+            }
+        }
+
+        let mut check = |key: &str, result: Result<()>| {
+            if let Err(e) = result {
+                errors.push(format!("{}: {}", key, e));
+            }
+        };
+
+        check("listen", {
+            match &doc["listen"] {
+                Yaml::BadValue => Ok(()),
+                Yaml::String(s) => {
+                    config.listen = vec![s.clone()];
+                    Ok(())
+                }
+                l => strings(l).map(|l| config.listen = l),
+            }
+        });
+
+        check("unix_socket_mode", {
+            match &doc["unix_socket_mode"] {
+                Yaml::BadValue => Ok(()),
+                // `0660` is read as the decimal integer 660
+                Yaml::Integer(n) => parse_mode(&n.to_string()).map(|m| config.unix_socket_mode = m),
+                Yaml::String(s) => parse_mode(s).map(|m| config.unix_socket_mode = m),
+                _ => Err("expected an octal mode like 0660".into()),
+            }
+        });
+
+        check(
+            "tokens_file",
+            path(&doc["tokens_file"]).map(|p| config.tokens_file = p),
+        );
+        check("hooks_listen", {
+            match &doc["hooks_listen"] {
+                Yaml::BadValue | Yaml::Null => Ok(()),
+                Yaml::String(s) => {
+                    config.hooks_listen = Some(s.clone());
+                    Ok(())
+                }
+                _ => Err("expected host:port".into()),
+            }
+        });
+        check(
+            "hooks_file",
+            path(&doc["hooks_file"]).map(|p| config.hooks_file = p),
+        );
+        check(
+            "tasks_dir",
+            path(&doc["tasks_dir"]).map(|p| config.tasks_dir = p),
+        );
+        check(
+            "calendars_dir",
+            path(&doc["calendars_dir"]).map(|p| config.calendars_dir = p),
+        );
+        check(
+            "secrets_dir",
+            path(&doc["secrets_dir"]).map(|p| config.secrets_dir = p),
+        );
+        check("secrets_env_prefix", {
+            match &doc["secrets_env_prefix"] {
+                Yaml::BadValue | Yaml::Null => Ok(()),
+                Yaml::String(s) => {
+                    config.secrets_env_prefix = Some(s.clone());
+                    Ok(())
+                }
+                _ => Err("expected a prefix like CHAINZ_SECRET_".into()),
+            }
+        });
+        check(
+            "state_file",
+            path(&doc["state_file"]).map(|p| config.state_file = p),
+        );
+
+        check("tls", {
+            match &doc["tls"] {
+                Yaml::BadValue => Ok(()),
+                tls => self::tls(tls).map(|t| config.tls = Some(t)),
+            }
+        });
+
+        check("shutdown_grace", {
+            match &doc["shutdown_grace"] {
+                Yaml::BadValue => Ok(()),
+                Yaml::String(s) => parse_duration(s).map(|d| config.shutdown_grace = d),
+                _ => Err("expected a duration like 30s".into()),
+            }
+        });
+
+        check("max_concurrent_tasks", {
+            match &doc["max_concurrent_tasks"] {
+                Yaml::BadValue => Ok(()),
+                Yaml::Integer(n) => usize::try_from(*n)
+                    .map(|n| config.scheduler.max_concurrent_tasks = n)
+                    .map_err(|_| format!("invalid number: {}", n).into()),
+                _ => Err("expected a number".into()),
+            }
+        });
+
+        check("default_timeout", {
+            match &doc["default_timeout"] {
+                Yaml::BadValue => Ok(()),
+                Yaml::String(s) => {
+                    parse_duration(s).map(|d| config.scheduler.default_timeout = Some(d))
+                }
+                _ => Err("expected a duration like 30m".into()),
+            }
+        });
+
+        check("default_retries", {
+            match &doc["default_retries"] {
+                Yaml::BadValue => Ok(()),
+                Yaml::Integer(n) => u16::try_from(*n)
+                    .map(|n| config.scheduler.default_retries = n)
+                    .map_err(|_| format!("invalid number: {}", n).into()),
+                _ => Err("expected a number".into()),
+            }
+        });
+
+        check("plugin_dirs", {
+            match &doc["plugin_dirs"] {
+                Yaml::BadValue => Ok(()),
+                dirs => strings(dirs).map(|d| {
+                    config.scheduler.plugin_dirs = d.into_iter().map(PathBuf::from).collect()
+                }),
+            }
+        });
+
+        check(
+            "cgroup_root",
+            path(&doc["cgroup_root"]).map(|p| config.scheduler.cgroup_root = p),
+        );
+
+        check("log_format", {
+            match doc["log_format"].as_str() {
+                Some(f) => f.parse().map(|f| config.log_format = f).map_err(Into::into),
+                None if doc["log_format"].is_badvalue() => Ok(()),
+                None => Err("expected pretty | compact | full | json".into()),
+            }
+        });
+
+        check("log_level", {
+            match doc["log_level"].as_str() {
+                Some(l) => parse_level(l).map(|l| config.log_level = l),
+                None if doc["log_level"].is_badvalue() => Ok(()),
+                None => Err("expected trace | debug | info | warn | error".into()),
+            }
+        });
+
+        match errors.is_empty() {
+            true => Ok(config),
+            false => Err(errors.join("\n").into()),
+        }
+    }
+
+    /// Check the config can be used to start a server, all problems are reported at once
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::new();
+
+        if self.listen.is_empty() {
+            errors.push("listen: no addresses to listen on".to_string());
+        }
+
+        for address in &self.listen {
+            match address.strip_prefix(UNIX_PREFIX) {
+                Some(path) => {
+                    if path.is_empty() {
+                        errors.push(format!("listen: no socket path in {}", address));
+                    } else if let Err(e) = parent_exists(Path::new(path)) {
+                        errors.push(format!("listen: {}: {}", address, e));
+                    }
+                }
+                None => {
+                    let valid = address.rsplit_once(':').is_some_and(|(host, port)| {
+                        !host.is_empty() && port.parse::<u16>().is_ok()
+                    });
+
+                    if !valid {
+                        errors.push(format!(
+                            "listen: expected host:port or unix:<path>, got {}",
+                            address
+                        ));
+                    }
+                }
+            }
+        }
+
+        if self.unix_socket_mode > 0o777 {
+            errors.push(format!(
+                "unix_socket_mode: invalid mode {:o}",
+                self.unix_socket_mode
+            ));
+        }
+
+        if let Some(p) = &self.tokens_file {
+            if !p.is_file() {
+                errors.push(format!("tokens_file: {} does not exist", p.display()));
+            }
+        }
+
+        match (&self.hooks_listen, &self.hooks_file) {
+            (Some(address), Some(p)) => {
+                let valid = address
+                    .rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+
+                if !valid {
+                    errors.push(format!("hooks_listen: expected host:port, got {}", address));
+                }
+                if !p.is_file() {
+                    errors.push(format!("hooks_file: {} does not exist", p.display()));
+                }
+            }
+            (Some(_), None) => errors.push("hooks_listen: requires hooks_file".to_string()),
+            (None, Some(_)) => errors.push("hooks_file: requires hooks_listen".to_string()),
+            (None, None) => {}
+        }
+
+        if let Some(tls) = &self.tls {
+            for (key, p) in [
+                ("tls.cert", Some(&tls.cert)),
+                ("tls.key", Some(&tls.key)),
+                ("tls.client_ca", tls.client_ca.as_ref()),
+            ] {
+                if let Some(p) = p {
+                    if !p.is_file() {
+                        errors.push(format!("{}: {} does not exist", key, p.display()));
+                    }
+                }
+            }
+        }
+
+        if let Some(p) = &self.tasks_dir {
+            if !p.is_dir() {
+                errors.push(format!("tasks_dir: {} is not a directory", p.display()));
+            }
+        }
+
+        if let Some(p) = &self.calendars_dir {
+            if !p.is_dir() {
+                errors.push(format!("calendars_dir: {} is not a directory", p.display()));
+            }
+        }
+
+        if let Some(p) = &self.secrets_dir {
+            if !p.is_dir() {
+                errors.push(format!("secrets_dir: {} is not a directory", p.display()));
+            }
+        }
+
+        if self
+            .secrets_env_prefix
+            .as_ref()
+            .is_some_and(|p| p.is_empty())
+        {
+            errors.push("secrets_env_prefix: must not be empty".to_string());
+        }
+
+        if let Some(p) = &self.state_file {
+            if p.is_dir() {
+                errors.push(format!("state_file: {} is a directory", p.display()));
+            } else if let Err(e) = parent_exists(p) {
+                errors.push(format!("state_file: {}", e));
+            }
+        }
+
+        if self.scheduler.max_concurrent_tasks == 0 {
+            errors.push("max_concurrent_tasks: must be at least 1".to_string());
+        }
+
+        if self.scheduler.default_timeout.is_some_and(|t| t.is_zero()) {
+            errors.push("default_timeout: must be longer than 0s".to_string());
+        }
+
+        for p in &self.scheduler.plugin_dirs {
+            if !p.is_dir() {
+                errors.push(format!("plugin_dirs: {} is not a directory", p.display()));
+            }
+        }
+
+        if let Some(p) = &self.scheduler.cgroup_root {
+            if !p.join("cgroup.procs").is_file() {
+                errors.push(format!("cgroup_root: {} is not a cgroup", p.display()));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("\n").into()),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec!["127.0.0.1:3333".to_string()],
+            unix_socket_mode: Server::UNIX_SOCKET_MODE,
+            tokens_file: None,
+            hooks_listen: None,
+            hooks_file: None,
+            tls: None,
+            tasks_dir: None,
+            calendars_dir: None,
+            secrets_dir: None,
+            secrets_env_prefix: None,
+            state_file: None,
+            shutdown_grace: Server::SHUTDOWN_GRACE,
+            scheduler: SchedulerConfig::default(),
+            log_format: LogFormat::Pretty,
+            log_level: Level::INFO,
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "compact" => Ok(LogFormat::Compact),
+            "full" => Ok(LogFormat::Full),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format '{}', expected pretty | compact | full | json",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Pretty => write!(f, "pretty"),
+            LogFormat::Compact => write!(f, "compact"),
+            LogFormat::Full => write!(f, "full"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Parse a log level: trace | debug | info | warn | error
+pub fn parse_level(data: &str) -> Result<Level> {
+    data.parse().map_err(|_| {
+        format!(
+            "unknown log level '{}', expected trace | debug | info | warn | error",
+            data
+        )
+        .into()
+    })
+}
+
+/// Parse unix permissions in octal, `0660` or `660`
+pub fn parse_mode(data: &str) -> Result<u32> {
+    match u32::from_str_radix(data.trim_start_matches("0o"), 8) {
+        Ok(m) if m <= 0o777 => Ok(m),
+        _ => Err(format!("invalid mode '{}', expected an octal mode like 0660", data).into()),
+    }
+}
+
+fn strings(doc: &Yaml) -> Result<Vec<String>> {
+    let items = doc.as_vec().ok_or("expected a list")?;
+
+    items
+        .iter()
+        .map(|i| match i.as_str() {
+            Some(s) => Ok(s.to_string()),
+            None => Err(format!("expected a string, got {:?}", i).into()),
+        })
+        .collect()
+}
+
+fn path(doc: &Yaml) -> Result<Option<PathBuf>> {
+    match doc {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::String(s) => Ok(Some(PathBuf::from(s))),
+        _ => Err("expected a path".into()),
+    }
+}
+
+fn tls(doc: &Yaml) -> Result<ServerTlsConfig> {
+    let hash = doc
+        .as_hash()
+        .ok_or("expected a mapping with cert, key and client_ca")?;
+
+    for key in hash.keys() {
+        match key.as_str() {
+            Some(k) if ServerConfig::TLS_KEYS.contains(&k) => {}
+            _ => return Err(format!("unknown key: {:?}", key).into()),
+        }
+    }
+
+    Ok(ServerTlsConfig {
+        cert: path(&doc["cert"])?.ok_or("no cert provided")?,
+        key: path(&doc["key"])?.ok_or("no key provided")?,
+        client_ca: path(&doc["client_ca"])?,
+    })
+}
+
+fn parent_exists(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+            Err(format!("directory {} does not exist", dir.display()).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Temporary directory, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("chainz-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn errors(result: Result<impl fmt::Debug>) -> Vec<String> {
+        result
+            .unwrap_err()
+            .to_string()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn from_yaml() {
+        let config = ServerConfig::from_yaml_str(
+            "listen: [127.0.0.1:4000, unix:/run/chainz.sock]
+unix_socket_mode: 0600
+tokens_file: tokens.yaml
+hooks_listen: 127.0.0.1:8080
+hooks_file: hooks.yaml
+tls:
+  cert: server.pem
+  key: server.key
+  client_ca: clients.pem
+tasks_dir: tasks
+secrets_env_prefix: CHAINZ_SECRET_
+shutdown_grace: 1m
+max_concurrent_tasks: 8
+default_timeout: 30m
+default_retries: 2
+plugin_dirs: [/opt/chainz/bin]
+log_format: json
+log_level: debug
+",
+        )
+        .unwrap();
+
+        assert_eq!(config.listen, ["127.0.0.1:4000", "unix:/run/chainz.sock"]);
+        assert_eq!(config.unix_socket_mode, 0o600);
+        assert_eq!(config.tokens_file, Some(PathBuf::from("tokens.yaml")));
+        assert_eq!(config.hooks_listen.as_deref(), Some("127.0.0.1:8080"));
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.client_ca, Some(PathBuf::from("clients.pem")));
+        assert_eq!(config.secrets_env_prefix.as_deref(), Some("CHAINZ_SECRET_"));
+        assert_eq!(config.shutdown_grace, Duration::from_secs(60));
+        assert_eq!(config.scheduler.max_concurrent_tasks, 8);
+        assert_eq!(
+            config.scheduler.default_timeout,
+            Some(Duration::from_secs(1800))
+        );
+        assert_eq!(config.scheduler.default_retries, 2);
+        assert_eq!(
+            config.scheduler.plugin_dirs,
+            [PathBuf::from("/opt/chainz/bin")]
+        );
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.log_level, Level::DEBUG);
+
+        // A single address, and keys left out keep their defaults
+        let config = ServerConfig::from_yaml_str("listen: 0.0.0.0:3333").unwrap();
+        assert_eq!(config.listen, ["0.0.0.0:3333"]);
+        assert_eq!(config.unix_socket_mode, Server::UNIX_SOCKET_MODE);
+        assert_eq!(config.scheduler.max_concurrent_tasks, 4);
+        assert!(config.tls.is_none());
+
+        for data in ["", "# nothing", "~"] {
+            let config = ServerConfig::from_yaml_str(data).unwrap();
+            assert_eq!(config.listen, ["127.0.0.1:3333"]);
+        }
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert_eq!(
+            errors(ServerConfig::from_yaml_str(
+                "listen: 127.0.0.1:3333\nport: 3333"
+            )),
+            ["unknown key: String(\"port\")"]
+        );
+        assert_eq!(
+            errors(ServerConfig::from_yaml_str(
+                "tls:\n  cert: a.pem\n  key: a.key\n  ca: ca.pem"
+            )),
+            ["tls: unknown key: String(\"ca\")"]
+        );
+
+        // Every error at once
+        assert_eq!(
+            errors(ServerConfig::from_yaml_str(
+                "unix_socket_mode: 999
+shutdown_grace: soon
+max_concurrent_tasks: -1
+default_retries: lots
+plugin_dirs: /opt
+log_format: xml
+log_level: loud
+tls:
+  cert: a.pem
+"
+            )),
+            [
+                "unix_socket_mode: invalid mode '999', expected an octal mode like 0660",
+                "tls: no key provided",
+                "shutdown_grace: invalid duration: soon",
+                "max_concurrent_tasks: invalid number: -1",
+                "default_retries: expected a number",
+                "plugin_dirs: expected a list",
+                "log_format: unknown log format 'xml', expected pretty | compact | full | json",
+                "log_level: unknown log level 'loud', expected trace | debug | info | warn | error",
+            ]
+        );
+
+        for data in ["- listen", "a: 1\n---\nb: 2", "listen: [1, 2]"] {
+            assert!(ServerConfig::from_yaml_str(data).is_err(), "{}", data);
+        }
+    }
+
+    #[test]
+    fn from_file() {
+        let dir = TempDir::new("config");
+        let path = dir.0.join("chainz.yaml");
+
+        std::fs::write(&path, "max_concurrent_tasks: 2\n").unwrap();
+        let config = ServerConfig::from_file(&path).unwrap();
+        assert_eq!(config.scheduler.max_concurrent_tasks, 2);
+
+        std::fs::write(&path, "max_concurrent_tasks: two\n").unwrap();
+        let err = ServerConfig::from_file(&path).unwrap_err().to_string();
+        assert!(
+            err.starts_with(&format!("invalid config {}", path.display())),
+            "{}",
+            err
+        );
+
+        let missing = dir.0.join("missing.yaml");
+        let err = ServerConfig::from_file(&missing).unwrap_err().to_string();
+        assert!(err.starts_with("failed to read config"), "{}", err);
+    }
+
+    #[test]
+    fn validate() {
+        let dir = TempDir::new("config-validate");
+        std::fs::write(dir.0.join("tokens.yaml"), "tokens: []").unwrap();
+
+        let mut config = ServerConfig {
+            listen: vec![
+                "127.0.0.1:3333".to_string(),
+                format!("unix:{}", dir.0.join("chainz.sock").display()),
+            ],
+            tokens_file: Some(dir.0.join("tokens.yaml")),
+            tasks_dir: Some(dir.0.clone()),
+            state_file: Some(dir.0.join("state.yaml")),
+            ..ServerConfig::default()
+        };
+        config.validate().unwrap();
+
+        config.listen = vec![
+            "localhost".to_string(),
+            ":3333".to_string(),
+            "127.0.0.1:99999".to_string(),
+            "unix:".to_string(),
+            "unix:/nonexistent/chainz.sock".to_string(),
+        ];
+        config.tokens_file = Some(dir.0.join("missing.yaml"));
+        config.hooks_listen = Some("127.0.0.1:8080".to_string());
+        config.tasks_dir = Some(dir.0.join("tokens.yaml"));
+        config.secrets_env_prefix = Some(String::new());
+        config.state_file = Some(dir.0.clone());
+        config.scheduler.max_concurrent_tasks = 0;
+        config.scheduler.default_timeout = Some(Duration::ZERO);
+
+        let tokens = dir.0.join("tokens.yaml").display().to_string();
+        assert_eq!(
+            errors(config.validate()),
+            [
+                "listen: expected host:port or unix:<path>, got localhost".to_string(),
+                "listen: expected host:port or unix:<path>, got :3333".to_string(),
+                "listen: expected host:port or unix:<path>, got 127.0.0.1:99999".to_string(),
+                "listen: no socket path in unix:".to_string(),
+                "listen: unix:/nonexistent/chainz.sock: directory /nonexistent does not exist"
+                    .to_string(),
+                format!(
+                    "tokens_file: {}/missing.yaml does not exist",
+                    dir.0.display()
+                ),
+                "hooks_listen: requires hooks_file".to_string(),
+                format!("tasks_dir: {} is not a directory", tokens),
+                "secrets_env_prefix: must not be empty".to_string(),
+                format!("state_file: {} is a directory", dir.0.display()),
+                "max_concurrent_tasks: must be at least 1".to_string(),
+                "default_timeout: must be longer than 0s".to_string(),
+            ]
+        );
+
+        config.listen.clear();
+        assert!(
+            errors(config.validate()).contains(&"listen: no addresses to listen on".to_string())
+        );
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(parse_mode("0660").unwrap(), 0o660);
+        assert_eq!(parse_mode("660").unwrap(), 0o660);
+        assert_eq!(parse_mode("0o600").unwrap(), 0o600);
+        assert_eq!(parse_mode("0").unwrap(), 0);
+
+        for data in ["", "0888", "1777", "rw-rw----", "-1"] {
+            assert!(parse_mode(data).is_err(), "{}", data);
+        }
+    }
+}
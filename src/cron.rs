@@ -0,0 +1,663 @@
+//! Cron expressions evaluated in a time zone
+//!
+//! Expressions are matched against the local time of the task's time zone, so
+//! `30 2 * * *` in `Europe/Oslo` runs at 02:30 on the clock in Oslo, whatever its UTC offset.
+//! Daylight saving transitions are resolved as follows:
+//! - gap: a local time skipped when clocks move forward runs shifted forward by the length of
+//!   the gap, 02:30 becomes 03:30 on the day summer time starts. It is merged with a run of the
+//!   expression at that time, if any.
+//! - overlap: a local time repeated when clocks move back runs once, at its first occurrence.
+//!   Expressions running every hour (`*` as hour) run at both, so they keep their period.
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use chrono::{
+    DateTime, Datelike, LocalResult, NaiveDate, NaiveDateTime, Offset, TimeDelta, TimeZone,
+    Timelike, Utc,
+};
+use chrono_tz::Tz;
+
+use crate::Result;
+
+/// Parsed cron expression: `minute hour day-of-month month day-of-week`.
+///
+/// Fields accept `*`, values, ranges `a-b`, steps `*/n`, `a-b/n` and `a/n`, and lists of
+/// those separated by `,`. Months and days of the week also accept names (`jan`, `mon`),
+/// sunday is `0` or `7`. When both day fields are restricted a day matching either runs.
+/// `@yearly`, `@monthly`, `@weekly`, `@daily` and `@hourly` are shorthands.
+///
+/// `H` stands for a value derived from a hash of the task, as in Jenkins, so tasks sharing
+/// an expression run at different but stable times: `H`, `H(a-b)`, `H/n` and `H(a-b)/n`.
+/// Days of the month from `H` are at most 28, to exist in every month.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    /// Day of month or day of week is `*`, days must match both
+    all_days: bool,
+}
+
+/// Values accepted by a field
+struct Field {
+    min: u32,
+    max: u32,
+    /// Names of the values from `first` on
+    names: &'static [&'static str],
+    first: u32,
+    /// Largest value of `H`
+    hash_max: u32,
+}
+
+const MINUTE: Field = Field {
+    min: 0,
+    max: 59,
+    names: &[],
+    first: 0,
+    hash_max: 59,
+};
+const HOUR: Field = Field {
+    min: 0,
+    max: 23,
+    names: &[],
+    first: 0,
+    hash_max: 23,
+};
+const DAY: Field = Field {
+    min: 1,
+    max: 31,
+    names: &[],
+    first: 0,
+    hash_max: 28,
+};
+const MONTH: Field = Field {
+    min: 1,
+    max: 12,
+    names: &[
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ],
+    first: 1,
+    hash_max: 12,
+};
+const WEEKDAY: Field = Field {
+    min: 0,
+    max: 7,
+    names: &["sun", "mon", "tue", "wed", "thu", "fri", "sat"],
+    first: 0,
+    hash_max: 6,
+};
+
+/// Bound of the difference between local time and UTC in any time zone
+const MAX_OFFSET: TimeDelta = TimeDelta::hours(15);
+
+/// Longest time searched for a point of an expression, long enough for February 29th
+/// across a century without a leap year
+const MAX_SEARCH: Duration = Duration::from_secs(60 * 60 * 24 * 366 * 9);
+
+impl Cron {
+    /// Parse an expression, `H` is resolved for an empty key, see [`Cron::hashed`]
+    pub fn parse(data: &str) -> Result<Self> {
+        Cron::parse_hashed(data, "")
+    }
+
+    /// Same expression with `H` resolved for `key`, the id of the task
+    pub fn hashed(&self, key: &str) -> Result<Self> {
+        Cron::parse_hashed(&self.expr, key)
+    }
+
+    fn parse_hashed(data: &str, key: &str) -> Result<Self> {
+        let expr = data.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        let fields = match expr.as_str() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            e => e,
+        };
+
+        let fields: Vec<&str> = fields.split(' ').collect();
+        let [minute, hour, day, month, weekday] = fields.as_slice() else {
+            return Err(format!(
+                "invalid cron expression '{}', expected 5 fields: minute hour day-of-month month day-of-week",
+                expr
+            )
+            .into());
+        };
+
+        // Separate values per field, `H H * * *` does not run at 05:05 or 17:17 only
+        let hash = |i: &str| stable_hash(&format!("{}\0{}", key, i));
+
+        let weekdays = field(weekday, &WEEKDAY, hash("weekday"))?;
+
+        let cron = Cron {
+            minutes: field(minute, &MINUTE, hash("minute"))?,
+            hours: field(hour, &HOUR, hash("hour"))? as u32,
+            days: field(day, &DAY, hash("day"))? as u32,
+            months: field(month, &MONTH, hash("month"))? as u16,
+            // Sunday is both 0 and 7
+            weekdays: ((weekdays | weekdays >> 7) & 0x7f) as u8,
+            all_days: day.starts_with('*') || weekday.starts_with('*'),
+            expr,
+        };
+
+        // e.g. `0 0 31 2 *`
+        let start = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap().into();
+        if cron
+            .next_local(start, start + TimeDelta::days(366 * 9))
+            .is_none()
+        {
+            return Err(format!("cron expression '{}' never matches", cron.expr).into());
+        }
+
+        Ok(cron)
+    }
+
+    /// First point after `after`
+    pub fn next_after(&self, after: SystemTime, tz: Tz) -> Option<SystemTime> {
+        let from = after + Duration::from_nanos(1);
+
+        search_windows().find_map(|w| self.points(from, from + w, tz).first().copied())
+    }
+
+    /// Last point before `before`
+    pub fn prev_before(&self, before: SystemTime, tz: Tz) -> Option<SystemTime> {
+        search_windows().find_map(|w| self.points(before - w, before, tz).last().copied())
+    }
+
+    /// Points in `[from, to)`, oldest first
+    pub fn points(&self, from: SystemTime, to: SystemTime, tz: Tz) -> Vec<SystemTime> {
+        let mut local = ceil_minute(DateTime::<Utc>::from(from).naive_utc() - MAX_OFFSET);
+        let end = DateTime::<Utc>::from(to).naive_utc() + MAX_OFFSET;
+
+        let mut points = Vec::new();
+
+        while let Some(t) = self.next_local(local, end) {
+            points.extend(
+                self.resolve(t, tz)
+                    .into_iter()
+                    .flatten()
+                    .filter(|p| (from..to).contains(p)),
+            );
+            local = t + TimeDelta::minutes(1);
+        }
+
+        points.sort();
+        points.dedup();
+        points
+    }
+
+    /// First local time matching the expression in `[t, end)`, `t` is at a whole minute
+    fn next_local(&self, mut t: NaiveDateTime, end: NaiveDateTime) -> Option<NaiveDateTime> {
+        while t < end {
+            if self.months & (1 << t.month()) == 0 {
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    m => (t.year(), m + 1),
+                };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.into();
+            } else if !self.day_matches(t.date()) {
+                t = t.date().succ_opt()?.into();
+            } else if self.hours & (1 << t.hour()) == 0 {
+                t = t.with_minute(0)? + TimeDelta::hours(1);
+            } else if self.minutes & (1 << t.minute()) == 0 {
+                t += TimeDelta::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+
+        None
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        match self.all_days {
+            true => day && weekday,
+            false => day || weekday,
+        }
+    }
+
+    /// Instants a matching local time runs at, see the module documentation for transitions
+    fn resolve(&self, local: NaiveDateTime, tz: Tz) -> [Option<SystemTime>; 2] {
+        match tz.from_local_datetime(&local) {
+            LocalResult::Ambiguous(first, second) if self.hours == (1 << 24) - 1 => {
+                [Some(first.into()), Some(second.into())]
+            }
+            _ => [resolve_local(local, tz), None],
+        }
+    }
+}
+
+/// Instant of a local time in `tz`: its first occurrence if repeated, shifted forward by the
+/// length of the gap if skipped, see the module documentation
+pub(crate) fn resolve_local(local: NaiveDateTime, tz: Tz) -> Option<SystemTime> {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Some(t.into()),
+        // Local time of the offset before the gap
+        LocalResult::None => {
+            let before = (1..=48).find_map(|h| {
+                tz.from_local_datetime(&(local - TimeDelta::hours(h)))
+                    .earliest()
+            })?;
+
+            before
+                .offset()
+                .fix()
+                .from_local_datetime(&local)
+                .single()
+                .map(SystemTime::from)
+        }
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+/// Growing spans searched for a point, so frequent expressions only look at the near future
+fn search_windows() -> impl Iterator<Item = Duration> {
+    [60 * 60, 60 * 60 * 24, 60 * 60 * 24 * 32, 60 * 60 * 24 * 366]
+        .into_iter()
+        .map(Duration::from_secs)
+        .chain([MAX_SEARCH])
+}
+
+fn ceil_minute(t: NaiveDateTime) -> NaiveDateTime {
+    let floor = t.with_second(0).unwrap().with_nanosecond(0).unwrap();
+
+    match floor == t {
+        true => t,
+        false => floor + TimeDelta::minutes(1),
+    }
+}
+
+/// 64 bit FNV-1a, unlike the hasher of std stable across builds
+pub(crate) fn stable_hash(data: &str) -> u64 {
+    data.bytes().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Bit set of the values of a field, `hash` picks the values of `H`
+fn field(data: &str, f: &Field, hash: u64) -> Result<u64> {
+    let (min, max) = (f.min, f.max);
+
+    let value = |v: &str| -> Result<u32> {
+        let n = match f.names.iter().position(|n| n.eq_ignore_ascii_case(v)) {
+            Some(i) => f.first + i as u32,
+            None => v
+                .parse()
+                .map_err(|_| format!("invalid value in cron field '{}': {}", data, v))?,
+        };
+
+        match (min..=max).contains(&n) {
+            true => Ok(n),
+            false => Err(format!(
+                "value out of range in cron field '{}': {} ({}-{})",
+                data, v, min, max
+            )
+            .into()),
+        }
+    };
+
+    let mut bits = 0u64;
+
+    for part in data.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((r, s)) => match s.parse::<usize>() {
+                Ok(s) if s > 0 => (r, Some(s)),
+                _ => return Err(format!("invalid step in cron field '{}': {}", data, s).into()),
+            },
+            None => (part, None),
+        };
+
+        if let Some(h) = range.strip_prefix('H') {
+            let (lo, hi) = match h.strip_prefix('(').and_then(|h| h.strip_suffix(')')) {
+                Some(r) => match r.split_once('-') {
+                    Some((a, b)) => (value(a)?, value(b)?),
+                    None => return Err(format!("invalid H in cron field '{}'", data).into()),
+                },
+                None if h.is_empty() => (min, f.hash_max),
+                None => return Err(format!("invalid H in cron field '{}'", data).into()),
+            };
+
+            if lo > hi {
+                return Err(format!("invalid range in cron field '{}': {}", data, range).into());
+            }
+
+            // First value within the first step, or within the range if shorter
+            let span = step.unwrap_or(usize::MAX).min((hi - lo + 1) as usize);
+            let start = lo + (hash % span as u64) as u32;
+
+            match step {
+                Some(n) => (start..=hi).step_by(n).for_each(|v| bits |= 1 << v),
+                None => bits |= 1 << start,
+            }
+            continue;
+        }
+
+        let (lo, hi) = match (range, range.split_once('-')) {
+            ("*", _) => (min, max),
+            (_, Some((a, b))) => (value(a)?, value(b)?),
+            // `a/n` runs from `a` to the end of the range
+            (a, None) if step.is_some() => (value(a)?, max),
+            (a, None) => (value(a)?, value(a)?),
+        };
+
+        if lo > hi {
+            return Err(format!("invalid range in cron field '{}': {}", data, range).into());
+        }
+
+        for v in (lo..=hi).step_by(step.unwrap_or(1)) {
+            bits |= 1 << v;
+        }
+    }
+
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::{format_time, parse_time};
+
+    fn t(data: &str) -> SystemTime {
+        parse_time(data).unwrap()
+    }
+
+    /// Points of `expr` in `tz` within `[from, to)`
+    fn points(expr: &str, tz: Tz, from: &str, to: &str) -> Vec<String> {
+        Cron::parse(expr)
+            .unwrap()
+            .points(t(from), t(to), tz)
+            .into_iter()
+            .map(format_time)
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        for expr in [
+            "* * * * *",
+            "*/15 9-17 * * mon-fri",
+            "0 0 1,15 jan,JUL *",
+            "5/20 * * * 7",
+            "@daily",
+        ] {
+            assert!(Cron::parse(expr).is_ok(), "{}", expr);
+        }
+
+        for expr in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "0 24 * * *",
+            "0 0 0 * *",
+            "0 0 * 13 *",
+            "0 0 * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "x * * * *",
+            "0 0 31 2 *",
+        ] {
+            assert!(Cron::parse(expr).is_err(), "{}", expr);
+        }
+
+        assert_eq!(Cron::parse("0  0 * * *").unwrap().to_string(), "0 0 * * *");
+    }
+
+    #[test]
+    fn next_and_previous_points() {
+        let cron = Cron::parse("*/15 9-17 * * mon-fri").unwrap();
+
+        // Saturday
+        let next = cron.next_after(t("2024-03-09T12:00:00Z"), Tz::UTC);
+        assert_eq!(next, Some(t("2024-03-11T09:00:00Z")));
+        let prev = cron.prev_before(t("2024-03-09T12:00:00Z"), Tz::UTC);
+        assert_eq!(prev, Some(t("2024-03-08T17:45:00Z")));
+
+        // Strictly after and before
+        let next = cron.next_after(t("2024-03-11T09:00:00Z"), Tz::UTC);
+        assert_eq!(next, Some(t("2024-03-11T09:15:00Z")));
+        let prev = cron.prev_before(t("2024-03-11T09:15:00Z"), Tz::UTC);
+        assert_eq!(prev, Some(t("2024-03-11T09:00:00Z")));
+
+        let leap = Cron::parse("0 0 29 2 *").unwrap();
+        let next = leap.next_after(t("2024-03-01T00:00:00Z"), Tz::UTC);
+        assert_eq!(next, Some(t("2028-02-29T00:00:00Z")));
+    }
+
+    #[test]
+    fn restricted_days_match_either_field() {
+        // The 13th and every friday of September 2024
+        assert_eq!(
+            points(
+                "0 0 13 * fri",
+                Tz::UTC,
+                "2024-09-01T00:00:00Z",
+                "2024-10-01T00:00:00Z"
+            ),
+            [
+                "2024-09-06T00:00:00Z",
+                "2024-09-13T00:00:00Z",
+                "2024-09-20T00:00:00Z",
+                "2024-09-27T00:00:00Z",
+            ]
+        );
+
+        // Both with a `*`-prefixed field: fridays on the 1st, 14th or 27th
+        assert_eq!(
+            points(
+                "0 0 */13 * fri",
+                Tz::UTC,
+                "2024-01-01T00:00:00Z",
+                "2025-01-01T00:00:00Z"
+            ),
+            [
+                "2024-03-01T00:00:00Z",
+                "2024-06-14T00:00:00Z",
+                "2024-09-27T00:00:00Z",
+                "2024-11-01T00:00:00Z",
+                "2024-12-27T00:00:00Z",
+            ]
+        );
+    }
+
+    #[test]
+    fn local_time_of_the_zone() {
+        let berlin = Tz::Europe__Berlin;
+
+        assert_eq!(
+            points(
+                "30 2 * * *",
+                berlin,
+                "2024-01-10T00:00:00Z",
+                "2024-01-11T00:00:00Z"
+            ),
+            ["2024-01-10T01:30:00Z"]
+        );
+        assert_eq!(
+            points(
+                "30 2 * * *",
+                berlin,
+                "2024-07-10T00:00:00Z",
+                "2024-07-11T00:00:00Z"
+            ),
+            ["2024-07-10T00:30:00Z"]
+        );
+    }
+
+    #[test]
+    fn daylight_saving_gap() {
+        // 02:00 CET jumps to 03:00 CEST on 2024-03-31, 02:30 runs at 03:30 CEST
+        let berlin = Tz::Europe__Berlin;
+        assert_eq!(
+            points(
+                "30 2 * * *",
+                berlin,
+                "2024-03-30T00:00:00Z",
+                "2024-04-02T00:00:00Z"
+            ),
+            [
+                "2024-03-30T01:30:00Z",
+                "2024-03-31T01:30:00Z",
+                "2024-04-01T00:30:00Z",
+            ]
+        );
+
+        // Merged with the run at 03:30
+        assert_eq!(
+            points(
+                "30 2,3 * * *",
+                berlin,
+                "2024-03-31T00:00:00Z",
+                "2024-04-01T00:00:00Z"
+            ),
+            ["2024-03-31T01:30:00Z"]
+        );
+
+        // 02:00 EST jumps to 03:00 EDT on 2024-03-10
+        let new_york = Tz::America__New_York;
+        assert_eq!(
+            points(
+                "30 2 * * *",
+                new_york,
+                "2024-03-10T00:00:00Z",
+                "2024-03-11T00:00:00Z"
+            ),
+            ["2024-03-10T07:30:00Z"]
+        );
+        assert_eq!(
+            points(
+                "0 * * * *",
+                new_york,
+                "2024-03-10T05:00:00Z",
+                "2024-03-10T09:00:00Z"
+            ),
+            [
+                "2024-03-10T05:00:00Z",
+                "2024-03-10T06:00:00Z",
+                "2024-03-10T07:00:00Z",
+                "2024-03-10T08:00:00Z",
+            ]
+        );
+    }
+
+    #[test]
+    fn daylight_saving_overlap() {
+        // 03:00 CEST goes back to 02:00 CET on 2024-10-27, 02:30 runs at its first occurrence
+        let berlin = Tz::Europe__Berlin;
+        assert_eq!(
+            points(
+                "30 2 * * *",
+                berlin,
+                "2024-10-27T00:00:00Z",
+                "2024-10-28T00:00:00Z"
+            ),
+            ["2024-10-27T00:30:00Z"]
+        );
+
+        // 02:00 EDT goes back to 01:00 EST on 2024-11-03
+        let new_york = Tz::America__New_York;
+        assert_eq!(
+            points(
+                "30 1 * * *",
+                new_york,
+                "2024-11-03T00:00:00Z",
+                "2024-11-04T00:00:00Z"
+            ),
+            ["2024-11-03T05:30:00Z"]
+        );
+
+        // Hourly runs keep their period through the repeated hour
+        assert_eq!(
+            points(
+                "30 * * * *",
+                new_york,
+                "2024-11-03T04:00:00Z",
+                "2024-11-03T08:00:00Z"
+            ),
+            [
+                "2024-11-03T04:30:00Z",
+                "2024-11-03T05:30:00Z",
+                "2024-11-03T06:30:00Z",
+                "2024-11-03T07:30:00Z",
+            ]
+        );
+    }
+
+    /// Minutes of the points of `expr` hashed for `key` in an hour
+    fn hashed_minutes(expr: &str, key: &str) -> Vec<u32> {
+        Cron::parse(expr)
+            .unwrap()
+            .hashed(key)
+            .unwrap()
+            .points(
+                t("2024-01-01T00:00:00Z"),
+                t("2024-01-01T01:00:00Z"),
+                Tz::UTC,
+            )
+            .into_iter()
+            .map(|p| DateTime::<Utc>::from(p).minute())
+            .collect()
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        // FNV-1a test vectors, schedules must not move between builds
+        assert_eq!(stable_hash(""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash("foobar"), 0x85944171f73967e8);
+
+        let cron = Cron::parse("H H * * *").unwrap();
+        assert_eq!(
+            cron.hashed("load_orders").unwrap(),
+            cron.hashed("load_orders").unwrap()
+        );
+        assert_eq!(cron.hashed("load_orders").unwrap().to_string(), "H H * * *");
+        // FNV-1a of "load_orders\0minute" modulo 60
+        assert_eq!(hashed_minutes("H * * * *", "load_orders"), [53]);
+    }
+
+    #[test]
+    fn hash_spreads_tasks() {
+        let minutes: std::collections::BTreeSet<Vec<u32>> = (0..20)
+            .map(|i| hashed_minutes("H * * * *", &format!("task_{}", i)))
+            .collect();
+        assert!(minutes.len() > 5, "{:?}", minutes);
+
+        for i in 0..50 {
+            let key = format!("task_{}", i);
+
+            let minutes = hashed_minutes("H(10-19)/5 * * * *", &key);
+            assert_eq!(minutes.len(), 2, "{:?}", minutes);
+            assert!((10..15).contains(&minutes[0]), "{:?}", minutes);
+            assert_eq!(minutes[1], minutes[0] + 5);
+
+            let cron = Cron::parse("0 0 H * *").unwrap().hashed(&key).unwrap();
+            assert!((1..=28).contains(&cron.days.trailing_zeros()));
+        }
+    }
+
+    #[test]
+    fn hash_errors() {
+        for expr in [
+            "H(5) * * * *",
+            "H(10-5) * * * *",
+            "H5 * * * *",
+            "H(0-60) * * * *",
+        ] {
+            assert!(Cron::parse(expr).is_err(), "{}", expr);
+        }
+    }
+}
@@ -0,0 +1,438 @@
+//! Datasets, logical names of data like `warehouse.orders`
+//!
+//! Tasks list the datasets their successful runs update in `produces`. A task scheduled on
+//! `datasets:<name>,...` runs once every dataset of its schedule was updated since it last
+//! ran, so pipelines connect through their data instead of naming each other.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::SystemTime;
+
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::task::{ScheduleType, Task, TaskId, TaskInstance};
+use crate::time::{format_time, parse_time};
+use crate::Result;
+
+pub type DatasetId = String;
+
+/// Latest update of a dataset
+#[derive(Debug, Clone)]
+pub struct Update {
+    /// Run that updated it
+    pub instance_id: String,
+    pub task_id: TaskId,
+    pub logical_date: SystemTime,
+    pub updated_at: SystemTime,
+}
+
+/// Updates of datasets and the consumers waiting for them
+#[derive(Debug, Default)]
+pub struct Datasets {
+    updates: HashMap<DatasetId, Update>,
+    /// Datasets updated since the last run of each consumer
+    pending: HashMap<TaskId, BTreeSet<DatasetId>>,
+}
+
+/// Check a dataset name: letters, digits, `.`, `_` and `-`
+pub fn validate_dataset_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "invalid dataset name '{}', expected letters, digits, '.', '_' and '-'",
+            name
+        )
+        .into()),
+    }
+}
+
+impl Datasets {
+    /// Record the datasets updated by a successful run of `producer`.
+    /// Returns the consumers in `tasks` all of whose datasets are now updated, their pending
+    /// updates are cleared.
+    pub fn update<'a>(
+        &mut self,
+        producer: &TaskInstance,
+        tasks: impl Iterator<Item = &'a Task>,
+    ) -> Vec<TaskId> {
+        let now = SystemTime::now();
+
+        for dataset in &producer.task.produces {
+            self.updates.insert(
+                dataset.clone(),
+                Update {
+                    instance_id: producer.instance_id.clone(),
+                    task_id: producer.task.task_id.clone(),
+                    logical_date: producer.logical_date,
+                    updated_at: now,
+                },
+            );
+        }
+
+        let mut ready = Vec::new();
+
+        for task in tasks {
+            let datasets = match &task.schedule {
+                ScheduleType::Datasets(d) => d,
+                _ => continue,
+            };
+
+            if !producer.task.produces.iter().any(|d| datasets.contains(d)) {
+                continue;
+            }
+
+            let pending = self.pending.entry(task.task_id.clone()).or_default();
+            pending.extend(
+                producer
+                    .task
+                    .produces
+                    .iter()
+                    .filter(|d| datasets.contains(d))
+                    .cloned(),
+            );
+
+            if datasets.iter().all(|d| pending.contains(d)) {
+                self.pending.remove(&task.task_id);
+                ready.push(task.task_id.clone());
+            }
+        }
+
+        ready.sort();
+        ready
+    }
+
+    /// Datasets updated since the last run of `task_id`
+    pub fn pending(&self, task_id: &str) -> Vec<DatasetId> {
+        self.pending
+            .get(task_id)
+            .map(|p| p.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget the pending updates of a removed consumer
+    pub fn remove_consumer(&mut self, task_id: &str) {
+        self.pending.remove(task_id);
+    }
+
+    /// Every dataset updated or referred to by `tasks`, with its latest update and consumers
+    pub fn to_yaml<'a>(&self, tasks: impl Iterator<Item = &'a Task>) -> Yaml {
+        let mut producers: HashMap<&str, BTreeSet<&str>> = HashMap::new();
+        let mut consumers: HashMap<&str, BTreeSet<&str>> = HashMap::new();
+
+        for task in tasks {
+            for d in &task.produces {
+                producers.entry(d).or_default().insert(&task.task_id);
+            }
+            if let ScheduleType::Datasets(datasets) = &task.schedule {
+                for d in datasets {
+                    consumers.entry(d).or_default().insert(&task.task_id);
+                }
+            }
+        }
+
+        let names: BTreeSet<&str> = self
+            .updates
+            .keys()
+            .map(String::as_str)
+            .chain(producers.keys().copied())
+            .chain(consumers.keys().copied())
+            .collect();
+
+        let list = |ids: Option<&BTreeSet<&str>>| {
+            Yaml::Array(
+                ids.into_iter()
+                    .flatten()
+                    .map(|id| Yaml::String(id.to_string()))
+                    .collect(),
+            )
+        };
+
+        let datasets = names
+            .into_iter()
+            .map(|name| {
+                let mut h = Hash::new();
+
+                h.insert(
+                    Yaml::String("dataset".into()),
+                    Yaml::String(name.to_string()),
+                );
+                h.insert(Yaml::String("producers".into()), list(producers.get(name)));
+                h.insert(Yaml::String("consumers".into()), list(consumers.get(name)));
+                if let Some(update) = self.updates.get(name) {
+                    h.insert(
+                        Yaml::String("updated_at".into()),
+                        Yaml::String(format_time(update.updated_at)),
+                    );
+                    h.insert(
+                        Yaml::String("updated_by".into()),
+                        Yaml::String(update.instance_id.clone()),
+                    );
+                }
+
+                Yaml::Hash(h)
+            })
+            .collect();
+
+        Yaml::Array(datasets)
+    }
+
+    /// Updates and pending updates, as saved in the state file
+    pub fn to_state_yaml(&self) -> Yaml {
+        let mut updates: Vec<(&DatasetId, &Update)> = self.updates.iter().collect();
+        updates.sort_by_key(|(name, _)| *name);
+
+        let mut pending: Vec<(&TaskId, &BTreeSet<DatasetId>)> = self.pending.iter().collect();
+        pending.sort_by_key(|(id, _)| *id);
+
+        let mut h = Hash::new();
+        h.insert(
+            Yaml::String("updates".into()),
+            Yaml::Hash(
+                updates
+                    .into_iter()
+                    .map(|(name, u)| (Yaml::String(name.clone()), u.to_yaml()))
+                    .collect(),
+            ),
+        );
+        h.insert(
+            Yaml::String("pending".into()),
+            Yaml::Hash(
+                pending
+                    .into_iter()
+                    .map(|(id, datasets)| {
+                        (
+                            Yaml::String(id.clone()),
+                            Yaml::Array(datasets.iter().cloned().map(Yaml::String).collect()),
+                        )
+                    })
+                    .collect(),
+            ),
+        );
+
+        Yaml::Hash(h)
+    }
+
+    /// Parse what [`Datasets::to_state_yaml`] wrote
+    pub fn from_state_yaml(doc: &Yaml) -> Result<Self> {
+        let mut datasets = Datasets::default();
+
+        if let Some(h) = doc["updates"].as_hash() {
+            for (name, u) in h {
+                let name = name
+                    .as_str()
+                    .ok_or_else(|| format!("invalid dataset: {:?}", name))?;
+                datasets
+                    .updates
+                    .insert(name.to_string(), Update::from_yaml(u)?);
+            }
+        }
+
+        if let Some(h) = doc["pending"].as_hash() {
+            for (id, names) in h {
+                match (id.as_str(), names.as_vec()) {
+                    (Some(id), Some(names)) => datasets.pending.insert(
+                        id.to_string(),
+                        names
+                            .iter()
+                            .filter_map(|n| n.as_str().map(str::to_string))
+                            .collect(),
+                    ),
+                    _ => return Err(format!("invalid pending datasets: {:?}", id).into()),
+                };
+            }
+        }
+
+        Ok(datasets)
+    }
+}
+
+impl Update {
+    fn to_yaml(&self) -> Yaml {
+        let mut h = Hash::new();
+
+        h.insert(
+            Yaml::String("instance_id".into()),
+            Yaml::String(self.instance_id.clone()),
+        );
+        h.insert(
+            Yaml::String("task_id".into()),
+            Yaml::String(self.task_id.clone()),
+        );
+        h.insert(
+            Yaml::String("logical_date".into()),
+            Yaml::String(format_time(self.logical_date)),
+        );
+        h.insert(
+            Yaml::String("updated_at".into()),
+            Yaml::String(format_time(self.updated_at)),
+        );
+
+        Yaml::Hash(h)
+    }
+
+    fn from_yaml(doc: &Yaml) -> Result<Self> {
+        let field = |key: &str| {
+            doc[key]
+                .as_str()
+                .ok_or_else(|| format!("dataset update without {}: {:?}", key, doc))
+        };
+
+        Ok(Update {
+            instance_id: field("instance_id")?.to_string(),
+            task_id: field("task_id")?.to_string(),
+            logical_date: parse_time(field("logical_date")?)?,
+            updated_at: parse_time(field("updated_at")?)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::scheduler::{Scheduler, TriggerOptions};
+
+    fn task(definition: &str) -> Task {
+        Task::from_yaml_str(definition).unwrap()
+    }
+
+    fn producer(task_id: &str, produces: &str) -> TaskInstance {
+        let task = task(&format!(
+            "task_id: {}\nschedule: interval:1h\nproduces: [{}]\ncmd: run",
+            task_id, produces
+        ));
+        TaskInstance::new(task, SystemTime::now(), 0)
+    }
+
+    #[test]
+    fn dataset_names() {
+        for name in ["warehouse.orders", "orders_2024", "raw-events"] {
+            assert!(validate_dataset_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", "warehouse/orders", "a b", "orders*"] {
+            assert!(validate_dataset_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn consumers_wait_for_every_dataset() {
+        let tasks = [
+            task("task_id: report\nschedule: datasets:orders,customers\ncmd: run"),
+            task("task_id: audit\nschedule: datasets:orders\ncmd: run"),
+            task("task_id: other\nschedule: datasets:events\ncmd: run"),
+            task("task_id: hourly\nschedule: interval:1h\ncmd: run"),
+        ];
+        let mut datasets = Datasets::default();
+
+        let orders = producer("load_orders", "orders");
+        assert_eq!(datasets.update(&orders, tasks.iter()), ["audit"]);
+        assert_eq!(datasets.pending("report"), ["orders"]);
+        assert!(datasets.pending("audit").is_empty());
+        assert!(datasets.pending("other").is_empty());
+
+        // Updated twice before its other dataset still runs once
+        assert_eq!(datasets.update(&orders, tasks.iter()), ["audit"]);
+        assert_eq!(datasets.pending("report"), ["orders"]);
+
+        let customers = producer("load_customers", "customers");
+        assert_eq!(datasets.update(&customers, tasks.iter()), ["report"]);
+        assert!(datasets.pending("report").is_empty());
+
+        // A run producing both
+        let both = producer("load_all", "orders, customers, events");
+        assert_eq!(
+            datasets.update(&both, tasks.iter()),
+            ["audit", "other", "report"]
+        );
+
+        datasets.update(&orders, tasks.iter());
+        datasets.remove_consumer("report");
+        assert!(datasets.pending("report").is_empty());
+    }
+
+    #[test]
+    fn state() {
+        let tasks = [task(
+            "task_id: report\nschedule: datasets:orders,customers\ncmd: run",
+        )];
+        let mut datasets = Datasets::default();
+        let orders = producer("load_orders", "orders");
+        datasets.update(&orders, tasks.iter());
+
+        let restored = Datasets::from_state_yaml(&datasets.to_state_yaml()).unwrap();
+        assert_eq!(restored.pending("report"), ["orders"]);
+        let update = &restored.updates["orders"];
+        assert_eq!(update.instance_id, orders.instance_id);
+        assert_eq!(update.task_id, "load_orders");
+
+        let customers = producer("load_customers", "customers");
+        let mut restored = restored;
+        assert_eq!(restored.update(&customers, tasks.iter()), ["report"]);
+
+        let listed = datasets.to_yaml(tasks.iter().chain([&orders.task]));
+        let listed = listed.as_vec().unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0]["dataset"].as_str(), Some("customers"));
+        assert!(listed[0]["updated_by"].is_badvalue());
+        assert_eq!(listed[1]["dataset"].as_str(), Some("orders"));
+        assert_eq!(listed[1]["producers"][0].as_str(), Some("load_orders"));
+        assert_eq!(listed[1]["consumers"][0].as_str(), Some("report"));
+        assert_eq!(
+            listed[1]["updated_by"].as_str(),
+            Some(orders.instance_id.as_str())
+        );
+    }
+
+    fn runs(sched: &Scheduler, task_id: &str) -> usize {
+        sched.history.lock().unwrap().runs(Some(task_id)).count()
+    }
+
+    /// Drive the scheduler until `done`, at most 5s
+    async fn drive(sched: &Arc<Scheduler>, done: impl Fn() -> bool) {
+        for _ in 0..50 {
+            let _ = tokio::time::timeout(Duration::from_millis(100), sched.clone().run()).await;
+            if done() {
+                return;
+            }
+        }
+        panic!("timed out");
+    }
+
+    #[tokio::test]
+    async fn producers_trigger_consumers() {
+        let sched = Arc::new(Scheduler::new());
+        let later = SystemTime::now() + Duration::from_secs(3600);
+
+        for definition in [
+            "task_id: load_orders\nschedule: interval:1d\nproduces: [orders]\ncmd: echo orders",
+            "task_id: load_customers\nschedule: interval:1d\nproduces: [customers]\ncmd: echo customers",
+            "task_id: report\nschedule: datasets:orders,customers\ncmd: echo report",
+        ] {
+            sched.add_task(task(definition), later).unwrap();
+        }
+
+        sched
+            .trigger_task("load_orders", TriggerOptions::default())
+            .unwrap();
+        drive(&sched, || runs(&sched, "load_orders") > 0).await;
+        tokio::time::timeout(Duration::from_millis(300), sched.clone().run())
+            .await
+            .unwrap_err();
+        assert_eq!(runs(&sched, "report"), 0);
+        assert_eq!(sched.datasets.lock().unwrap().pending("report"), ["orders"]);
+
+        sched
+            .trigger_task("load_customers", TriggerOptions::default())
+            .unwrap();
+        drive(&sched, || runs(&sched, "report") > 0).await;
+        assert_eq!(runs(&sched, "report"), 1);
+        assert!(sched.datasets.lock().unwrap().pending("report").is_empty());
+    }
+}
@@ -1,9 +1,12 @@
 use std::error::Error;
 use std::fmt;
 
+use crate::history::FailureReason;
+
 #[derive(Debug)]
 pub struct ExecError {
     pub(crate) message: String,
+    pub(crate) reason: FailureReason,
 }
 
 impl Error for ExecError {}
@@ -18,6 +21,7 @@ impl From<std::io::Error> for ExecError {
     fn from(error: std::io::Error) -> Self {
         ExecError {
             message: format!("Io error on exec: {}", error),
+            reason: FailureReason::Error,
         }
     }
 }
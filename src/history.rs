@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::SystemTime;
+
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::task::{params_to_yaml, Params, TaskId, Trigger};
+use crate::time::format_time;
+
+/// Outcome of a finished [`crate::task::TaskInstance`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    Success,
+    Failed,
+    /// Not run, see [`crate::task::MisfirePolicy::Skip`]
+    Skipped,
+}
+
+/// Why a run failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    /// The command failed or could not be run
+    Error,
+    /// Killed for exceeding its timeout
+    Timeout,
+    /// Killed for exceeding its memory limit, see [`crate::limits`]
+    OutOfMemory,
+    /// Not run, see [`crate::task::MisfirePolicy::Fail`]
+    Misfired,
+}
+
+/// Record of a single run of a [`crate::task::TaskInstance`]
+#[derive(Debug, Clone)]
+pub struct RunRecord {
+    pub instance_id: String,
+    pub task_id: TaskId,
+    pub retry_num: u16,
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    pub status: RunStatus,
+    pub logs: String,
+    pub trigger: Trigger,
+    /// Parameter overrides of the run
+    pub params: Params,
+    pub logical_date: SystemTime,
+    /// Outputs of a successful run, see [`crate::task::Outcome`]
+    pub outputs: Params,
+    /// Command as rendered for the run, empty when it did not run one
+    pub cmd: String,
+    /// Failed runs only
+    pub reason: Option<FailureReason>,
+}
+
+/// Bounded log of past runs, oldest records are dropped first
+#[derive(Debug)]
+pub struct History {
+    records: VecDeque<RunRecord>,
+    capacity: usize,
+}
+
+impl History {
+    pub const DEFAULT_CAPACITY: usize = 1000;
+
+    pub fn new(capacity: usize) -> Self {
+        History {
+            records: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, record: RunRecord) {
+        if self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Records of `task_id`, or of all tasks if `None`, newest first
+    pub fn runs(&self, task_id: Option<&str>) -> impl Iterator<Item = &RunRecord> {
+        let task_id = task_id.map(str::to_string);
+
+        self.records.iter().rev().filter(move |r| match &task_id {
+            Some(id) => &r.task_id == id,
+            None => true,
+        })
+    }
+
+    /// Find the record of a run by instance id
+    pub fn get(&self, instance_id: &str) -> Option<&RunRecord> {
+        self.records
+            .iter()
+            .rev()
+            .find(|r| r.instance_id == instance_id)
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(History::DEFAULT_CAPACITY)
+    }
+}
+
+impl fmt::Display for RunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunStatus::Success => write!(f, "success"),
+            RunStatus::Failed => write!(f, "failed"),
+            RunStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureReason::Error => write!(f, "error"),
+            FailureReason::Timeout => write!(f, "timeout"),
+            FailureReason::OutOfMemory => write!(f, "out_of_memory"),
+            FailureReason::Misfired => write!(f, "misfired"),
+        }
+    }
+}
+
+impl RunRecord {
+    /// Summary of the run, without logs
+    pub fn to_yaml(&self) -> Yaml {
+        let mut h = Hash::new();
+
+        h.insert(
+            Yaml::String("instance_id".into()),
+            Yaml::String(self.instance_id.clone()),
+        );
+        h.insert(
+            Yaml::String("task_id".into()),
+            Yaml::String(self.task_id.clone()),
+        );
+        h.insert(
+            Yaml::String("status".into()),
+            Yaml::String(self.status.to_string()),
+        );
+        if let Some(reason) = self.reason {
+            h.insert(
+                Yaml::String("reason".into()),
+                Yaml::String(reason.to_string()),
+            );
+        }
+        h.insert(
+            Yaml::String("retry_num".into()),
+            Yaml::Integer(self.retry_num as i64),
+        );
+        h.insert(
+            Yaml::String("started_at".into()),
+            Yaml::String(format_time(self.started_at)),
+        );
+        h.insert(
+            Yaml::String("finished_at".into()),
+            Yaml::String(format_time(self.finished_at)),
+        );
+        h.insert(
+            Yaml::String("trigger".into()),
+            Yaml::String(self.trigger.to_string()),
+        );
+        h.insert(
+            Yaml::String("logical_date".into()),
+            Yaml::String(format_time(self.logical_date)),
+        );
+        if !self.cmd.is_empty() {
+            h.insert(Yaml::String("cmd".into()), Yaml::String(self.cmd.clone()));
+        }
+        if !self.params.is_empty() {
+            h.insert(Yaml::String("params".into()), params_to_yaml(&self.params));
+        }
+        if !self.outputs.is_empty() {
+            h.insert(
+                Yaml::String("outputs".into()),
+                params_to_yaml(&self.outputs),
+            );
+        }
+
+        Yaml::Hash(h)
+    }
+}
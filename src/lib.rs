@@ -3,11 +3,31 @@
 
 // use tracing::Level;
 
+pub mod auth;
+pub mod backfill;
+pub mod calendar;
+pub mod client;
 pub mod command;
+pub mod config;
+pub mod cron;
+pub mod dataset;
 mod errors;
+pub mod history;
+pub mod limits;
+pub mod output;
+pub mod process;
+pub mod protocol;
+pub mod repl;
+pub mod sandbox;
 pub mod scheduler;
+pub mod secrets;
+pub mod sensor;
 pub mod server;
 pub mod task;
+pub mod template;
+pub mod time;
+pub mod tls;
+pub mod webhook;
 // pub use task::{Task, TaskInstance, ScheduleType, TaskId};
 // use scheduler::Scheduler;
 
@@ -0,0 +1,446 @@
+//! CPU, memory and process limits of the commands of tasks
+//!
+//! ```yaml
+//! task_id: crunch
+//! schedule: interval:1h
+//! cmd: ./crunch.sh
+//! memory_limit: 512M   # bytes, with an optional K, M, G or T suffix
+//! cpu_limit: 1.5       # CPUs
+//! pids_limit: 64       # processes and threads
+//! ```
+//! With `cgroup_root` set to a cgroup v2 directory the server may create cgroups in, each
+//! run gets its own cgroup enforcing the limits, and runs killed for exceeding their
+//! memory fail as out of memory. Without it, or where the cgroup can not be set up, the
+//! memory and pids limits fall back to the `RLIMIT_AS` and `RLIMIT_NPROC` resource
+//! limits of the process, the latter counting every process of its user, and the CPU
+//! limit is not enforced.
+
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{event, Level};
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Bytes
+    pub memory: Option<u64>,
+    /// Thousandths of a CPU
+    pub cpu: Option<u32>,
+    /// Processes and threads
+    pub pids: Option<u64>,
+}
+
+/// Cgroup of a run, its processes are killed and it is removed on drop
+#[derive(Debug)]
+pub(crate) struct Cgroup {
+    dir: PathBuf,
+    /// `cgroup.procs`, the child moves itself into the cgroup by writing to it
+    #[cfg(target_os = "linux")]
+    procs: Option<std::fs::File>,
+}
+
+impl ResourceLimits {
+    pub const KEYS: [&'static str; 3] = ["memory_limit", "cpu_limit", "pids_limit"];
+
+    /// Period of the CPU quota, in microseconds
+    const CPU_PERIOD: u64 = 100_000;
+
+    /// Parse the limit keys of a task definition
+    pub fn from_yaml(doc: &Yaml) -> Result<Self> {
+        let memory = match &doc["memory_limit"] {
+            Yaml::BadValue => None,
+            Yaml::Integer(n) if *n > 0 => Some(*n as u64),
+            Yaml::String(s) => Some(parse_bytes(s)?),
+            m => return Err(format!("invalid memory_limit: {:?}", m).into()),
+        };
+
+        let cpus = match &doc["cpu_limit"] {
+            Yaml::BadValue => None,
+            Yaml::Integer(n) => Some(*n as f64),
+            Yaml::Real(r) | Yaml::String(r) => Some(r.parse::<f64>().unwrap_or(f64::NAN)),
+            _ => Some(f64::NAN),
+        };
+        let cpu = match cpus {
+            None => None,
+            Some(c) if (0.001..=4096.0).contains(&c) => Some((c * 1000.0).round() as u32),
+            Some(_) => {
+                return Err(format!(
+                    "invalid cpu_limit: {:?}, expected CPUs like 1.5",
+                    doc["cpu_limit"]
+                )
+                .into())
+            }
+        };
+
+        let pids = match &doc["pids_limit"] {
+            Yaml::BadValue => None,
+            Yaml::Integer(n) if *n > 0 => Some(*n as u64),
+            p => return Err(format!("invalid pids_limit: {:?}", p).into()),
+        };
+
+        Ok(ResourceLimits { memory, cpu, pids })
+    }
+
+    /// Add the limit keys to a task definition
+    pub fn insert_yaml(&self, h: &mut Hash) {
+        if let Some(memory) = self.memory {
+            h.insert(
+                Yaml::String("memory_limit".into()),
+                Yaml::String(format_bytes(memory)),
+            );
+        }
+        if let Some(cpu) = self.cpu {
+            h.insert(
+                Yaml::String("cpu_limit".into()),
+                Yaml::Real(format!("{}", cpu as f64 / 1000.0)),
+            );
+        }
+        if let Some(pids) = self.pids {
+            h.insert(
+                Yaml::String("pids_limit".into()),
+                Yaml::Integer(pids as i64),
+            );
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+
+    /// Enforce the limits on `cmd`, in a cgroup `name` under `cgroup_root` if possible.
+    /// The cgroup returned must be kept until the command finished.
+    pub(crate) fn apply(
+        &self,
+        cmd: &mut Command,
+        cgroup_root: Option<&Path>,
+        name: &str,
+    ) -> Result<Option<Cgroup>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+
+        let cgroup = match cgroup_root {
+            Some(root) => match Cgroup::create(root, name, self) {
+                Ok(cgroup) => Some(cgroup),
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        id = name,
+                        err = e.to_string(),
+                        "failed to set up cgroup, falling back to rlimits"
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        if cgroup.is_none() && self.cpu.is_some() {
+            event!(
+                Level::WARN,
+                id = name,
+                "cpu_limit requires a cgroup, not enforced"
+            );
+        }
+
+        self.enforce(cmd, cgroup.as_ref())?;
+        Ok(cgroup)
+    }
+
+    /// Move the child into `cgroup`, or else set its resource limits, before it runs the
+    /// command
+    #[cfg(unix)]
+    fn enforce(&self, cmd: &mut Command, cgroup: Option<&Cgroup>) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        let procs = cgroup
+            .and_then(|c| c.procs.as_ref())
+            .map(std::os::fd::AsRawFd::as_raw_fd);
+        #[cfg(not(target_os = "linux"))]
+        let procs: Option<i32> = cgroup.and(None);
+
+        let (memory, pids) = match procs {
+            Some(_) => (None, None),
+            None => (self.memory, self.pids),
+        };
+
+        // SAFETY: the closure only makes system calls, no allocation or locking
+        unsafe {
+            cmd.pre_exec(move || {
+                // `0` is the writing process
+                if let Some(fd) = procs {
+                    if libc::write(fd, b"0".as_ptr().cast(), 1) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                if let Some(bytes) = memory {
+                    let limit = libc::rlimit {
+                        rlim_cur: bytes as libc::rlim_t,
+                        rlim_max: bytes as libc::rlim_t,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                if let Some(n) = pids {
+                    let limit = libc::rlimit {
+                        rlim_cur: n as libc::rlim_t,
+                        rlim_max: n as libc::rlim_t,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_NPROC, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                Ok(())
+            });
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn enforce(&self, _cmd: &mut Command, _cgroup: Option<&Cgroup>) -> Result<()> {
+        Err("resource limits are only supported on unix".into())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Cgroup {
+    /// Create the cgroup `root/name` with `limits`
+    fn create(root: &Path, name: &str, limits: &ResourceLimits) -> Result<Self> {
+        // Controllers the limits need, enabled for the children of root
+        let controllers = [
+            ("+memory", limits.memory.is_some()),
+            ("+cpu", limits.cpu.is_some()),
+            ("+pids", limits.pids.is_some()),
+        ];
+        for (controller, _) in controllers.iter().filter(|(_, needed)| *needed) {
+            // Already enabled or not available, writing the limit tells which
+            let _ = std::fs::write(root.join("cgroup.subtree_control"), controller);
+        }
+
+        let dir = root.join(name);
+        std::fs::create_dir(&dir)
+            .map_err(|e| format!("failed to create cgroup {}: {}", dir.display(), e))?;
+
+        // Removed on drop from here on
+        let mut cgroup = Cgroup { dir, procs: None };
+
+        let write = |file: &str, value: String| {
+            std::fs::write(cgroup.dir.join(file), value)
+                .map_err(|e| format!("failed to write {}: {}", file, e))
+        };
+
+        if let Some(bytes) = limits.memory {
+            write("memory.max", bytes.to_string())?;
+            // Out of memory means out of memory, not swapping
+            let _ = write("memory.swap.max", "0".to_string());
+        }
+        if let Some(cpu) = limits.cpu {
+            let quota = cpu as u64 * ResourceLimits::CPU_PERIOD / 1000;
+            write(
+                "cpu.max",
+                format!("{} {}", quota, ResourceLimits::CPU_PERIOD),
+            )?;
+        }
+        if let Some(n) = limits.pids {
+            write("pids.max", n.to_string())?;
+        }
+
+        cgroup.procs = Some(
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(cgroup.dir.join("cgroup.procs"))?,
+        );
+
+        Ok(cgroup)
+    }
+
+    /// Whether a process of the cgroup was killed for exceeding its memory limit
+    pub(crate) fn oom_killed(&self) -> bool {
+        std::fs::read_to_string(self.dir.join("memory.events"))
+            .ok()
+            .and_then(|events| {
+                events.lines().find_map(|l| {
+                    l.strip_prefix("oom_kill ")
+                        .and_then(|n| n.trim().parse::<u64>().ok())
+                })
+            })
+            .is_some_and(|n| n > 0)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        let dir = std::mem::take(&mut self.dir);
+
+        // Waits for the killed processes, not on an async worker
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || remove_cgroup(&dir));
+            }
+            Err(_) => remove_cgroup(&dir),
+        }
+    }
+}
+
+/// Kill what is left in the cgroup at `dir`, e.g. processes that left the process group,
+/// and remove it
+#[cfg(target_os = "linux")]
+fn remove_cgroup(dir: &Path) {
+    let _ = std::fs::write(dir.join("cgroup.kill"), "1");
+
+    // Busy until the killed processes are gone
+    for _ in 0..20 {
+        match std::fs::remove_dir(dir) {
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                std::thread::sleep(std::time::Duration::from_millis(5))
+            }
+            _ => return,
+        }
+    }
+
+    event!(Level::WARN, cgroup = %dir.display(), "failed to remove cgroup");
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Cgroup {
+    fn create(_root: &Path, _name: &str, _limits: &ResourceLimits) -> Result<Self> {
+        Err("cgroups are only supported on linux".into())
+    }
+
+    pub(crate) fn oom_killed(&self) -> bool {
+        false
+    }
+}
+
+/// Parse a size in bytes, `512M` or `1073741824`, suffixes are powers of 1024
+pub fn parse_bytes(data: &str) -> Result<u64> {
+    let data = data.trim();
+    let (number, unit) = match data.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => data.split_at(i),
+        None => (data, ""),
+    };
+
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("invalid size '{}', expected bytes like 512M", data).into()),
+    };
+
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+    {
+        Some(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid size '{}', expected bytes like 512M", data).into()),
+    }
+}
+
+/// Format a size in bytes in the largest unit it is a whole number of
+pub fn format_bytes(bytes: u64) -> String {
+    for (unit, size) in [
+        ("T", 1u64 << 40),
+        ("G", 1 << 30),
+        ("M", 1 << 20),
+        ("K", 1 << 10),
+    ] {
+        if bytes.is_multiple_of(size) {
+            return format!("{}{}", bytes / size, unit);
+        }
+    }
+
+    bytes.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn limits(definition: &str) -> Result<ResourceLimits> {
+        ResourceLimits::from_yaml(&YamlLoader::load_from_str(definition)?[0])
+    }
+
+    #[test]
+    fn bytes() {
+        for (data, bytes) in [
+            ("512", 512),
+            ("1B", 1),
+            ("4k", 4 << 10),
+            ("512M", 512 << 20),
+            ("2 GiB", 2 << 30),
+            ("1TB", 1 << 40),
+        ] {
+            assert_eq!(parse_bytes(data).unwrap(), bytes, "{}", data);
+        }
+
+        for data in ["", "0", "0M", "M", "-1M", "1.5G", "1X", "99999999999T"] {
+            assert!(parse_bytes(data).is_err(), "{}", data);
+        }
+
+        assert_eq!(format_bytes(512 << 20), "512M");
+        assert_eq!(format_bytes(1536 << 20), "1536M");
+        assert_eq!(format_bytes(1 << 40), "1T");
+        assert_eq!(format_bytes(1000), "1000");
+    }
+
+    #[test]
+    fn from_yaml() {
+        let l = limits("memory_limit: 512M\ncpu_limit: 1.5\npids_limit: 64").unwrap();
+        assert_eq!(
+            l,
+            ResourceLimits {
+                memory: Some(512 << 20),
+                cpu: Some(1500),
+                pids: Some(64),
+            }
+        );
+
+        assert!(limits("task_id: t").unwrap().is_empty());
+        assert_eq!(limits("memory_limit: 1024").unwrap().memory, Some(1024));
+        assert_eq!(limits("cpu_limit: 2").unwrap().cpu, Some(2000));
+        assert_eq!(limits("cpu_limit: '0.5'").unwrap().cpu, Some(500));
+        assert_eq!(limits("cpu_limit: 0.001").unwrap().cpu, Some(1));
+        assert_eq!(limits("cpu_limit: 4096").unwrap().cpu, Some(4_096_000));
+
+        let mut h = Hash::new();
+        l.insert_yaml(&mut h);
+        assert_eq!(ResourceLimits::from_yaml(&Yaml::Hash(h)).unwrap(), l);
+    }
+
+    #[test]
+    fn invalid() {
+        for definition in [
+            "memory_limit: 0",
+            "memory_limit: -1",
+            "memory_limit: lots",
+            "memory_limit: [1]",
+            "cpu_limit: 0",
+            "cpu_limit: 0.0001",
+            "cpu_limit: 4097",
+            "cpu_limit: -1",
+            "cpu_limit: two",
+            "cpu_limit: ''",
+            "cpu_limit: .nan",
+            "cpu_limit: .inf",
+            "cpu_limit: true",
+            "pids_limit: 0",
+            "pids_limit: '64'",
+            "pids_limit: 1.5",
+        ] {
+            assert!(limits(definition).is_err(), "{}", definition);
+        }
+    }
+}
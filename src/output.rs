@@ -0,0 +1,277 @@
+use std::fmt;
+use std::str::FromStr;
+
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+
+use crate::Result;
+
+/// How the cli prints listings returned by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            s => Err(format!(
+                "invalid output format: {} (table | json | yaml)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Table => write!(f, "table"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Yaml => write!(f, "yaml"),
+        }
+    }
+}
+
+/// Render a yaml document, as sent by the server, in `format`
+pub fn render(data: &str, format: OutputFormat) -> Result<String> {
+    let doc = match YamlLoader::load_from_str(data)?.into_iter().next() {
+        Some(doc) => doc,
+        None => Yaml::Null,
+    };
+
+    let out = match format {
+        OutputFormat::Table => table(&doc),
+        OutputFormat::Json => json(&doc),
+        OutputFormat::Yaml => {
+            let mut out = String::new();
+            YamlEmitter::new(&mut out).dump(&doc)?;
+            out.push('\n');
+            out
+        }
+    };
+
+    Ok(out)
+}
+
+/// Arrays of mappings are printed with a column per key,
+/// a single mapping with a row per key
+fn table(doc: &Yaml) -> String {
+    let rows: Vec<Vec<String>> = match doc {
+        Yaml::Array(items) => {
+            let mut header: Vec<&Yaml> = Vec::new();
+
+            for item in items {
+                if let Yaml::Hash(h) = item {
+                    for key in h.keys() {
+                        if !header.contains(&key) {
+                            header.push(key);
+                        }
+                    }
+                }
+            }
+
+            if header.is_empty() {
+                items.iter().map(|i| vec![cell(i)]).collect()
+            } else {
+                let mut rows = vec![header.iter().map(|k| cell(k).to_uppercase()).collect()];
+
+                for item in items {
+                    rows.push(
+                        header
+                            .iter()
+                            .map(|k| cell(&item[cell(k).as_str()]))
+                            .collect(),
+                    );
+                }
+
+                rows
+            }
+        }
+        Yaml::Hash(h) => h.iter().map(|(k, v)| vec![cell(k), cell(v)]).collect(),
+        Yaml::Null | Yaml::BadValue => Vec::new(),
+        s => vec![vec![cell(s)]],
+    };
+
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|c| {
+            rows.iter()
+                .filter_map(|r| r.get(c))
+                .map(|v| v.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let mut out = String::new();
+
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(v, w)| format!("{:<w$}", v, w = w))
+            .collect();
+
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+
+    out
+}
+
+fn cell(value: &Yaml) -> String {
+    match value {
+        Yaml::String(s) | Yaml::Real(s) => s.clone(),
+        Yaml::Integer(n) => n.to_string(),
+        Yaml::Boolean(b) => b.to_string(),
+        Yaml::Null | Yaml::BadValue => String::new(),
+        Yaml::Array(a)
+            if a.iter()
+                .all(|v| !matches!(v, Yaml::Array(_) | Yaml::Hash(_))) =>
+        {
+            a.iter().map(cell).collect::<Vec<String>>().join(",")
+        }
+        Yaml::Hash(h)
+            if h.values()
+                .all(|v| !matches!(v, Yaml::Array(_) | Yaml::Hash(_))) =>
+        {
+            h.iter()
+                .map(|(k, v)| format!("{}={}", cell(k), cell(v)))
+                .collect::<Vec<String>>()
+                .join(",")
+        }
+        v => json(v).trim_end().to_string(),
+    }
+}
+
+fn json(doc: &Yaml) -> String {
+    let mut out = String::new();
+    write_json(doc, 0, &mut out);
+    out.push('\n');
+    out
+}
+
+fn write_json(doc: &Yaml, indent: usize, out: &mut String) {
+    let pad = "  ".repeat(indent + 1);
+
+    match doc {
+        Yaml::String(s) => write_json_str(s, out),
+        // Written the way JSON spells numbers, which has none for infinity and NaN
+        Yaml::Real(s) => match doc.as_f64() {
+            Some(r) if r.is_finite() => out.push_str(&format!("{:?}", r)),
+            Some(_) => out.push_str("null"),
+            None => write_json_str(s, out),
+        },
+        Yaml::Integer(n) => out.push_str(&n.to_string()),
+        Yaml::Boolean(b) => out.push_str(&b.to_string()),
+        Yaml::Null | Yaml::BadValue | Yaml::Alias(_) => out.push_str("null"),
+        Yaml::Array(a) if a.is_empty() => out.push_str("[]"),
+        Yaml::Hash(h) if h.is_empty() => out.push_str("{}"),
+        Yaml::Array(a) => {
+            out.push_str("[\n");
+            for (i, v) in a.iter().enumerate() {
+                out.push_str(&pad);
+                write_json(v, indent + 1, out);
+                out.push_str(if i + 1 < a.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&"  ".repeat(indent));
+            out.push(']');
+        }
+        Yaml::Hash(h) => {
+            out.push_str("{\n");
+            for (i, (k, v)) in h.iter().enumerate() {
+                out.push_str(&pad);
+                write_json_str(&cell(k), out);
+                out.push_str(": ");
+                write_json(v, indent + 1, out);
+                out.push_str(if i + 1 < h.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&"  ".repeat(indent));
+            out.push('}');
+        }
+    }
+}
+
+fn write_json_str(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_numbers() {
+        let out = render(
+            "[1, -2, 1.5, 1.50, -0.25, 1e3, 2.5e-7, .5, +1.5, .inf, -.inf, .nan, true, ~]",
+            OutputFormat::Json,
+        )
+        .unwrap();
+        let values: Vec<&str> = out
+            .lines()
+            .filter(|l| l.starts_with("  "))
+            .map(|l| l.trim().trim_end_matches(','))
+            .collect();
+
+        assert_eq!(
+            values,
+            [
+                "1", "-2", "1.5", "1.5", "-0.25", "1000.0", "2.5e-7", "0.5", "1.5", "null", "null",
+                "null", "true", "null"
+            ]
+        );
+    }
+
+    #[test]
+    fn json_documents() {
+        let out = render(
+            "- task_id: load\n  cmd: \"echo \\\"a\\\"\\tb\"\n  params: {}\n  tags: []",
+            OutputFormat::Json,
+        )
+        .unwrap();
+
+        assert_eq!(
+            out,
+            "[\n  {\n    \"task_id\": \"load\",\n    \"cmd\": \"echo \\\"a\\\"\\tb\",\n    \
+             \"params\": {},\n    \"tags\": []\n  }\n]\n"
+        );
+    }
+
+    #[test]
+    fn tables() {
+        let out = render(
+            "- {task_id: load, schedule: interval:1h}\n- {task_id: report_daily, retries: 2}",
+            OutputFormat::Table,
+        )
+        .unwrap();
+
+        assert_eq!(
+            out,
+            "TASK_ID       SCHEDULE     RETRIES\n\
+             load          interval:1h\n\
+             report_daily               2\n"
+        );
+
+        let out = render("{a: 1, list: [x, y], map: {k: v}}", OutputFormat::Table).unwrap();
+        assert_eq!(out, "a     1\nlist  x,y\nmap   k=v\n");
+    }
+}
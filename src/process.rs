@@ -0,0 +1,573 @@
+//! Environment, working directory and identity of the commands of tasks
+//!
+//! ```yaml
+//! task_id: load_orders
+//! schedule: interval:1d
+//! cmd: ./load.sh
+//! env:
+//!   WAREHOUSE_URL: postgres://warehouse/orders
+//! inherit_env: [PATH, LANG]   # true (the default), false or names of variables to keep
+//! cwd: /srv/pipelines/orders
+//! umask: "027"
+//! user: etl                   # name or uid, the server must run as root
+//! group: etl                  # defaults to the primary group of the user
+//! ```
+//! Commands inherit the environment and working directory of the server and run as its
+//! user unless their task says otherwise.
+
+use std::path::PathBuf;
+
+use tokio::process::Command;
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::task::{params_from_yaml, params_to_yaml, Params};
+use crate::Result;
+
+/// Variables of the server environment passed on to commands
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum InheritEnv {
+    #[default]
+    All,
+    Nothing,
+    Only(Vec<String>),
+}
+
+/// How the command of a task is started
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProcessOptions {
+    /// Variables set for the command, parameters take precedence over them
+    pub env: Params,
+    pub inherit_env: InheritEnv,
+    /// Working directory, the server's by default
+    pub cwd: Option<PathBuf>,
+    pub umask: Option<u32>,
+    /// User to run as, a name or uid
+    pub user: Option<String>,
+    /// Group to run as, a name or gid
+    pub group: Option<String>,
+}
+
+/// User and groups a command runs as
+#[derive(Debug, Clone)]
+struct Identity {
+    uid: u32,
+    gid: u32,
+    groups: Vec<u32>,
+    /// Name and home directory, unknown for a uid without a passwd entry
+    account: Option<(String, String)>,
+}
+
+impl ProcessOptions {
+    pub const KEYS: [&'static str; 6] = ["env", "inherit_env", "cwd", "umask", "user", "group"];
+
+    /// Parse the process keys of a task definition
+    pub fn from_yaml(doc: &Yaml) -> Result<Self> {
+        let mut options = ProcessOptions::default();
+
+        if !doc["env"].is_badvalue() {
+            options.env =
+                params_from_yaml(&doc["env"]).map_err(|e| format!("invalid env: {}", e))?;
+        }
+
+        options.inherit_env = match &doc["inherit_env"] {
+            Yaml::BadValue | Yaml::Boolean(true) => InheritEnv::All,
+            Yaml::Boolean(false) => InheritEnv::Nothing,
+            Yaml::String(name) => InheritEnv::Only(vec![name.clone()]),
+            Yaml::Array(names) => InheritEnv::Only(
+                names
+                    .iter()
+                    .map(|n| match n.as_str() {
+                        Some(n) if !n.is_empty() && !n.contains('=') => Ok(n.to_string()),
+                        _ => Err(format!("invalid inherit_env variable: {:?}", n)),
+                    })
+                    .collect::<std::result::Result<_, _>>()?,
+            ),
+            i => return Err(format!("invalid inherit_env: {:?}", i).into()),
+        };
+
+        if let Some(cwd) = doc["cwd"].as_str() {
+            if cwd.is_empty() {
+                return Err("cwd must not be empty".into());
+            }
+            options.cwd = Some(PathBuf::from(cwd));
+        }
+
+        options.umask = match &doc["umask"] {
+            Yaml::BadValue => None,
+            // Read as octal, `umask: 022` is the integer 22 in YAML
+            Yaml::String(s) => Some(parse_umask(s)?),
+            Yaml::Integer(n) => Some(parse_umask(&n.to_string())?),
+            u => return Err(format!("invalid umask: {:?}", u).into()),
+        };
+
+        options.user = id_from_yaml(&doc["user"], "user")?;
+        options.group = id_from_yaml(&doc["group"], "group")?;
+
+        Ok(options)
+    }
+
+    /// Add the process keys to a task definition
+    pub fn insert_yaml(&self, h: &mut Hash) {
+        if !self.env.is_empty() {
+            h.insert(Yaml::String("env".into()), params_to_yaml(&self.env));
+        }
+        match &self.inherit_env {
+            InheritEnv::All => {}
+            InheritEnv::Nothing => {
+                h.insert(Yaml::String("inherit_env".into()), Yaml::Boolean(false));
+            }
+            InheritEnv::Only(names) => {
+                h.insert(
+                    Yaml::String("inherit_env".into()),
+                    Yaml::Array(names.iter().cloned().map(Yaml::String).collect()),
+                );
+            }
+        }
+        if let Some(cwd) = &self.cwd {
+            h.insert(
+                Yaml::String("cwd".into()),
+                Yaml::String(cwd.display().to_string()),
+            );
+        }
+        if let Some(umask) = self.umask {
+            h.insert(
+                Yaml::String("umask".into()),
+                Yaml::String(format!("{:03o}", umask)),
+            );
+        }
+        if let Some(user) = &self.user {
+            h.insert(Yaml::String("user".into()), Yaml::String(user.clone()));
+        }
+        if let Some(group) = &self.group {
+            h.insert(Yaml::String("group".into()), Yaml::String(group.clone()));
+        }
+    }
+
+    /// Set up `cmd` as described, before parameters are added to its environment.
+    /// Returns the uid and gid the command switches to, if any.
+    pub async fn apply(&self, cmd: &mut Command) -> Result<Option<(u32, u32)>> {
+        match &self.inherit_env {
+            InheritEnv::All => {}
+            InheritEnv::Nothing => {
+                cmd.env_clear();
+            }
+            InheritEnv::Only(names) => {
+                cmd.env_clear();
+                for name in names {
+                    if let Some(value) = std::env::var_os(name) {
+                        cmd.env(name, value);
+                    }
+                }
+            }
+        }
+
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+
+        let mut owner = None;
+
+        if self.user.is_some() || self.group.is_some() {
+            // Lookups may read files or ask a directory service
+            let (user, group) = (self.user.clone(), self.group.clone());
+            let identity = tokio::task::spawn_blocking(move || {
+                Identity::resolve(user.as_deref(), group.as_deref()).map_err(|e| e.to_string())
+            })
+            .await??;
+
+            if let Some((name, home)) = &identity.account {
+                cmd.env("USER", name).env("LOGNAME", name).env("HOME", home);
+            }
+
+            owner = Some((identity.uid, identity.gid));
+            self.apply_identity(cmd, Some(identity))?;
+        } else if self.umask.is_some() {
+            self.apply_identity(cmd, None)?;
+        }
+
+        cmd.envs(&self.env);
+        Ok(owner)
+    }
+
+    /// Set the umask and switch to `identity` in the child, before it runs the command
+    #[cfg(unix)]
+    fn apply_identity(&self, cmd: &mut Command, identity: Option<Identity>) -> Result<()> {
+        let umask = self.umask;
+
+        // SAFETY: the closure only makes system calls, no allocation or locking
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(umask) = umask {
+                    libc::umask(umask as libc::mode_t);
+                }
+
+                if let Some(id) = &identity {
+                    // Supplementary groups first, only root may change them
+                    if libc::setgroups(id.groups.len() as _, id.groups.as_ptr()) != 0
+                        || libc::setgid(id.gid) != 0
+                        || libc::setuid(id.uid) != 0
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                Ok(())
+            });
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply_identity(&self, _cmd: &mut Command, _identity: Option<Identity>) -> Result<()> {
+        Err("umask, user and group are only supported on unix".into())
+    }
+}
+
+fn parse_umask(data: &str) -> Result<u32> {
+    match u32::from_str_radix(data, 8) {
+        Ok(umask) if umask <= 0o777 => Ok(umask),
+        _ => Err(format!("invalid umask '{}', expected octal digits like 022", data).into()),
+    }
+}
+
+/// User or group, a name or a numeric id
+fn id_from_yaml(doc: &Yaml, key: &str) -> Result<Option<String>> {
+    match doc {
+        Yaml::BadValue => Ok(None),
+        Yaml::String(s) if !s.is_empty() => Ok(Some(s.clone())),
+        Yaml::Integer(n) if u32::try_from(*n).is_ok() => Ok(Some(n.to_string())),
+        d => Err(format!("invalid {}: {:?}", key, d).into()),
+    }
+}
+
+#[cfg(unix)]
+impl Identity {
+    /// Look up the ids of `user` and `group`, a missing one is the server's
+    fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Self> {
+        let gid = group.map(lookup_group).transpose()?;
+
+        let (uid, account) = match user {
+            Some(user) => match lookup_user(user)? {
+                Some(entry) => (entry.uid, Some(entry)),
+                None => match user.parse() {
+                    Ok(uid) => (uid, None),
+                    Err(_) => return Err(format!("unknown user '{}'", user).into()),
+                },
+            },
+            // SAFETY: getuid has no memory safety requirements
+            None => (unsafe { libc::getuid() }, None),
+        };
+
+        let gid = match (gid, &account) {
+            (Some(gid), _) => gid,
+            (None, Some(entry)) => entry.gid,
+            (None, None) => match user {
+                Some(user) => {
+                    return Err(
+                        format!("user {} has no passwd entry, a group is required", user).into(),
+                    )
+                }
+                // SAFETY: getgid has no memory safety requirements
+                None => unsafe { libc::getgid() },
+            },
+        };
+
+        // The groups of the user with a known name, otherwise only `gid`
+        let groups = match &account {
+            Some(entry) => group_list(&entry.name, gid)?,
+            _ => vec![gid],
+        };
+
+        Ok(Identity {
+            uid,
+            gid,
+            groups,
+            account: account.map(|e| (e.name, e.home)),
+        })
+    }
+}
+
+#[cfg(not(unix))]
+impl Identity {
+    fn resolve(_user: Option<&str>, _group: Option<&str>) -> Result<Self> {
+        Err("user and group are only supported on unix".into())
+    }
+}
+
+#[cfg(unix)]
+struct PasswdEntry {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: String,
+}
+
+/// Entry of `user`, by name or else by uid. `None` when there is none.
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<Option<PasswdEntry>> {
+    use std::ffi::{CStr, CString};
+
+    let name = CString::new(user).map_err(|_| format!("invalid user '{}'", user))?;
+    let uid: Option<libc::uid_t> = user.parse().ok();
+
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: passwd is plain data, all zeroes is a valid value
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+
+    // SAFETY: every pointer is valid for the duration of the call, buf for its length
+    let err = unsafe {
+        match uid {
+            Some(uid) => libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result),
+            None => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            ),
+        }
+    };
+
+    if err != 0 {
+        return Err(format!(
+            "failed to look up user '{}': {}",
+            user,
+            std::io::Error::from_raw_os_error(err)
+        )
+        .into());
+    }
+
+    if result.is_null() {
+        return Ok(None);
+    }
+
+    // SAFETY: on success the strings of pwd point into buf and are nul terminated
+    let string =
+        |s: *const libc::c_char| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned();
+
+    Ok(Some(PasswdEntry {
+        name: string(pwd.pw_name),
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        home: string(pwd.pw_dir),
+    }))
+}
+
+/// Gid of `group`, a name or a gid
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<u32> {
+    use std::ffi::CString;
+
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).map_err(|_| format!("invalid group '{}'", group))?;
+
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: group is plain data, all zeroes is a valid value
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+
+    // SAFETY: every pointer is valid for the duration of the call, buf for its length
+    let err = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+
+    match (err, result.is_null()) {
+        (0, false) => Ok(grp.gr_gid),
+        (0, true) => Err(format!("unknown group '{}'", group).into()),
+        (e, _) => Err(format!(
+            "failed to look up group '{}': {}",
+            group,
+            std::io::Error::from_raw_os_error(e)
+        )
+        .into()),
+    }
+}
+
+/// Supplementary groups of `user`, including `gid`
+#[cfg(unix)]
+fn group_list(user: &str, gid: u32) -> Result<Vec<u32>> {
+    let name = std::ffi::CString::new(user).map_err(|_| format!("invalid user '{}'", user))?;
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+
+    loop {
+        let mut n = groups.len() as libc::c_int;
+
+        // SAFETY: groups is valid for writes of n ids, n is updated to the number needed
+        let found = unsafe {
+            libc::getgrouplist(name.as_ptr(), gid as _, groups.as_mut_ptr() as _, &mut n)
+        };
+
+        if found >= 0 {
+            groups.truncate(n as usize);
+            return Ok(groups);
+        }
+
+        if n as usize <= groups.len() {
+            groups.resize(groups.len() * 2, 0);
+        } else {
+            groups.resize(n as usize, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn options(definition: &str) -> Result<ProcessOptions> {
+        ProcessOptions::from_yaml(&YamlLoader::load_from_str(definition)?[0])
+    }
+
+    /// Output of `script` run with `options` applied
+    async fn run(options: &ProcessOptions, script: &str) -> String {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        options.apply(&mut cmd).await.unwrap();
+
+        let output = cmd.output().await.unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn from_yaml() {
+        let o = options(
+            "env: {WAREHOUSE_URL: postgres://warehouse, RETRIES: 3}
+inherit_env: [PATH, LANG]
+cwd: /srv/pipelines
+umask: 027
+user: etl
+group: 1000",
+        )
+        .unwrap();
+
+        assert_eq!(o.env["WAREHOUSE_URL"], "postgres://warehouse");
+        assert_eq!(o.env["RETRIES"], "3");
+        assert_eq!(
+            o.inherit_env,
+            InheritEnv::Only(vec!["PATH".into(), "LANG".into()])
+        );
+        assert_eq!(o.cwd, Some(PathBuf::from("/srv/pipelines")));
+        assert_eq!(o.umask, Some(0o027));
+        assert_eq!(o.user.as_deref(), Some("etl"));
+        assert_eq!(o.group.as_deref(), Some("1000"));
+
+        let mut h = Hash::new();
+        o.insert_yaml(&mut h);
+        assert_eq!(ProcessOptions::from_yaml(&Yaml::Hash(h)).unwrap(), o);
+
+        assert_eq!(options("cmd: x").unwrap(), ProcessOptions::default());
+        assert_eq!(
+            options("inherit_env: false").unwrap().inherit_env,
+            InheritEnv::Nothing
+        );
+        assert_eq!(
+            options("inherit_env: true").unwrap().inherit_env,
+            InheritEnv::All
+        );
+        assert_eq!(
+            options("inherit_env: PATH").unwrap().inherit_env,
+            InheritEnv::Only(vec!["PATH".into()])
+        );
+        assert_eq!(options("umask: '0077'").unwrap().umask, Some(0o077));
+        assert_eq!(options("umask: 0").unwrap().umask, Some(0));
+    }
+
+    #[test]
+    fn invalid() {
+        for definition in [
+            "env: [A]",
+            "inherit_env: [A=1]",
+            "inherit_env: ['']",
+            "inherit_env: 1",
+            "cwd: ''",
+            "umask: 0800",
+            "umask: '1777'",
+            "umask: rwx",
+            "umask: [1]",
+            "user: ''",
+            "user: -1",
+            "group: [etl]",
+        ] {
+            assert!(options(definition).is_err(), "{}", definition);
+        }
+    }
+
+    #[tokio::test]
+    async fn environment() {
+        std::env::set_var("CHAINZ_TEST_PROCESS_KEPT", "kept");
+        std::env::set_var("CHAINZ_TEST_PROCESS_DROPPED", "dropped");
+        let script = "echo \"$CHAINZ_TEST_PROCESS_KEPT|$CHAINZ_TEST_PROCESS_DROPPED|$OWN\"";
+
+        let all = options("env: {OWN: own}").unwrap();
+        assert_eq!(run(&all, script).await, "kept|dropped|own\n");
+
+        let only = options("env: {OWN: own}\ninherit_env: [CHAINZ_TEST_PROCESS_KEPT]").unwrap();
+        assert_eq!(run(&only, script).await, "kept||own\n");
+
+        let nothing = options("inherit_env: false").unwrap();
+        assert_eq!(run(&nothing, script).await, "||\n");
+
+        // Set variables win over inherited ones
+        let own = options("env: {CHAINZ_TEST_PROCESS_KEPT: own}").unwrap();
+        assert_eq!(run(&own, script).await, "own|dropped|\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cwd_and_umask() {
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let o = options(&format!("cwd: {}\numask: '027'", dir.display())).unwrap();
+        assert_eq!(
+            run(&o, "pwd; umask").await,
+            format!("{}\n0027\n", dir.display())
+        );
+
+        let o = options("umask: '077'").unwrap();
+        assert_eq!(run(&o, "umask").await, "0077\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn identity() {
+        // SAFETY: geteuid has no memory safety requirements
+        if unsafe { libc::geteuid() } != 0 {
+            let o = options("user: nobody").unwrap();
+            let mut cmd = Command::new("true");
+            o.apply(&mut cmd).await.unwrap();
+            assert!(cmd.status().await.is_err());
+            return;
+        }
+
+        let o = options("user: nobody").unwrap();
+        let nobody = lookup_user("nobody").unwrap().unwrap();
+        assert_eq!(
+            run(&o, "id -u; id -g; echo $USER").await,
+            format!("{}\n{}\nnobody\n", nobody.uid, nobody.gid)
+        );
+
+        // A uid without a passwd entry needs a group
+        let o = options("user: 54321\ngroup: 54321").unwrap();
+        assert_eq!(run(&o, "id -u; id -G").await, "54321\n54321\n");
+        let o = options("user: 54321").unwrap();
+        assert!(o.apply(&mut Command::new("true")).await.is_err());
+
+        let o = options("user: no-such-user-chainz").unwrap();
+        assert!(o.apply(&mut Command::new("true")).await.is_err());
+        let o = options("group: no-such-group-chainz").unwrap();
+        assert!(o.apply(&mut Command::new("true")).await.is_err());
+    }
+}
@@ -0,0 +1,257 @@
+//! Wire protocol between [`crate::server::Server`] and clients
+//!
+//! A request is a single command line, e.g. `LIST` or `KILL task1`.
+//! Commands carrying a document (see [`crate::command::expects_body`]) follow
+//! the command line with the document and a line holding a single `.`,
+//! lines of the document starting with `.` are escaped with another `.`.
+//!
+//! A response is a status line `OK <len>` or `ERR <len>` followed by `len`
+//! bytes of body.
+//!
+//! Requests are limited to [`MAX_REQUEST`] bytes and responses to
+//! [`MAX_RESPONSE`], the connection is not usable after exceeding them.
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, Take};
+
+use crate::command::expects_body;
+use crate::Result;
+
+const BODY_END: &str = ".";
+
+/// Largest request, command line and document, in bytes
+pub const MAX_REQUEST: u64 = 1024 * 1024;
+
+/// Largest response, status line and body, in bytes
+pub const MAX_RESPONSE: u64 = 64 * 1024 * 1024;
+
+/// Command line and optional document sent by a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub line: String,
+    pub body: Option<String>,
+}
+
+/// Reply of the server to a [`Request`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok(String),
+    Err(String),
+}
+
+impl Request {
+    pub fn new(line: &str) -> Self {
+        Request {
+            line: line.to_string(),
+            body: None,
+        }
+    }
+
+    pub fn with_body(line: &str, body: &str) -> Self {
+        Request {
+            line: line.to_string(),
+            body: Some(body.to_string()),
+        }
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let mut data = format!("{}\n", self.line.trim_end());
+
+        if let Some(body) = &self.body {
+            for line in body.lines() {
+                if line.starts_with('.') {
+                    data.push('.');
+                }
+                data.push_str(line);
+                data.push('\n');
+            }
+            data.push_str(BODY_END);
+            data.push('\n');
+        }
+
+        writer.write_all(data.as_bytes()).await?;
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    /// Read the next request, `None` if the client closed the connection
+    pub async fn read_from<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Self>> {
+        let mut reader = reader.take(MAX_REQUEST);
+        let mut line = String::new();
+
+        if read_line(&mut reader, &mut line, MAX_REQUEST).await? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+
+        if !expects_body(&line) {
+            return Ok(Some(Request { line, body: None }));
+        }
+
+        let mut body = String::new();
+
+        loop {
+            let mut body_line = String::new();
+
+            if read_line(&mut reader, &mut body_line, MAX_REQUEST).await? == 0 {
+                return Err("connection closed before end of document".into());
+            }
+
+            let body_line = body_line.trim_end_matches(['\r', '\n']);
+
+            if body_line == BODY_END {
+                break;
+            }
+
+            body.push_str(body_line.strip_prefix('.').unwrap_or(body_line));
+            body.push('\n');
+        }
+
+        Ok(Some(Request {
+            line,
+            body: Some(body),
+        }))
+    }
+}
+
+impl Response {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Response::Ok(_))
+    }
+
+    pub fn body(&self) -> &str {
+        match self {
+            Response::Ok(body) | Response::Err(body) => body,
+        }
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let mut status = match self {
+            Response::Ok(_) => "OK",
+            Response::Err(_) => "ERR",
+        };
+
+        let mut body = self.body();
+        let too_large;
+
+        // Leave room for the status line
+        if body.len() as u64 > MAX_RESPONSE - 32 {
+            too_large = format!(
+                "response of {} bytes exceeds the limit of {} bytes",
+                body.len(),
+                MAX_RESPONSE
+            );
+            status = "ERR";
+            body = &too_large;
+        }
+
+        writer
+            .write_all(format!("{} {}\n", status, body.len()).as_bytes())
+            .await?;
+        writer.write_all(body.as_bytes()).await?;
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    /// Read the next response, `None` if the server closed the connection
+    pub async fn read_from<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Self>> {
+        let mut reader = reader.take(MAX_RESPONSE);
+        let mut header = String::new();
+
+        if read_line(&mut reader, &mut header, MAX_RESPONSE).await? == 0 {
+            return Ok(None);
+        }
+
+        let (status, len) = match header.trim_end().split_once(' ') {
+            Some(parts) => parts,
+            None => return Err(format!("malformed response: {}", header.trim_end()).into()),
+        };
+
+        let len: u64 = len.parse()?;
+
+        if len > reader.limit() {
+            return Err(format!(
+                "response of {} bytes exceeds the limit of {} bytes",
+                len, MAX_RESPONSE
+            )
+            .into());
+        }
+
+        let mut body = Vec::with_capacity(len as usize);
+        (&mut reader).take(len).read_to_end(&mut body).await?;
+
+        if body.len() as u64 != len {
+            return Err("connection closed before end of response".into());
+        }
+
+        let body = String::from_utf8(body)?;
+
+        match status {
+            "OK" => Ok(Some(Response::Ok(body))),
+            "ERR" => Ok(Some(Response::Err(body))),
+            s => Err(format!("unknown response status: {}", s).into()),
+        }
+    }
+}
+
+/// Read a line from a message limited by `reader`, failing once the limit is reached
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut Take<R>,
+    buf: &mut String,
+    max: u64,
+) -> Result<usize> {
+    let read = reader.read_line(buf).await?;
+
+    if reader.limit() == 0 && (read == 0 || !buf.ends_with('\n')) {
+        return Err(format!("message exceeds the limit of {} bytes", max).into());
+    }
+
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let request = Request::with_body("ADD", "id: a\n.hidden\n");
+        let mut data = Vec::new();
+        request.write_to(&mut data).await.unwrap();
+        Response::Ok("done".to_string())
+            .write_to(&mut data)
+            .await
+            .unwrap();
+
+        let mut reader = data.as_slice();
+        assert_eq!(
+            Request::read_from(&mut reader).await.unwrap(),
+            Some(request)
+        );
+        assert_eq!(
+            Response::read_from(&mut reader).await.unwrap(),
+            Some(Response::Ok("done".to_string()))
+        );
+        assert_eq!(Response::read_from(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let line = format!("LIST {}\n", "a".repeat(MAX_REQUEST as usize));
+        assert!(Request::read_from(&mut line.as_bytes()).await.is_err());
+
+        let body = "x\n".repeat(MAX_REQUEST as usize / 2);
+        let request = format!("ADD\n{}.\n", body);
+        assert!(Request::read_from(&mut request.as_bytes()).await.is_err());
+
+        let response = format!("OK {}\n", MAX_RESPONSE);
+        let err = Response::read_from(&mut response.as_bytes())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+
+        assert!(Response::read_from(&mut &b"OK 10\nshort"[..]).await.is_err());
+    }
+}
@@ -0,0 +1,302 @@
+//! Interactive shell of `chainz_cli`
+//!
+//! Lines are edited with [`rustyline`], history is kept in `~/.chainz_history`,
+//! without `AUTH` lines.
+//! Commands taking a document (see [`crate::command::expects_body`]) continue
+//! on the following lines until a line holding a single `.`.
+
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper};
+use yaml_rust::YamlLoader;
+
+use crate::client::Client;
+use crate::command::{expects_body, COMMANDS};
+use crate::protocol::{Request, Response};
+use crate::Result;
+
+const HISTORY_FILE: &str = ".chainz_history";
+
+/// Commands whose first argument is a task id
+const TASK_COMMANDS: [&str; 8] = [
+    "SHOW", "DRAIN", "KILL", "PAUSE", "RESUME", "TRIGGER", "BACKFILL", "HISTORY",
+];
+
+/// Commands without side effects, resent after reconnecting
+const READ_COMMANDS: [&str; 8] = [
+    "LIST", "SHOW", "LOGS", "HISTORY", "DATASETS", "VALIDATE", "HELP", "EXIT",
+];
+
+/// Commands changing the tasks, the ids to complete are fetched again after them
+const TASK_CHANGES: [&str; 3] = ["AUTH", "ADD", "KILL"];
+
+/// How long task ids are completed before being fetched again
+const TASK_IDS_TTL: Duration = Duration::from_secs(60);
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+pub struct Repl {
+    client: Client,
+    editor: Editor<ReplHelper, DefaultHistory>,
+    task_ids: Arc<Mutex<Vec<String>>>,
+    /// When the task ids were fetched, `None` to fetch them before the next prompt
+    task_ids_at: Option<Instant>,
+    history_path: Option<PathBuf>,
+    color: bool,
+}
+
+/// Completion and multi-line input for [`Repl`]
+struct ReplHelper {
+    task_ids: Arc<Mutex<Vec<String>>>,
+}
+
+impl Repl {
+    pub fn new(client: Client) -> Result<Self> {
+        let task_ids = Arc::new(Mutex::new(Vec::new()));
+
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(ReplHelper {
+            task_ids: task_ids.clone(),
+        }));
+
+        let history_path = std::env::var_os("HOME").map(|h| PathBuf::from(h).join(HISTORY_FILE));
+
+        if let Some(path) = &history_path {
+            // No history yet on first run
+            let _ = editor.load_history(path);
+        }
+
+        Ok(Repl {
+            client,
+            editor,
+            task_ids,
+            task_ids_at: None,
+            history_path,
+            color: std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        })
+    }
+
+    /// Read, send and print commands until EXIT or end of input
+    pub async fn run(&mut self) -> Result<()> {
+        let prompt = self.paint(BOLD, &format!("chainz {}> ", self.client.address()));
+
+        loop {
+            if self
+                .task_ids_at
+                .is_none_or(|at| at.elapsed() >= TASK_IDS_TTL)
+            {
+                self.refresh_task_ids().await;
+            }
+
+            let input = match tokio::task::block_in_place(|| self.editor.readline(&prompt)) {
+                Ok(input) => input,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            if input.trim().is_empty() {
+                continue;
+            }
+
+            let request = parse_input(&input);
+            let command = command(&request);
+
+            // Tokens are secrets, like passwords typed in shells
+            if command != "AUTH" {
+                self.editor.add_history_entry(input.as_str())?;
+            }
+
+            if TASK_CHANGES.contains(&command.as_str()) {
+                self.task_ids_at = None;
+            }
+
+            match self.request(&request).await {
+                Ok(Response::Ok(body)) => println!("{}", body.trim_end()),
+                Ok(Response::Err(body)) => {
+                    println!(
+                        "{}",
+                        self.paint(RED, &format!("error: {}", body.trim_end()))
+                    )
+                }
+                Err(e) => println!("{}", self.paint(RED, &format!("error: {}", e))),
+            }
+
+            if command == "EXIT" {
+                break;
+            }
+        }
+
+        if let Some(path) = &self.history_path {
+            self.editor.save_history(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a request, reconnecting if the connection was lost. Only requests without
+    /// side effects are resent, others may have reached the server before it was lost.
+    async fn request(&mut self, request: &Request) -> Result<Response> {
+        match self.client.request(request).await {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                println!("{}", self.paint(RED, &format!("connection lost: {}", e)));
+
+                self.client.reconnect().await?;
+
+                println!(
+                    "{}",
+                    self.paint(GREEN, &format!("reconnected to {}", self.client.address()))
+                );
+
+                match READ_COMMANDS.contains(&command(request).as_str()) {
+                    true => self.client.request(request).await,
+                    false => Err(
+                        "not resent, it may have run, check with SHOW or HISTORY before retrying"
+                            .into(),
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Fetch task ids for completion, keeps the previous ids if the server is unavailable
+    async fn refresh_task_ids(&mut self) {
+        self.task_ids_at = Some(Instant::now());
+
+        let body = match self.client.request(&Request::new("LIST")).await {
+            Ok(Response::Ok(body)) => body,
+            _ => return,
+        };
+
+        let ids = match YamlLoader::load_from_str(&body) {
+            Ok(docs) => docs
+                .first()
+                .and_then(|d| d.as_vec())
+                .map(|tasks| {
+                    tasks
+                        .iter()
+                        .filter_map(|t| t["task_id"].as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            Err(_) => return,
+        };
+
+        *self.task_ids.lock().unwrap() = ids;
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        match self.color {
+            true => format!("{}{}{}", color, text, RESET),
+            false => text.to_string(),
+        }
+    }
+}
+
+/// Command of a request, in uppercase
+fn command(request: &Request) -> String {
+    let command = request.line.split_whitespace().next().unwrap_or_default();
+    command.to_uppercase()
+}
+
+/// Split editor input into the command line and its document
+fn parse_input(input: &str) -> Request {
+    match input.split_once('\n') {
+        Some((line, rest)) if expects_body(line) => {
+            let body: Vec<&str> = rest.lines().take_while(|l| *l != ".").collect();
+            Request::with_body(line, &(body.join("\n") + "\n"))
+        }
+        _ => Request::new(input.trim()),
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+
+        // Only the command line of multi-line input is completed
+        if line.contains('\n') {
+            return Ok((pos, Vec::new()));
+        }
+
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let previous: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let candidates: Vec<String> = match previous.as_slice() {
+            [] => {
+                let upper = !word.chars().next().is_some_and(char::is_lowercase);
+
+                COMMANDS
+                    .iter()
+                    .filter(|c| c.starts_with(&word.to_uppercase()))
+                    .map(|c| match upper {
+                        true => c.to_string(),
+                        false => c.to_lowercase(),
+                    })
+                    .collect()
+            }
+            [cmd] if TASK_COMMANDS.contains(&cmd.to_uppercase().as_str()) => self
+                .task_ids
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|id| id.starts_with(word))
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok((
+            start,
+            candidates
+                .into_iter()
+                .map(|c| Pair {
+                    display: c.clone(),
+                    replacement: c + " ",
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        let line = input.lines().next().unwrap_or_default();
+
+        if expects_body(line) && !input.lines().skip(1).any(|l| l == ".") {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Helper for ReplHelper {}
@@ -0,0 +1,671 @@
+//! Isolation of the commands of less trusted tasks from the rest of the host, on Linux
+//!
+//! ```yaml
+//! task_id: partner_import
+//! schedule: interval:1h
+//! cmd: ./import.sh
+//! cwd: /srv/partner
+//! user: partner
+//! sandbox:
+//!   network: false              # loopback only, true by default
+//!   binds:
+//!     - /srv/partner            # read-only, at the same path
+//!     - /data/partner:/data:rw  # source:destination, writable
+//! ```
+//! `sandbox: true` isolates with the defaults. A sandboxed command runs in new user, mount
+//! and PID namespaces, and without network in a new network namespace. It sees the file
+//! systems of the host read-only, its own `/tmp` and its own processes in `/proc`, and can
+//! only write to the binds marked `rw` and to `$CHAINZ_OUTPUT`. It keeps the user and
+//! groups of the task, which can't be root, and runs without capabilities, not even in its
+//! own namespaces, and without gaining any through setuid programs. Destinations of binds
+//! must exist, except in `/tmp` where they are created.
+//!
+//! Requires Linux 5.12 and user namespaces, which some distributions only let root
+//! create.
+
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::Result;
+
+/// Namespaces and mounts of a sandboxed command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sandbox {
+    /// Paths of the host visible at their destination, over the read-only root
+    pub binds: Vec<Bind>,
+    /// Share the network of the host, else only a loopback interface is up
+    pub network: bool,
+}
+
+/// Path of the host mounted in a sandbox, `source[:destination][:ro|rw]` in task definitions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bind {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub writable: bool,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            binds: Vec::new(),
+            network: true,
+        }
+    }
+}
+
+impl Sandbox {
+    pub const KEYS: [&'static str; 1] = ["sandbox"];
+
+    /// Parse the sandbox key of a task definition, `None` for tasks not sandboxed
+    pub fn from_yaml(doc: &Yaml) -> Result<Option<Self>> {
+        let hash = match &doc["sandbox"] {
+            Yaml::BadValue | Yaml::Boolean(false) => return Ok(None),
+            Yaml::Boolean(true) => return Ok(Some(Sandbox::default())),
+            Yaml::Hash(h) => h,
+            s => return Err(format!("invalid sandbox: {:?}", s).into()),
+        };
+
+        for key in hash.keys() {
+            match key.as_str() {
+                Some("binds" | "network") => {}
+                _ => return Err(format!("unknown sandbox key: {:?}", key).into()),
+            }
+        }
+
+        let doc = &doc["sandbox"];
+        let network = match &doc["network"] {
+            Yaml::BadValue => true,
+            Yaml::Boolean(b) => *b,
+            n => return Err(format!("invalid sandbox network: {:?}", n).into()),
+        };
+
+        let binds = match &doc["binds"] {
+            Yaml::BadValue => Vec::new(),
+            Yaml::Array(binds) => binds
+                .iter()
+                .map(|b| match b.as_str() {
+                    Some(b) => Bind::parse(b),
+                    None => Err(format!("invalid bind: {:?}", b).into()),
+                })
+                .collect::<Result<_>>()?,
+            b => return Err(format!("invalid sandbox binds: {:?}", b).into()),
+        };
+
+        Ok(Some(Sandbox { binds, network }))
+    }
+
+    /// Add the sandbox key to a task definition
+    pub fn insert_yaml(&self, h: &mut Hash) {
+        let mut sandbox = Hash::new();
+        sandbox.insert(Yaml::String("network".into()), Yaml::Boolean(self.network));
+        if !self.binds.is_empty() {
+            sandbox.insert(
+                Yaml::String("binds".into()),
+                Yaml::Array(
+                    self.binds
+                        .iter()
+                        .map(|b| Yaml::String(b.to_string()))
+                        .collect(),
+                ),
+            );
+        }
+
+        h.insert(Yaml::String("sandbox".into()), Yaml::Hash(sandbox));
+    }
+
+    /// Run `cmd` in the sandbox, after it switched to the user of the task. `output` is the
+    /// file of its outputs, created by the server and shared with it.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn apply(&self, cmd: &mut Command, output: &Path) -> Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let cstring = |p: &Path| {
+            CString::new(p.as_os_str().as_bytes())
+                .map_err(|_| format!("invalid path {}", p.display()))
+        };
+
+        // Resolved again once the binds are mounted, the destination of one may be it
+        let cwd = match cmd.as_std().get_current_dir() {
+            Some(cwd) => cwd.to_path_buf(),
+            None => std::env::current_dir()?,
+        };
+        let cwd = cstring(&cwd)?;
+        let output_private = output.starts_with("/tmp");
+        let output = cstring(output)?;
+        let binds = self
+            .binds
+            .iter()
+            .map(|b| {
+                let private = b.destination.starts_with("/tmp");
+                Ok((
+                    cstring(&b.source)?,
+                    cstring(&b.destination)?,
+                    b.writable,
+                    private,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let network = self.network;
+        // Descriptors of the sources, in the new mount namespace before /tmp is replaced
+        let mut sources: Vec<libc::c_int> = vec![-1; binds.len()];
+
+        // SAFETY: the closure only makes system calls, no allocation or locking
+        unsafe {
+            cmd.pre_exec(move || {
+                use linux::*;
+
+                // Switching users made the process undumpable, with its /proc/self files
+                // owned by root, the maps can't be written without undoing it
+                cvt(libc::prctl(libc::PR_SET_DUMPABLE, 1 as libc::c_ulong))?;
+
+                // Ids of the user of the task, unmapped once in the new namespace. Root in
+                // the namespace could undo the read-only mounts.
+                let (uid, gid) = (libc::geteuid(), libc::getegid());
+                if uid == 0 {
+                    return Err(std::io::Error::from_raw_os_error(libc::EPERM));
+                }
+
+                let mut flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+                if !network {
+                    flags |= libc::CLONE_NEWNET;
+                }
+                cvt(libc::unshare(flags))?;
+
+                // Setting groups must be denied before mapping them without privileges
+                write_file(c"/proc/self/setgroups", StackStr::new().push(b"deny"))?;
+                write_file(c"/proc/self/uid_map", &id_map(uid))?;
+                write_file(c"/proc/self/gid_map", &id_map(gid))?;
+
+                // Only children join the new PID namespace, this process waits for its
+                // first one, the init of the namespace, and exits the way it did
+                match cvt(libc::fork())? {
+                    0 => {}
+                    pid => supervise(pid),
+                }
+                cvt(libc::prctl(
+                    libc::PR_SET_PDEATHSIG,
+                    libc::SIGKILL as libc::c_ulong,
+                ))?;
+
+                // Mounts of the namespace only, not propagated to the host
+                mount(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE)?;
+
+                // Opened with the rights of the user of the task and only as paths, files
+                // open for writing would keep the root from becoming read-only
+                for ((source, ..), fd) in binds.iter().zip(sources.iter_mut()) {
+                    *fd = cvt(libc::open(source.as_ptr(), libc::O_PATH | libc::O_CLOEXEC))?;
+                }
+                let output_fd = cvt(libc::open(output.as_ptr(), libc::O_PATH | libc::O_CLOEXEC))?;
+
+                mount(
+                    Some(c"tmpfs"),
+                    c"/tmp",
+                    Some(c"tmpfs"),
+                    libc::MS_NOSUID | libc::MS_NODEV,
+                )?;
+
+                for ((_, destination, _, private), fd) in binds.iter().zip(&sources) {
+                    if *private {
+                        make_mountpoint(destination, *fd)?;
+                    }
+                    mount(
+                        Some(fd_path(*fd).as_cstr()),
+                        destination,
+                        None,
+                        libc::MS_BIND | libc::MS_REC,
+                    )?;
+                    libc::close(*fd);
+                }
+
+                // The file on the host, over one created in the new /tmp
+                if output_private {
+                    make_mountpoint(&output, output_fd)?;
+                }
+                mount(
+                    Some(fd_path(output_fd).as_cstr()),
+                    &output,
+                    None,
+                    libc::MS_BIND,
+                )?;
+                libc::close(output_fd);
+
+                set_read_only(c"/", true, AT_RECURSIVE)?;
+                set_read_only(c"/tmp", false, 0)?;
+                for (_, destination, writable, _) in &binds {
+                    if *writable {
+                        set_read_only(destination, false, AT_RECURSIVE)?;
+                    }
+                }
+                set_read_only(&output, false, 0)?;
+
+                mount(
+                    Some(c"proc"),
+                    c"/proc",
+                    Some(c"proc"),
+                    libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                )?;
+
+                if !network {
+                    loopback_up()?;
+                }
+
+                drop_privileges()?;
+
+                // Kept as it was if hidden by the new /tmp
+                if libc::chdir(cwd.as_ptr()) != 0
+                    && std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOENT)
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn apply(&self, _cmd: &mut Command, _output: &Path) -> Result<()> {
+        Err("sandbox is only supported on linux".into())
+    }
+}
+
+impl Bind {
+    /// Parse `source[:destination][:ro|rw]`, read-only at the same path by default
+    pub fn parse(data: &str) -> Result<Self> {
+        let parts: Vec<&str> = data.split(':').collect();
+
+        let (source, destination, mode) = match parts.as_slice() {
+            [source] => (*source, *source, "ro"),
+            [source, mode @ ("ro" | "rw")] => (*source, *source, *mode),
+            [source, destination] => (*source, *destination, "ro"),
+            [source, destination, mode] => (*source, *destination, *mode),
+            _ => return Err(format!("invalid bind '{}'", data).into()),
+        };
+
+        let writable = match mode {
+            "ro" => false,
+            "rw" => true,
+            _ => return Err(format!("invalid bind mode '{}', expected ro or rw", mode).into()),
+        };
+
+        for path in [source, destination] {
+            if !path.starts_with('/') {
+                return Err(format!("invalid bind '{}', paths must be absolute", data).into());
+            }
+        }
+
+        Ok(Bind {
+            source: source.into(),
+            destination: destination.into(),
+            writable,
+        })
+    }
+}
+
+impl std::fmt::Display for Bind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source.display())?;
+        if self.destination != self.source {
+            write!(f, ":{}", self.destination.display())?;
+        }
+        if self.writable {
+            write!(f, ":rw")?;
+        }
+        Ok(())
+    }
+}
+
+/// System calls of the sandboxed child, which must not allocate
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::CStr;
+    use std::io;
+
+    /// Not in libc, from linux/mount.h
+    #[repr(C)]
+    struct MountAttr {
+        attr_set: u64,
+        attr_clr: u64,
+        propagation: u64,
+        userns_fd: u64,
+    }
+
+    const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+    /// Not in all versions of libc
+    pub(super) const AT_RECURSIVE: libc::c_uint = 0x8000;
+
+    /// `struct ifreq` with the `ifr_flags` member of its union
+    #[repr(C)]
+    struct IfReq {
+        name: [u8; libc::IFNAMSIZ],
+        flags: libc::c_short,
+        _pad: [u8; 22],
+    }
+
+    /// Short NUL terminated string built on the stack
+    pub(super) struct StackStr {
+        buf: [u8; 64],
+        len: usize,
+    }
+
+    impl StackStr {
+        pub(super) fn new() -> Self {
+            StackStr {
+                buf: [0; 64],
+                len: 0,
+            }
+        }
+
+        /// Truncated to fit, the paths and maps built are far shorter
+        pub(super) fn push(&mut self, data: &[u8]) -> &mut Self {
+            for b in data {
+                if self.len < self.buf.len() - 1 {
+                    self.buf[self.len] = *b;
+                    self.len += 1;
+                }
+            }
+            self
+        }
+
+        pub(super) fn push_num(&mut self, mut n: u32) -> &mut Self {
+            let mut digits = [0u8; 10];
+            let mut i = digits.len();
+            loop {
+                i -= 1;
+                digits[i] = b'0' + (n % 10) as u8;
+                n /= 10;
+                if n == 0 {
+                    break;
+                }
+            }
+            self.push(&digits[i..])
+        }
+
+        pub(super) fn as_bytes(&self) -> &[u8] {
+            &self.buf[..self.len]
+        }
+
+        pub(super) fn as_cstr(&self) -> &CStr {
+            // NUL terminated, the last byte of the buffer is never written
+            CStr::from_bytes_until_nul(&self.buf).unwrap_or(c"")
+        }
+    }
+
+    pub(super) fn cvt<T: Default + PartialOrd>(ret: T) -> io::Result<T> {
+        match ret < T::default() {
+            true => Err(io::Error::last_os_error()),
+            false => Ok(ret),
+        }
+    }
+
+    /// `id id 1`, the id of the namespace is the id on the host
+    pub(super) fn id_map(id: u32) -> StackStr {
+        let mut map = StackStr::new();
+        map.push_num(id).push(b" ").push_num(id).push(b" 1");
+        map
+    }
+
+    /// Path of the file a descriptor refers to, even one no longer reachable by its own
+    pub(super) fn fd_path(fd: libc::c_int) -> StackStr {
+        let mut path = StackStr::new();
+        path.push(b"/proc/self/fd/").push_num(fd as u32);
+        path
+    }
+
+    /// Create `path` and its parents in the new `/tmp`, a directory if the descriptor
+    /// `source` is one, else a file
+    pub(super) unsafe fn make_mountpoint(path: &CStr, source: libc::c_int) -> io::Result<()> {
+        let mut stat: libc::stat = std::mem::zeroed();
+        cvt(libc::fstat(source, &mut stat))?;
+
+        let mut buf = [0u8; libc::PATH_MAX as usize];
+        let len = path.to_bytes().len();
+        if len >= buf.len() {
+            return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+        }
+        buf[..len].copy_from_slice(path.to_bytes());
+
+        let exists = |ret: libc::c_int| match ret < 0 {
+            true if io::Error::last_os_error().raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            true => Err(io::Error::last_os_error()),
+            false => Ok(()),
+        };
+
+        for i in 1..len {
+            if buf[i] == b'/' {
+                buf[i] = 0;
+                exists(libc::mkdir(buf.as_ptr().cast(), 0o755))?;
+                buf[i] = b'/';
+            }
+        }
+
+        match stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            true => exists(libc::mkdir(path.as_ptr(), 0o755)),
+            false => {
+                let fd = libc::open(path.as_ptr(), libc::O_CREAT | libc::O_RDONLY, 0o600);
+                exists(fd).map(|_| {
+                    libc::close(fd);
+                })
+            }
+        }
+    }
+
+    pub(super) unsafe fn write_file(path: &CStr, data: &StackStr) -> io::Result<()> {
+        let fd = cvt(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+        let written = libc::write(fd, data.as_bytes().as_ptr().cast(), data.len);
+        libc::close(fd);
+
+        match written == data.len as isize {
+            true => Ok(()),
+            false => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub(super) unsafe fn mount(
+        source: Option<&CStr>,
+        target: &CStr,
+        fstype: Option<&CStr>,
+        flags: libc::c_ulong,
+    ) -> io::Result<()> {
+        let ptr = |s: Option<&CStr>| s.map_or(std::ptr::null(), CStr::as_ptr);
+        cvt(libc::mount(
+            ptr(source),
+            target.as_ptr(),
+            ptr(fstype),
+            flags,
+            std::ptr::null(),
+        ))
+        .map(drop)
+    }
+
+    /// Set or clear the read-only flag of the mount at `path`, with `AT_RECURSIVE` of
+    /// those below it too
+    pub(super) unsafe fn set_read_only(
+        path: &CStr,
+        read_only: bool,
+        flags: libc::c_uint,
+    ) -> io::Result<()> {
+        let attr = MountAttr {
+            attr_set: if read_only { MOUNT_ATTR_RDONLY } else { 0 },
+            attr_clr: if read_only { 0 } else { MOUNT_ATTR_RDONLY },
+            propagation: 0,
+            userns_fd: 0,
+        };
+
+        cvt(libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            flags,
+            &attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        ))
+        .map(drop)
+    }
+
+    /// Bring up `lo`, the only interface of a new network namespace, which starts down
+    pub(super) unsafe fn loopback_up() -> io::Result<()> {
+        let fd = cvt(libc::socket(
+            libc::AF_INET,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            0,
+        ))?;
+
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: 0,
+            _pad: [0; 22],
+        };
+        req.name[..2].copy_from_slice(b"lo");
+
+        let result = cvt(libc::ioctl(fd, libc::SIOCGIFFLAGS as _, &mut req)).and_then(|_| {
+            req.flags |= libc::IFF_UP as libc::c_short;
+            cvt(libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &req))
+        });
+        libc::close(fd);
+
+        result.map(drop)
+    }
+
+    /// Give up the capabilities the process has in its namespaces, for good, and the
+    /// privileges setuid and file capabilities would grant the command
+    pub(super) unsafe fn drop_privileges() -> io::Result<()> {
+        cvt(libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+        ))?;
+
+        // Up to the last capability the kernel knows of, beyond it fails with EINVAL
+        for cap in 0..64 as libc::c_ulong {
+            if libc::prctl(libc::PR_CAPBSET_DROP, cap) != 0 {
+                match io::Error::last_os_error().raw_os_error() {
+                    Some(libc::EINVAL) if cap > 0 => break,
+                    _ => return Err(io::Error::last_os_error()),
+                }
+            }
+        }
+
+        cvt(libc::prctl(
+            libc::PR_SET_NO_NEW_PRIVS,
+            1 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+        ))
+        .map(drop)
+    }
+
+    /// Wait for the init of the sandbox and exit with its status, never returning to run
+    /// the command
+    pub(super) unsafe fn supervise(child: libc::pid_t) -> ! {
+        // Among them the pipe the spawning server waits to be closed by the exec of the
+        // command, which this process never does
+        libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0);
+
+        let mut status = 0;
+        while libc::waitpid(child, &mut status, 0) < 0 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                libc::_exit(1);
+            }
+        }
+
+        if libc::WIFEXITED(status) {
+            libc::_exit(libc::WEXITSTATUS(status));
+        }
+        libc::_exit(128 + libc::WTERMSIG(status))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::process::ProcessOptions;
+
+    /// Runs `script` sandboxed as nobody, `None` where the test can't run: the server must
+    /// be root to switch users and the kernel must allow user namespaces
+    async fn run_sandboxed(script: &str) -> Option<std::process::Output> {
+        // SAFETY: geteuid has no memory safety requirements
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("skipped, requires root");
+            return None;
+        }
+
+        let output =
+            std::env::temp_dir().join(format!("chainz-sandbox-{}.out", std::process::id()));
+        std::fs::File::create(&output).unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script).current_dir("/");
+
+        let process = ProcessOptions {
+            user: Some("nobody".into()),
+            ..ProcessOptions::default()
+        };
+        process.apply(&mut cmd).await.unwrap();
+        Sandbox::default().apply(&mut cmd, &output).unwrap();
+
+        let result = cmd.output().await;
+        let _ = std::fs::remove_file(&output);
+        match result {
+            Ok(out) => Some(out),
+            Err(e) => {
+                eprintln!("skipped, sandbox not supported: {}", e);
+                None
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn root_is_read_only() {
+        let path = "/chainz-sandbox-test";
+        let Some(out) = run_sandboxed(&format!("touch {}", path)).await else {
+            return;
+        };
+
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("Read-only file system"));
+        assert!(!Path::new(path).exists());
+    }
+
+    #[tokio::test]
+    async fn read_only_root_can_not_be_remounted() {
+        let script = "grep -E '^(CapEff|CapBnd|NoNewPrivs)' /proc/self/status; \
+                      mount -o remount,rw / 2>/dev/null && echo remounted; \
+                      touch /chainz-sandbox-remount 2>/dev/null && echo written";
+        let Some(out) = run_sandboxed(script).await else {
+            return;
+        };
+
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        assert!(stdout.contains("CapEff:\t0000000000000000"), "{}", stdout);
+        assert!(stdout.contains("CapBnd:\t0000000000000000"), "{}", stdout);
+        assert!(stdout.contains("NoNewPrivs:\t1"), "{}", stdout);
+        assert!(!stdout.contains("remounted") && !stdout.contains("written"));
+    }
+
+    #[test]
+    fn binds() {
+        let bind = Bind::parse("/data/partner:/data:rw").unwrap();
+        assert_eq!(bind.source, Path::new("/data/partner"));
+        assert_eq!(bind.destination, Path::new("/data"));
+        assert!(bind.writable);
+        assert_eq!(bind.to_string(), "/data/partner:/data:rw");
+
+        let bind = Bind::parse("/srv/partner").unwrap();
+        assert_eq!(bind.destination, Path::new("/srv/partner"));
+        assert!(!bind.writable);
+        assert_eq!(bind.to_string(), "/srv/partner");
+
+        assert!(Bind::parse("data:/data").is_err());
+        assert!(Bind::parse("/data:/data:rx").is_err());
+    }
+}
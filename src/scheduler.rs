@@ -1,33 +1,158 @@
 use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use crate::task::{ScheduleType, Task, TaskId, TaskInstance};
+use crate::backfill::{self, Backfill, BackfillOptions};
+use crate::calendar::Calendar;
+use crate::dataset::Datasets;
+use crate::errors::ExecError;
+use crate::history::{FailureReason, History, RunRecord, RunStatus};
+use crate::secrets::{redact, Secrets};
+use crate::task::{
+    validate_param_name, MisfirePolicy, Outcome, Params, ScheduleType, Task, TaskId, TaskInstance,
+    TaskType, Trigger,
+};
+use crate::time::{format_duration, format_time, parse_time};
 use crate::Result;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinHandle;
 use tokio::time::Duration;
-use tracing::{event, span, Level};
+use tracing::{event, span, Instrument, Level};
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+
+/// Limits and defaults applied by the [`Scheduler`]
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    /// Maximum number of task instances executing at the same time
+    pub max_concurrent_tasks: usize,
+    /// Timeout of tasks that do not set one
+    pub default_timeout: Option<Duration>,
+    /// Retries of tasks that do not set them
+    pub default_retries: u16,
+    /// Searched for task executables before the `PATH` of the server
+    pub plugin_dirs: Vec<PathBuf>,
+    /// Calendars tasks may refer to, by name
+    pub calendars: HashMap<String, Arc<Calendar>>,
+    /// Providers the secrets of tasks are looked up in
+    pub secrets: Secrets,
+    /// Cgroup v2 directory the cgroups enforcing the limits of runs are created in, see
+    /// [`crate::limits`]
+    pub cgroup_root: Option<PathBuf>,
+}
+
+/// How to run a manually triggered task, see [`Scheduler::trigger_task`]
+#[derive(Debug, Clone, Default)]
+pub struct TriggerOptions {
+    /// Overrides of the task parameters
+    pub params: Params,
+    /// Also trigger the down-stream chain of the task once it succeeds
+    pub downstream: bool,
+}
+
+/// What to do with the runs a paused task missed, see [`Scheduler::resume_task`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumePolicy {
+    /// Continue at the next slot of the schedule, missed runs of periodic schedules
+    /// that ended are dropped. Runs of other schedules, like those triggered by
+    /// datasets or up-stream tasks, have no slot to skip to and run now.
+    Skip,
+    /// Run once now for the latest missed point, in place of all missed runs
+    CatchUp,
+}
 
 /// Schedules and manages lifecycle of [`Task`]s
 pub struct Scheduler {
     pub tasks: Mutex<HashMap<TaskId, Task>>,
     pub task_q: Mutex<BinaryHeap<TaskInstance>>,
+    pub history: Mutex<History>,
     drain: Mutex<Vec<TaskId>>,
+    /// Paused tasks and since when
+    paused: Mutex<HashMap<TaskId, SystemTime>>,
+    /// Scheduled instances that became due while their task was paused
+    held: Mutex<Vec<TaskInstance>>,
+    backfills: Mutex<HashMap<String, Backfill>>,
+    /// Successful scheduled runs of tasks with a [`Task::max_runs`]
+    runs: Mutex<HashMap<TaskId, u32>>,
+    pub datasets: Mutex<Datasets>,
+    wake: Notify,
+    config: SchedulerConfig,
+    slots: Arc<Semaphore>,
+    running: Mutex<HashMap<String, Running>>,
+    /// Notified whenever a running instance finishes
+    finished: Notify,
+}
+
+/// Instance being executed, aborting it kills its process
+struct Running {
+    instance: TaskInstance,
+    handle: JoinHandle<()>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            max_concurrent_tasks: 4,
+            default_timeout: None,
+            default_retries: 0,
+            plugin_dirs: Vec::new(),
+            calendars: HashMap::new(),
+            secrets: Secrets::new(),
+            cgroup_root: None,
+        }
+    }
 }
 
 impl Scheduler {
     /// Create new scheduler instance
     pub fn new() -> Self {
+        Scheduler::with_config(SchedulerConfig::default())
+    }
+
+    /// Create new scheduler instance with limits and defaults from `config`
+    pub fn with_config(config: SchedulerConfig) -> Self {
         Scheduler {
             tasks: Mutex::new(HashMap::new()),
             task_q: Mutex::new(BinaryHeap::<TaskInstance>::new()),
+            history: Mutex::new(History::default()),
             drain: Mutex::new(Vec::new()),
+            paused: Mutex::new(HashMap::new()),
+            held: Mutex::new(Vec::new()),
+            backfills: Mutex::new(HashMap::new()),
+            runs: Mutex::new(HashMap::new()),
+            datasets: Mutex::new(Datasets::default()),
+            wake: Notify::new(),
+            slots: Arc::new(Semaphore::new(config.max_concurrent_tasks)),
+            config,
+            running: Mutex::new(HashMap::new()),
+            finished: Notify::new(),
         }
     }
 
+    pub fn config(&self) -> &SchedulerConfig {
+        &self.config
+    }
+
     /// Add new task and schedule task
-    pub fn add_task(&self, task: Task, start_time: SystemTime) -> Result<()> {
+    /// Interval tasks with an origin start at its first point at or after `start_time`.
+    /// Tasks starting in the past are handled by their misfire policy.
+    pub fn add_task(&self, mut task: Task, start_time: SystemTime) -> Result<()> {
         event!(Level::INFO, id = task.task_id, "add");
 
+        task.retries.get_or_insert(self.config.default_retries);
+        task.timeout = task.timeout.or(self.config.default_timeout);
+        self.resolve_calendar(&mut task)?;
+
+        let start_time = task.align(start_time).ok_or_else(|| {
+            format!(
+                "schedule of task '{}' has no points between its start and end_time",
+                task.task_id
+            )
+        })?;
+
         let do_contain = self.tasks.lock().unwrap().contains_key(&task.task_id);
 
         match do_contain {
@@ -42,7 +167,22 @@ impl Scheduler {
                 let task3 = task.clone();
                 self.tasks.lock().unwrap().insert(task.task_id, task2);
 
-                self.schedule_task(task3, start_time, 0)?;
+                // Runs once its datasets are updated
+                if !matches!(task3.schedule, ScheduleType::Datasets(_)) {
+                    self.schedule_point(task3, start_time)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Look up the calendar a task refers to
+    fn resolve_calendar(&self, task: &mut Task) -> Result<()> {
+        if let Some(name) = &task.calendar {
+            match self.config.calendars.get(name) {
+                Some(calendar) => task.business_days = calendar.clone(),
+                None => return Err(format!("unknown calendar '{}'", name).into()),
             }
         }
 
@@ -52,34 +192,36 @@ impl Scheduler {
     /// Actually schedule task
     /// Creates a new [`TaskInstance`] and adds it to the queue
     pub fn schedule_task(&self, task: Task, exec_at: SystemTime, retry_num: u16) -> Result<()> {
-        event!(Level::TRACE, "scheduling task");
-        let task2 = task.clone();
-        let task3 = task.clone();
+        self.schedule_instance(TaskInstance::new(task, exec_at, retry_num))?;
+        Ok(())
+    }
 
-        let ti = TaskInstance::new(task2, exec_at, retry_num);
+    /// Queue the run of `task` for a point of its schedule, at the time its calendar moves it to
+    fn schedule_point(&self, task: Task, point: SystemTime) -> Result<()> {
+        let mut ti = TaskInstance::new(task, point, 0);
+        ti.exec_at = ti.task.exec_time(point);
+        self.schedule_instance(ti)?;
+        Ok(())
+    }
+
+    /// Add an already created [`TaskInstance`] to the queue and wake the scheduler
+    fn schedule_instance(&self, ti: TaskInstance) -> Result<String> {
+        event!(Level::TRACE, "scheduling task");
 
         let inst_id = ti.instance_id.clone();
+        let task_id = ti.task.task_id.clone();
 
         self.task_q.lock().unwrap().push(ti);
+        self.wake.notify_one();
 
-        event!(
-            Level::TRACE,
-            id = task3.task_id,
-            inst_id = inst_id,
-            "scheduled"
-        );
+        event!(Level::TRACE, id = task_id, inst_id = inst_id, "scheduled");
 
-        Ok(())
+        Ok(inst_id)
     }
 
-    /// Exec tasks at top of schedule
-    /// Reschedule if config says so
-    /// Trigger down-stream tasks
+    /// Exec tasks at top of schedule, at most `max_concurrent_tasks` at a time
     /// Sleep until trigger of next task or until new task is added
-    async fn poll(&self) -> Result<()> {
-        let span = span!(Level::TRACE, "poll");
-        let _enter = span.enter();
-
+    async fn poll(self: &Arc<Self>) -> Result<()> {
         event!(Level::TRACE, "polling");
 
         while self
@@ -89,118 +231,1285 @@ impl Scheduler {
             .peek()
             .is_some_and(|ti| ti.exec_at < SystemTime::now())
         {
-            // Should never return None
-            let next_task = self.task_q.lock().unwrap().pop().unwrap();
+            let permit = self.slots.clone().acquire_owned().await?;
 
-            event!(
-                Level::INFO,
-                id = next_task.task.task_id,
-                inst_id = next_task.instance_id,
-                "exec"
-            );
+            // Killed while waiting for a free slot
+            let next_task = match self.task_q.lock().unwrap().pop() {
+                Some(ti) => ti,
+                None => break,
+            };
+
+            if next_task.trigger == Trigger::Scheduled && self.is_paused(&next_task.task.task_id) {
+                event!(
+                    Level::INFO,
+                    id = next_task.task.task_id,
+                    inst_id = next_task.instance_id,
+                    "task paused, holding instance"
+                );
+                self.held.lock().unwrap().push(next_task);
+                continue;
+            }
+
+            // Down-stream runs triggered after the end of the task's schedule
+            if next_task.trigger == Trigger::Scheduled
+                && !next_task.task.before_end(next_task.exec_at)
+            {
+                self.retire(&next_task.task.task_id, "end_time passed");
+                continue;
+            }
+
+            let next_task = match self.misfire(next_task) {
+                Some(ti) => ti,
+                None => continue,
+            };
+
+            let inst_id = next_task.instance_id.clone();
+            let instance = next_task.clone();
+            let sched = self.clone();
+
+            // Locked until the instance is registered, its task removes it when done
+            let mut running = self.running.lock().unwrap();
+
+            let handle = tokio::spawn(async move {
+                let inst_id = next_task.instance_id.clone();
+
+                sched.run_instance(next_task).await;
+
+                sched.running.lock().unwrap().remove(&inst_id);
+                sched.finished.notify_waiters();
+                drop(permit);
+            });
+
+            running.insert(inst_id, Running { instance, handle });
+        }
+
+        let sleep_dur = match self.task_q.lock().unwrap().peek() {
+            Some(t) => t
+                .exec_at
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO),
+            None => tokio::time::Duration::from_secs(2),
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_dur) => {}
+            _ = self.wake.notified() => {}
+        }
+
+        Ok(())
+    }
+
+    /// Apply the misfire policy of the task to a scheduled run starting later than its
+    /// tolerance. Returns the instance to run, if any.
+    fn misfire(&self, mut ti: TaskInstance) -> Option<TaskInstance> {
+        let now = SystemTime::now();
+
+        let late = match now.duration_since(ti.exec_at) {
+            Ok(late) if ti.trigger == Trigger::Scheduled && ti.retry_num == 0 => late,
+            _ => return Some(ti),
+        };
+        if late <= ti.task.misfire_tolerance {
+            return Some(ti);
+        }
+        let late = Duration::from_secs(late.as_secs());
+
+        event!(
+            Level::WARN,
+            id = ti.task.task_id,
+            inst_id = ti.instance_id,
+            late = format_duration(late),
+            policy = ti.task.misfire.to_string(),
+            "misfire"
+        );
+
+        let status = match ti.task.misfire {
+            MisfirePolicy::FireAll => return Some(ti),
+            MisfirePolicy::FireOnce => {
+                // Latest point at or before now, later points follow from it
+                if let Some(latest) = ti.task.latest_point(ti.logical_date, now) {
+                    ti.logical_date = latest;
+                }
+                return Some(ti);
+            }
+            MisfirePolicy::Skip => RunStatus::Skipped,
+            MisfirePolicy::Fail => RunStatus::Failed,
+        };
+
+        self.history.lock().unwrap().push(RunRecord {
+            instance_id: ti.instance_id.clone(),
+            task_id: ti.task.task_id.clone(),
+            retry_num: ti.retry_num,
+            started_at: now,
+            finished_at: now,
+            status,
+            logs: format!(
+                "missed its schedule by {}, tolerance {}",
+                format_duration(late),
+                format_duration(ti.task.misfire_tolerance)
+            ),
+            trigger: ti.trigger,
+            params: ti.params.clone(),
+            logical_date: ti.logical_date,
+            outputs: Params::new(),
+            cmd: String::new(),
+            reason: (status == RunStatus::Failed).then_some(FailureReason::Misfired),
+        });
+
+        // Next point after now, periodic schedules without one are done
+        match ti.task.next_point(ti.logical_date, now) {
+            Some(next) => {
+                if let Err(e) = self.schedule_point(ti.task, next) {
+                    event!(Level::ERROR, err = e.to_string(), "failed to reschedule");
+                }
+            }
+            None if ti.task.schedule.is_periodic() => {
+                self.retire(&ti.task.task_id, "schedule ended")
+            }
+            None => {}
+        }
+
+        None
+    }
+
+    /// Exec a single instance and record it in history
+    /// Reschedule if config says so
+    /// Trigger down-stream tasks
+    async fn run_instance(&self, next_task: TaskInstance) {
+        event!(
+            Level::INFO,
+            id = next_task.task.task_id,
+            inst_id = next_task.instance_id,
+            "exec"
+        );
+
+        let started_at = SystemTime::now();
+        let prepared = self
+            .config
+            .secrets
+            .resolve(&next_task.task.secrets)
+            .and_then(|secrets| Ok((next_task.render(&secrets)?, secrets)))
+            .map_err(|e| e.to_string());
+
+        let result = match &prepared {
+            Ok((ti, secrets)) => ti.exec(&self.config, secrets).await.map_err(|e| {
+                let reason = e
+                    .downcast_ref::<ExecError>()
+                    .map_or(FailureReason::Error, |e| e.reason);
+                (reason, e.to_string())
+            }),
+            Err(e) => Err((FailureReason::Error, e.clone())),
+        };
+        let reason = result.as_ref().err().map(|(reason, _)| *reason);
+        let result = result.map_err(|(_, e)| e);
+
+        // Nothing recorded of the run may show the values of its secrets
+        let (cmd, result) = match &prepared {
+            Ok((ti, secrets)) => (
+                redact(&ti.task.cmd, secrets),
+                result
+                    .map(|mut outcome| {
+                        outcome.logs = redact(&outcome.logs, secrets);
+                        for value in outcome.outputs.values_mut() {
+                            *value = redact(value, secrets);
+                        }
+                        outcome
+                    })
+                    .map_err(|e| redact(&e, secrets)),
+            ),
+            Err(_) => (String::new(), result),
+        };
+
+        let (status, logs, outputs) = match &result {
+            Ok(outcome) => (
+                RunStatus::Success,
+                outcome.logs.clone(),
+                outcome.outputs.clone(),
+            ),
+            Err(e) => (RunStatus::Failed, e.clone(), Params::new()),
+        };
+
+        self.history.lock().unwrap().push(RunRecord {
+            instance_id: next_task.instance_id.clone(),
+            task_id: next_task.task.task_id.clone(),
+            retry_num: next_task.retry_num,
+            started_at,
+            finished_at: SystemTime::now(),
+            status,
+            logs,
+            trigger: next_task.trigger,
+            params: next_task.params.clone(),
+            logical_date: next_task.logical_date,
+            outputs,
+            cmd,
+            reason,
+        });
+
+        if let Err(e) = self.complete(next_task, result) {
+            event!(Level::ERROR, err = e.to_string(), "failed to reschedule");
+        }
+    }
+
+    fn complete(
+        &self,
+        next_task: TaskInstance,
+        result: std::result::Result<Outcome, String>,
+    ) -> Result<()> {
+        let retry =
+            result.is_err() && next_task.retry_num < next_task.task.retries.unwrap_or_default();
 
-            match next_task.exec().await {
-                // reschedule if failed and less than retry, with backoff
-                Err(e) => {
+        if let (Some(id), false) = (&next_task.backfill, retry) {
+            self.release_backfill(id, true);
+        }
+
+        if result.is_ok() && !next_task.task.produces.is_empty() {
+            self.update_datasets(&next_task);
+        }
+
+        // Sensors start their down-stream tasks whatever triggered them
+        if let (Ok(outcome), TaskType::Sensor(sensor)) = (&result, &next_task.task.task_type) {
+            for task_id in &sensor.downstream {
+                if let Err(e) = self.start_downstream(&next_task, task_id, &outcome.outputs) {
                     event!(
-                        Level::TRACE,
+                        Level::ERROR,
                         id = next_task.task.task_id,
-                        err = e,
-                        "task failed"
+                        downstream = task_id,
+                        err = e.to_string(),
+                        "failed to trigger down-stream task"
                     );
+                }
+            }
+        }
 
-                    if next_task.retry_num >= next_task.task.retries {
+        match result {
+            // reschedule if failed and less than retry, with backoff
+            Err(e) => {
+                event!(
+                    Level::TRACE,
+                    id = next_task.task.task_id,
+                    err = e,
+                    "task failed"
+                );
+
+                if !retry {
+                    event!(
+                        Level::ERROR,
+                        id = next_task.task.task_id,
+                        "task failed, no more retries"
+                    );
+                } else {
+                    self.schedule_instance(next_task.retry(
+                        SystemTime::now()
+                            + Duration::from_secs(2 * (next_task.retry_num + 1) as u64),
+                    ))?;
+                }
+            }
+            // Manual runs and backfills leave the schedule alone, at most continuing down-stream
+            Ok(outcome) if next_task.trigger != Trigger::Scheduled => {
+                if let (ScheduleType::DownStream(task_id), true) =
+                    (&next_task.task.schedule, next_task.downstream)
+                {
+                    if let Err(e) = self.start_downstream(&next_task, task_id, &outcome.outputs) {
                         event!(
                             Level::ERROR,
                             id = next_task.task.task_id,
-                            "task failed, no more retries"
+                            downstream = task_id,
+                            err = e.to_string(),
+                            "failed to trigger down-stream task"
                         );
-                    } else {
-                        self.schedule_task(
-                            next_task.task,
-                            SystemTime::now()
-                                + Duration::from_secs(2 * (next_task.retry_num + 1) as u64),
-                            next_task.retry_num + 1,
-                        )?;
                     }
                 }
-                Ok(()) => {
-                    let mut drain = false;
-
-                    for (i, d) in self.drain.lock().unwrap().iter_mut().enumerate() {
-                        if d == &next_task.task.task_id {
-                            self.tasks.lock().unwrap().remove(d);
-                            self.drain.lock().unwrap().swap_remove(i);
-                            drain = false;
-                            break;
+            }
+            Ok(outcome) => {
+                let drain = {
+                    let mut drain = self.drain.lock().unwrap();
+
+                    match drain.iter().position(|d| d == &next_task.task.task_id) {
+                        Some(i) => {
+                            drain.swap_remove(i);
+                            true
                         }
+                        None => false,
                     }
+                };
 
-                    if drain == false {
-                        match next_task.task.schedule {
-                            ScheduleType::DownStream(task_id) => {
-                                let task =
-                                    self.tasks.lock().unwrap().get(&task_id).unwrap().clone();
+                let max_runs = next_task.task.max_runs.is_some_and(|max| {
+                    let mut runs = self.runs.lock().unwrap();
+                    let n = runs.entry(next_task.task.task_id.clone()).or_default();
+                    *n += 1;
+                    *n >= max
+                });
 
-                                self.schedule_task(task, SystemTime::now(), 0)?;
-                            }
+                if drain {
+                    event!(Level::INFO, id = next_task.task.task_id, "drained");
+                    self.tasks.lock().unwrap().remove(&next_task.task.task_id);
+                } else {
+                    match &next_task.task.schedule {
+                        ScheduleType::DownStream(task_id) => {
+                            let task = self.tasks.lock().unwrap().get(task_id).cloned();
 
-                            ScheduleType::Interval(duration) => {
-                                self.schedule_task(
-                                    next_task.task.to_owned(),
-                                    SystemTime::now() + duration,
-                                    0,
-                                )?;
+                            match task {
+                                Some(task) => {
+                                    self.schedule_instance(
+                                        next_task.downstream(task, &outcome.outputs),
+                                    )?;
+                                }
+                                None => event!(
+                                    Level::ERROR,
+                                    id = next_task.task.task_id,
+                                    downstream = task_id,
+                                    "down-stream task does not exist"
+                                ),
                             }
+                        }
+
+                        // Runs again once its datasets are updated again
+                        ScheduleType::Datasets(_) => {}
+
+                        // Ran successfully, no reschedule
+                        ScheduleType::Once => {
+                            self.tasks.lock().unwrap().remove(&next_task.task.task_id);
+                        }
+
+                        // Next point after the one just run, not after the end of the run,
+                        // so the schedule does not drift. Missed points are left to the
+                        // misfire policy.
+                        _ if !max_runs => {
+                            let ld = next_task.logical_date;
 
-                            // Ran successfully, no reschedule
-                            ScheduleType::Once => {
-                                self.tasks.lock().unwrap().remove(&next_task.task.task_id);
+                            match next_task.task.next_point(ld, ld) {
+                                Some(next) => self.schedule_point(next_task.task.clone(), next)?,
+                                None => self.retire(&next_task.task.task_id, "schedule ended"),
                             }
                         }
+                        _ => {}
+                    }
+
+                    if max_runs {
+                        self.retire(&next_task.task.task_id, "max_runs reached");
                     }
                 }
             }
         }
 
-        let sleep_dur = match self.task_q.lock().unwrap().peek() {
-            Some(t) => t.exec_at.duration_since(SystemTime::now()).unwrap(),
-            None => tokio::time::Duration::from_secs(2),
+        Ok(())
+    }
+
+    /// Record the datasets updated by a successful run, whatever triggered it, and run the
+    /// tasks all of whose datasets are updated now
+    fn update_datasets(&self, producer: &TaskInstance) {
+        event!(
+            Level::INFO,
+            id = producer.task.task_id,
+            datasets = producer.task.produces.join(","),
+            "datasets updated"
+        );
+
+        let ready: Vec<Task> = {
+            let tasks = self.tasks.lock().unwrap();
+            let ready = self
+                .datasets
+                .lock()
+                .unwrap()
+                .update(producer, tasks.values());
+            ready
+                .iter()
+                .filter_map(|id| tasks.get(id).cloned())
+                .collect()
         };
 
-        tokio::time::sleep(sleep_dur).await;
+        let now = SystemTime::now();
+
+        for task in ready {
+            if !task.before_end(now) {
+                self.retire(&task.task_id, "end_time passed");
+                continue;
+            }
+
+            let task_id = task.task_id.clone();
+
+            if let Err(e) = self.schedule_instance(TaskInstance::new(task, now, 0)) {
+                event!(
+                    Level::ERROR,
+                    id = task_id,
+                    err = e.to_string(),
+                    "failed to schedule consumer"
+                );
+            }
+        }
+    }
+
+    /// Run the down-stream task `task_id` of a finished run right away, for the same logical
+    /// date, with the same trigger and with its outputs in the context
+    fn start_downstream(
+        &self,
+        upstream: &TaskInstance,
+        task_id: &str,
+        outputs: &Params,
+    ) -> Result<()> {
+        let task = self
+            .tasks
+            .lock()
+            .unwrap()
+            .get(task_id)
+            .cloned()
+            .ok_or_else(|| format!("task '{}' does not exist", task_id))?;
 
+        self.schedule_instance(upstream.downstream(task, outputs))?;
         Ok(())
     }
 
-    pub async fn run(&self) {
+    /// Remove a task whose schedule ended, as a `Once` task is once it ran.
+    /// Runs already queued for it, like retries or backfills, still run.
+    fn retire(&self, task_id: &str, reason: &str) {
+        event!(Level::INFO, id = task_id, reason = reason, "retired");
+
+        self.tasks.lock().unwrap().remove(task_id);
+        self.runs.lock().unwrap().remove(task_id);
+        self.paused.lock().unwrap().remove(task_id);
+        self.datasets.lock().unwrap().remove_consumer(task_id);
+    }
+
+    /// Start instances as they become due, until the returned future is dropped.
+    /// Dropping it starts no new instances, running ones are left to [`Scheduler::shutdown`].
+    pub async fn run(self: Arc<Self>) {
         event!(Level::TRACE, "starting scheduler");
 
         loop {
-            self.poll().await.unwrap();
+            self.poll()
+                .instrument(span!(Level::TRACE, "poll"))
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Wait up to `grace` for running instances to finish, then kill the rest.
+    /// Killed instances are queued again to run first on the next start,
+    /// unless they finished before they could be killed.
+    pub async fn shutdown(&self, grace: Duration) {
+        let deadline = tokio::time::Instant::now() + grace;
+
+        loop {
+            let finished = self.finished.notified();
+
+            let running = self.running.lock().unwrap().len();
+            if running == 0 {
+                return;
+            }
+
+            event!(Level::INFO, running = running, "waiting for running tasks");
+
+            if tokio::time::timeout_at(deadline, finished).await.is_err() {
+                break;
+            }
+        }
+
+        let stragglers: Vec<Running> = self
+            .running
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, r)| r)
+            .collect();
+
+        for r in stragglers {
+            event!(
+                Level::WARN,
+                id = r.instance.task.task_id,
+                inst_id = r.instance.instance_id,
+                "killing task still running at shutdown"
+            );
+
+            r.handle.abort();
+
+            if r.handle.await.is_ok() {
+                continue;
+            }
+
+            let mut ti = r.instance;
+            ti.exec_at = SystemTime::now();
+            self.task_q.lock().unwrap().push(ti);
+        }
+    }
+
+    /// Write tasks, queued instances and draining tasks to `path`, replacing it atomically
+    pub fn save_state(&self, path: &Path) -> Result<()> {
+        let mut tasks: Vec<Task> = self.tasks.lock().unwrap().values().cloned().collect();
+        tasks.sort_by(|a, b| a.task_id.cmp(&b.task_id));
+
+        let mut queue: Vec<TaskInstance> = self.task_q.lock().unwrap().iter().cloned().collect();
+        queue.extend(self.held.lock().unwrap().iter().cloned());
+        queue.sort_by_key(|ti| ti.exec_at);
+
+        let draining = self.drain.lock().unwrap().clone();
+
+        let mut paused: Vec<(TaskId, SystemTime)> = self
+            .paused
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, since)| (id.clone(), *since))
+            .collect();
+        paused.sort();
+
+        let mut backfills: Vec<Backfill> =
+            self.backfills.lock().unwrap().values().cloned().collect();
+        backfills.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut runs: Vec<(TaskId, u32)> = self
+            .runs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, n)| (id.clone(), *n))
+            .collect();
+        runs.sort();
+
+        let mut h = Hash::new();
+        h.insert(
+            Yaml::String("tasks".into()),
+            Yaml::Array(tasks.iter().map(|t| t.to_yaml()).collect()),
+        );
+        h.insert(
+            Yaml::String("queue".into()),
+            Yaml::Array(queue.iter().map(|ti| ti.to_yaml()).collect()),
+        );
+        h.insert(
+            Yaml::String("draining".into()),
+            Yaml::Array(draining.into_iter().map(Yaml::String).collect()),
+        );
+        h.insert(
+            Yaml::String("paused".into()),
+            Yaml::Hash(
+                paused
+                    .into_iter()
+                    .map(|(id, since)| (Yaml::String(id), Yaml::String(format_time(since))))
+                    .collect(),
+            ),
+        );
+        h.insert(
+            Yaml::String("backfills".into()),
+            Yaml::Array(
+                backfills
+                    .iter()
+                    .map(|b| {
+                        let mut doc = b.to_yaml();
+                        if let Yaml::Hash(h) = &mut doc {
+                            h.insert(
+                                Yaml::String("pending".into()),
+                                Yaml::Array(b.pending.iter().map(|ti| ti.to_yaml()).collect()),
+                            );
+                        }
+                        doc
+                    })
+                    .collect(),
+            ),
+        );
+        h.insert(
+            Yaml::String("runs".into()),
+            Yaml::Hash(
+                runs.into_iter()
+                    .map(|(id, n)| (Yaml::String(id), Yaml::Integer(n as i64)))
+                    .collect(),
+            ),
+        );
+        h.insert(
+            Yaml::String("datasets".into()),
+            self.datasets.lock().unwrap().to_state_yaml(),
+        );
+
+        let mut out = String::new();
+        YamlEmitter::new(&mut out).dump(&Yaml::Hash(h))?;
+        out.push('\n');
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, out)
+            .map_err(|e| format!("failed to write state {}: {}", tmp.display(), e))?;
+        std::fs::rename(&tmp, path)
+            .map_err(|e| format!("failed to write state {}: {}", path.display(), e))?;
+
+        event!(
+            Level::INFO,
+            path = %path.display(),
+            tasks = tasks.len(),
+            queued = queue.len(),
+            "saved state"
+        );
+
+        Ok(())
+    }
+
+    /// Restore tasks, queued instances and draining tasks saved by [`Scheduler::save_state`]
+    pub fn restore_state(&self, path: &Path) -> Result<()> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read state {}: {}", path.display(), e))?;
+
+        let invalid = |e: String| format!("invalid state {}: {}", path.display(), e);
+
+        let docs = YamlLoader::load_from_str(&data).map_err(|e| invalid(e.to_string()))?;
+        let doc = match docs.as_slice() {
+            [doc] => doc,
+            _ => return Err(invalid("expected a single document".into()).into()),
+        };
+
+        let mut tasks = HashMap::new();
+        for t in doc["tasks"].as_vec().map_or(&[][..], |v| v.as_slice()) {
+            let mut task = Task::from_yaml(t).map_err(|e| invalid(e.to_string()))?;
+            self.resolve_calendar(&mut task)
+                .map_err(|e| invalid(e.to_string()))?;
+            tasks.insert(task.task_id.clone(), task);
         }
+
+        let mut queue = Vec::new();
+        for ti in doc["queue"].as_vec().map_or(&[][..], |v| v.as_slice()) {
+            let task = ti["task_id"]
+                .as_str()
+                .and_then(|id| tasks.get(id))
+                .ok_or_else(|| invalid(format!("queued instance of unknown task: {:?}", ti)))?;
+
+            let instance = TaskInstance::from_yaml(ti, task).map_err(|e| invalid(e.to_string()))?;
+
+            queue.push(instance);
+        }
+
+        let draining: Vec<TaskId> = doc["draining"]
+            .as_vec()
+            .map_or(&[][..], |v| v.as_slice())
+            .iter()
+            .filter_map(|d| d.as_str().map(str::to_string))
+            .collect();
+
+        let mut backfills = HashMap::new();
+        for b in doc["backfills"].as_vec().map_or(&[][..], |v| v.as_slice()) {
+            let (id, task) = match (
+                b["id"].as_str(),
+                b["task_id"].as_str().and_then(|id| tasks.get(id)),
+            ) {
+                (Some(id), Some(task)) => (id.to_string(), task),
+                _ => return Err(invalid(format!("invalid backfill: {:?}", b)).into()),
+            };
+
+            let pending = b["pending"]
+                .as_vec()
+                .map_or(&[][..], |v| v.as_slice())
+                .iter()
+                .map(|ti| TaskInstance::from_yaml(ti, task).map_err(|e| invalid(e.to_string())))
+                .collect::<std::result::Result<_, _>>()?;
+
+            let backfill = Backfill {
+                active: queue
+                    .iter()
+                    .filter(|ti| ti.backfill.as_deref() == Some(id.as_str()))
+                    .count(),
+                id: id.clone(),
+                task_id: task.task_id.clone(),
+                parallelism: b["parallelism"].as_i64().unwrap_or(1).max(1) as usize,
+                pending,
+            };
+
+            backfills.insert(id, backfill);
+        }
+
+        let mut paused = HashMap::new();
+        if let Some(h) = doc["paused"].as_hash() {
+            for (id, since) in h {
+                match (id.as_str(), since.as_str().map(parse_time)) {
+                    (Some(id), Some(Ok(since))) => {
+                        paused.insert(id.to_string(), since);
+                    }
+                    _ => return Err(invalid(format!("invalid paused task: {:?}", id)).into()),
+                }
+            }
+        }
+
+        let mut runs = HashMap::new();
+        if let Some(h) = doc["runs"].as_hash() {
+            for (id, n) in h {
+                match (id.as_str(), n.as_i64().map(u32::try_from)) {
+                    (Some(id), Some(Ok(n))) => {
+                        runs.insert(id.to_string(), n);
+                    }
+                    _ => return Err(invalid(format!("invalid run count: {:?}", id)).into()),
+                }
+            }
+        }
+
+        let datasets =
+            Datasets::from_state_yaml(&doc["datasets"]).map_err(|e| invalid(e.to_string()))?;
+
+        event!(
+            Level::INFO,
+            path = %path.display(),
+            tasks = tasks.len(),
+            queued = queue.len(),
+            paused = paused.len(),
+            "restored state"
+        );
+
+        self.tasks.lock().unwrap().extend(tasks);
+        self.task_q.lock().unwrap().extend(queue);
+        self.drain.lock().unwrap().extend(draining);
+        self.paused.lock().unwrap().extend(paused);
+        self.backfills.lock().unwrap().extend(backfills);
+        self.runs.lock().unwrap().extend(runs);
+        *self.datasets.lock().unwrap() = datasets;
+        self.wake.notify_one();
+
+        Ok(())
     }
 
     /// Drain task from schedule
     /// Scheduled tasks continue to run
     /// If task fails runs until no more retries left
-    pub fn drain_task(&mut self, task_id: TaskId) -> Result<()> {
-        self.drain.lock().unwrap().push(task_id);
+    pub fn drain_task(&self, task_id: TaskId) -> Result<()> {
+        if !self.tasks.lock().unwrap().contains_key(&task_id) {
+            return Err(format!("task '{}' does not exist", task_id).into());
+        }
+
+        event!(Level::INFO, id = task_id, "drain");
+
+        let mut drain = self.drain.lock().unwrap();
+        if !drain.contains(&task_id) {
+            drain.push(task_id);
+        }
         Ok(())
     }
 
     /// Immediately remove task from que and task map
     /// This is an expensive operation
-    #[allow(dead_code)]
-    fn kill_task(&mut self, _task_id: TaskId) -> Result<()> {
-        todo!();
+    pub fn kill_task(&self, task_id: TaskId) -> Result<()> {
+        if self.tasks.lock().unwrap().remove(&task_id).is_none() {
+            return Err(format!("task '{}' does not exist", task_id).into());
+        }
 
-        // if !self.tasks.contains_key(&task_id) {
-        //     return Err("Task does not exist".into())
-        // } else {
+        event!(Level::INFO, id = task_id, "kill");
 
-        // }
+        self.task_q
+            .lock()
+            .unwrap()
+            .retain(|ti| ti.task.task_id != task_id);
+        self.drain.lock().unwrap().retain(|d| d != &task_id);
+        self.paused.lock().unwrap().remove(&task_id);
+        self.held
+            .lock()
+            .unwrap()
+            .retain(|ti| ti.task.task_id != task_id);
+        self.backfills
+            .lock()
+            .unwrap()
+            .retain(|_, b| b.task_id != task_id);
+        self.runs.lock().unwrap().remove(&task_id);
+        self.datasets.lock().unwrap().remove_consumer(&task_id);
+
+        Ok(())
+    }
+
+    /// Run a task for every point of its schedule in a date range, at most
+    /// `parallelism` at a time. The runs leave the schedule alone and are
+    /// recorded as backfill in history.
+    /// Returns the id of the backfill and its number of runs
+    pub fn backfill_task(
+        &self,
+        task_id: &str,
+        options: BackfillOptions,
+    ) -> Result<(String, usize)> {
+        let task = match self.tasks.lock().unwrap().get(task_id) {
+            Some(t) => t.clone(),
+            None => return Err(format!("task '{}' does not exist", task_id).into()),
+        };
+
+        if options.parallelism == 0 {
+            return Err("parallelism must be at least 1".into());
+        }
+
+        let dates = backfill::logical_dates(&task, options.from, options.to)?;
+        if dates.is_empty() {
+            return Err("no scheduled runs in range".into());
+        }
+
+        let id = format!(
+            "backfill_{}_{}",
+            task_id,
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        );
+
+        let pending = dates
+            .iter()
+            .map(|date| {
+                let mut ti = TaskInstance::new(task.clone(), SystemTime::now(), 0);
+                ti.instance_id = format!("{}_{}", ti.instance_id, date_suffix(*date));
+                ti.trigger = Trigger::Backfill;
+                ti.downstream = options.downstream;
+                ti.logical_date = *date;
+                ti.backfill = Some(id.clone());
+                ti
+            })
+            .collect();
+
+        event!(
+            Level::INFO,
+            id = task_id,
+            backfill = id,
+            runs = dates.len(),
+            parallelism = options.parallelism,
+            "backfill"
+        );
+
+        self.backfills.lock().unwrap().insert(
+            id.clone(),
+            Backfill {
+                id: id.clone(),
+                task_id: task_id.to_string(),
+                parallelism: options.parallelism,
+                pending,
+                active: 0,
+            },
+        );
+
+        self.release_backfill(&id, false);
+
+        Ok((id, dates.len()))
+    }
+
+    /// Queue pending runs of a backfill while it has free slots,
+    /// after one of its runs `finished`
+    fn release_backfill(&self, id: &str, finished: bool) {
+        let mut backfills = self.backfills.lock().unwrap();
+
+        let b = match backfills.get_mut(id) {
+            Some(b) => b,
+            None => return,
+        };
+
+        if finished {
+            b.active = b.active.saturating_sub(1);
+        }
+
+        while b.active < b.parallelism {
+            match b.pending.pop_front() {
+                Some(mut ti) => {
+                    ti.exec_at = SystemTime::now();
+                    self.task_q.lock().unwrap().push(ti);
+                    b.active += 1;
+                }
+                None => break,
+            }
+        }
+
+        if b.active == 0 && b.pending.is_empty() {
+            event!(
+                Level::INFO,
+                id = b.task_id,
+                backfill = id,
+                "backfill finished"
+            );
+            backfills.remove(id);
+        }
+
+        self.wake.notify_one();
+    }
+
+    /// Backfills of a task in progress
+    pub fn backfills(&self, task_id: &str) -> Vec<Backfill> {
+        let mut backfills: Vec<Backfill> = self
+            .backfills
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|b| b.task_id == task_id)
+            .cloned()
+            .collect();
+
+        backfills.sort_by(|a, b| a.id.cmp(&b.id));
+        backfills
+    }
+
+    /// Stop running scheduled instances of a task, keeping its definition
+    /// Instances becoming due are held until the task is resumed, manual runs still run
+    pub fn pause_task(&self, task_id: &str) -> Result<()> {
+        if !self.tasks.lock().unwrap().contains_key(task_id) {
+            return Err(format!("task '{}' does not exist", task_id).into());
+        }
+
+        let mut paused = self.paused.lock().unwrap();
+        if paused.contains_key(task_id) {
+            return Err(format!("task '{}' is already paused", task_id).into());
+        }
+
+        event!(Level::INFO, id = task_id, "pause");
+
+        paused.insert(task_id.to_string(), SystemTime::now());
+        Ok(())
+    }
+
+    /// Continue scheduling a paused task, handling the runs it missed by `policy`
+    pub fn resume_task(&self, task_id: &str, policy: ResumePolicy) -> Result<()> {
+        if self.paused.lock().unwrap().remove(task_id).is_none() {
+            return Err(format!("task '{}' is not paused", task_id).into());
+        }
+
+        let mut missed: Vec<TaskInstance> = {
+            let mut held = self.held.lock().unwrap();
+            let (missed, rest) = held.drain(..).partition(|ti| ti.task.task_id == task_id);
+            *held = rest;
+            missed
+        };
+        missed.sort_by_key(|ti| ti.exec_at);
+
+        event!(
+            Level::INFO,
+            id = task_id,
+            policy = %policy,
+            missed = missed.len(),
+            "resume"
+        );
+
+        let now = SystemTime::now();
+        let mut q = self.task_q.lock().unwrap();
+
+        match policy {
+            ResumePolicy::CatchUp => {
+                if let Some(mut ti) = missed.into_iter().last() {
+                    // Run for the latest missed point, later points follow from it
+                    if let Some(latest) = ti.task.latest_point(ti.logical_date, now) {
+                        ti.logical_date = latest;
+                    }
+                    ti.exec_at = now;
+                    q.push(ti);
+                }
+            }
+            ResumePolicy::Skip => {
+                for mut ti in missed {
+                    if !ti.task.schedule.is_periodic() {
+                        ti.exec_at = now;
+                        q.push(ti);
+                        continue;
+                    }
+
+                    match ti.task.next_point(ti.logical_date, now) {
+                        Some(next) => {
+                            ti.logical_date = next;
+                            ti.exec_at = ti.task.exec_time(next);
+                            q.push(ti);
+                        }
+                        None => event!(
+                            Level::INFO,
+                            id = task_id,
+                            inst_id = ti.instance_id,
+                            "skipped missed run"
+                        ),
+                    }
+                }
+            }
+        }
+
+        drop(q);
+        self.wake.notify_one();
+
+        Ok(())
+    }
+
+    /// Successful scheduled runs of a task, counted towards its `max_runs`
+    pub fn runs(&self, task_id: &str) -> u32 {
+        self.runs
+            .lock()
+            .unwrap()
+            .get(task_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn is_paused(&self, task_id: &str) -> bool {
+        self.paused.lock().unwrap().contains_key(task_id)
+    }
+
+    /// Instances of a paused task held since they became due
+    pub fn held(&self, task_id: &str) -> Vec<TaskInstance> {
+        self.held
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|ti| ti.task.task_id == task_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Schedule an immediate run of a task, outside of its regular schedule
+    /// The run does not affect the schedule and is recorded as manual in history
+    /// Returns the id of the new [`TaskInstance`]
+    pub fn trigger_task(&self, task_id: &str, options: TriggerOptions) -> Result<String> {
+        self.trigger_as(task_id, options, Trigger::Manual)
+    }
+
+    /// Run a task now for something other than an operator, like a webhook
+    pub(crate) fn trigger_as(
+        &self,
+        task_id: &str,
+        options: TriggerOptions,
+        trigger: Trigger,
+    ) -> Result<String> {
+        let task = match self.tasks.lock().unwrap().get(task_id) {
+            Some(t) => t.clone(),
+            None => return Err(format!("task '{}' does not exist", task_id).into()),
+        };
+
+        // Only the values of parameters the task declares, any others would be passed
+        // to the command without its definition saying so
+        for name in options.params.keys() {
+            validate_param_name(name)?;
+            if !task.params.contains_key(name) {
+                return Err(format!("task '{}' has no parameter '{}'", task_id, name).into());
+            }
+        }
+
+        event!(
+            Level::INFO,
+            id = task_id,
+            downstream = options.downstream,
+            trigger = %trigger,
+            "trigger"
+        );
+
+        let mut ti = TaskInstance::new(task, SystemTime::now(), 0);
+        ti.trigger = trigger;
+        ti.params = options.params;
+        ti.downstream = options.downstream;
+
+        self.schedule_instance(ti)
+    }
+
+    /// Queued instances of a task, next to run first
+    pub fn queued(&self, task_id: &str) -> Vec<TaskInstance> {
+        let mut queued: Vec<TaskInstance> = self
+            .task_q
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|ti| ti.task.task_id == task_id)
+            .cloned()
+            .collect();
+
+        queued.sort_by_key(|ti| ti.exec_at);
+        queued
+    }
+}
+
+/// Logical date as part of an instance id, `20230101T000000`
+fn date_suffix(date: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(date)
+        .format("%Y%m%dT%H%M%S")
+        .to_string()
+}
+
+impl FromStr for ResumePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(ResumePolicy::Skip),
+            "catchup" | "catch-up" => Ok(ResumePolicy::CatchUp),
+            _ => Err(format!("invalid resume policy: {} (skip | catchup)", s)),
+        }
+    }
+}
+
+impl fmt::Display for ResumePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResumePolicy::Skip => write!(f, "skip"),
+            ResumePolicy::CatchUp => write!(f, "catchup"),
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// Drive the scheduler for `duration`
+    async fn run_for(sched: &Arc<Scheduler>, duration: Duration) {
+        let _ = tokio::time::timeout(duration, sched.clone().run()).await;
+    }
+
+    #[tokio::test]
+    async fn resume_catchup_runs_once_for_the_latest_point() {
+        let sched = Arc::new(Scheduler::new());
+        let task = Task::from_yaml_str("task_id: t\nschedule: interval:1m\ncmd: 'true'").unwrap();
+        let start = SystemTime::now() - Duration::from_secs(10 * 60 + 30);
+
+        sched.add_task(task, start).unwrap();
+        sched.pause_task("t").unwrap();
+        run_for(&sched, Duration::from_millis(200)).await;
+        assert_eq!(sched.held("t").len(), 1);
+
+        sched.resume_task("t", ResumePolicy::CatchUp).unwrap();
+
+        for _ in 0..50 {
+            run_for(&sched, Duration::from_millis(100)).await;
+            if sched.history.lock().unwrap().runs(Some("t")).count() > 0 {
+                break;
+            }
+        }
+        run_for(&sched, Duration::from_millis(500)).await;
+
+        assert_eq!(sched.history.lock().unwrap().runs(Some("t")).count(), 1);
+
+        let now = SystemTime::now();
+        let q = sched.task_q.lock().unwrap();
+        assert_eq!(q.len(), 1);
+        let next = q.peek().unwrap();
+        assert!(next.exec_at > now);
+        assert!(next.logical_date <= now + Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn resume_skip_keeps_dataset_triggers() {
+        let sched = Arc::new(Scheduler::new());
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        for definition in [
+            "task_id: load\nschedule: interval:1d\nproduces: [orders]\ncmd: echo load",
+            "task_id: report\nschedule: datasets:orders\ncmd: echo report",
+        ] {
+            sched
+                .add_task(Task::from_yaml_str(definition).unwrap(), later)
+                .unwrap();
+        }
+        let runs = |task_id| sched.history.lock().unwrap().runs(Some(task_id)).count();
+
+        sched.pause_task("report").unwrap();
+        sched
+            .trigger_task("load", TriggerOptions::default())
+            .unwrap();
+        for _ in 0..50 {
+            run_for(&sched, Duration::from_millis(100)).await;
+            if !sched.held("report").is_empty() {
+                break;
+            }
+        }
+        assert_eq!(sched.held("report").len(), 1);
+        assert_eq!(runs("report"), 0);
+
+        sched.resume_task("report", ResumePolicy::Skip).unwrap();
+        for _ in 0..50 {
+            run_for(&sched, Duration::from_millis(100)).await;
+            if runs("report") > 0 {
+                break;
+            }
+        }
+        assert_eq!(runs("report"), 1);
+        assert!(sched.held("report").is_empty());
+    }
+
+    /// Drive the scheduler until no backfill of `task_id` is left, at most 10s
+    async fn finish_backfills(sched: &Arc<Scheduler>, task_id: &str) {
+        for _ in 0..100 {
+            run_for(sched, Duration::from_millis(100)).await;
+            if sched.backfills(task_id).is_empty() {
+                return;
+            }
+        }
+        panic!("backfill of {} did not finish", task_id);
+    }
+
+    #[tokio::test]
+    async fn backfill_runs_every_point_with_bounded_parallelism() {
+        let sched = Arc::new(Scheduler::new());
+        let task =
+            Task::from_yaml_str("task_id: b\nschedule: cron:0 * * * *\ncmd: sleep 0.2").unwrap();
+        sched.add_task(task, SystemTime::now()).unwrap();
+
+        let t = |data| parse_time(data).unwrap();
+        let options = BackfillOptions {
+            from: t("2024-01-01T00:00:00Z"),
+            to: t("2024-01-01T05:00:00Z"),
+            parallelism: 2,
+            downstream: false,
+        };
+
+        let (_, runs) = sched.backfill_task("b", options).unwrap();
+        assert_eq!(runs, 6);
+        // Released up to the parallelism, the rest waits
+        let backfill = sched.backfills("b").remove(0);
+        assert_eq!((backfill.active, backfill.pending.len()), (2, 4));
+
+        finish_backfills(&sched, "b").await;
+
+        let history = sched.history.lock().unwrap();
+        let records: Vec<&RunRecord> = history.runs(Some("b")).collect();
+        assert!(records.iter().all(|r| r.trigger == Trigger::Backfill));
+        assert!(records.iter().all(|r| r.status == RunStatus::Success));
+
+        // Both ends of the range are included
+        let dates: BTreeSet<SystemTime> = records.iter().map(|r| r.logical_date).collect();
+        let expected: BTreeSet<SystemTime> = (0..6)
+            .map(|h| t("2024-01-01T00:00:00Z") + Duration::from_secs(h * 3600))
+            .collect();
+        assert_eq!(dates, expected);
+        assert_eq!(records.len(), 6);
+
+        // Runs at the same time, at any start of a run
+        let overlapping = |start: SystemTime| {
+            records
+                .iter()
+                .filter(|r| r.started_at <= start && start < r.finished_at)
+                .count()
+        };
+        let max = records.iter().map(|r| overlapping(r.started_at)).max();
+        assert_eq!(max, Some(2));
+    }
+
+    #[tokio::test]
+    async fn backfill_range_between_points() {
+        let sched = Arc::new(Scheduler::new());
+        let task =
+            Task::from_yaml_str("task_id: b\nschedule: cron:0 * * * *\ncmd: echo run").unwrap();
+        sched.add_task(task, SystemTime::now()).unwrap();
+
+        let t = |data| parse_time(data).unwrap();
+        let options = |from, to| BackfillOptions {
+            from: t(from),
+            to: t(to),
+            parallelism: 8,
+            downstream: false,
+        };
+
+        let (_, runs) = sched
+            .backfill_task("b", options("2024-01-01T00:30:00Z", "2024-01-01T03:59:59Z"))
+            .unwrap();
+        assert_eq!(runs, 3);
+        finish_backfills(&sched, "b").await;
+
+        let dates: BTreeSet<SystemTime> = sched
+            .history
+            .lock()
+            .unwrap()
+            .runs(Some("b"))
+            .map(|r| r.logical_date)
+            .collect();
+        let expected = [
+            "2024-01-01T01:00:00Z",
+            "2024-01-01T02:00:00Z",
+            "2024-01-01T03:00:00Z",
+        ];
+        assert_eq!(dates, expected.into_iter().map(t).collect());
 
-        // Ok(())
+        assert!(sched
+            .backfill_task("b", options("2024-01-01T00:10:00Z", "2024-01-01T00:50:00Z"))
+            .is_err());
+        assert!(sched
+            .backfill_task("b", options("2024-01-02T00:00:00Z", "2024-01-01T00:00:00Z"))
+            .is_err());
+        let mut zero = options("2024-01-01T00:00:00Z", "2024-01-01T01:00:00Z");
+        zero.parallelism = 0;
+        assert!(sched.backfill_task("b", zero).is_err());
     }
 }
@@ -0,0 +1,343 @@
+//! Secrets, credentials tasks need without keeping them in their definitions
+//!
+//! Tasks refer to secrets by name and get their values as environment variables:
+//! ```yaml
+//! task_id: load_orders
+//! schedule: interval:1d
+//! cmd: PGPASSWORD="$DB_PASSWORD" psql -h warehouse -U etl -f load.sql orders
+//! secrets:
+//!   DB_PASSWORD: warehouse_db_password   # variable: secret name
+//!   API_KEY: api_key
+//! ```
+//! Commands should only read secrets from their environment. They are also template
+//! values, `secrets.<var>`, but rendered into `cmd` they show in the arguments of the
+//! process, which any user of the host can list.
+//! Secrets are looked up at each run in the providers of the scheduler, in order. The
+//! server provides the files of `secrets_dir`, one per secret named after it, and the
+//! environment variables `<secrets_env_prefix><NAME>` of its own environment, which are
+//! not passed on to commands. Other stores can be added by implementing
+//! [`SecretProvider`].
+//!
+//! Values are replaced with `***` in the logs, errors, commands and outputs recorded for
+//! a run.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::process::Command;
+
+use crate::task::Params;
+use crate::Result;
+
+/// Replaces the values of secrets in what is recorded of a run
+pub const REDACTED: &str = "***";
+
+/// Store secrets are looked up in
+pub trait SecretProvider: Send + Sync {
+    /// Value of the secret `name`, `None` if the provider does not have it
+    fn get(&self, name: &str) -> Result<Option<String>>;
+
+    /// Shown in logs and errors
+    fn describe(&self) -> String;
+
+    /// Keep the provider's own data out of the environment of `cmd`
+    fn scrub(&self, _cmd: &mut Command) {}
+}
+
+/// Secrets in files of a directory, named after the secret, like those mounted by
+/// container orchestrators. A trailing newline is not part of the value.
+#[derive(Debug, Clone)]
+pub struct DirProvider {
+    dir: PathBuf,
+}
+
+/// Secrets in environment variables of the server, `<prefix><NAME>` with the name in
+/// uppercase and `.` and `-` replaced by `_`
+#[derive(Debug, Clone)]
+pub struct EnvProvider {
+    prefix: String,
+}
+
+/// Providers of the scheduler, looked up in order
+#[derive(Clone, Default)]
+pub struct Secrets {
+    providers: Vec<Arc<dyn SecretProvider>>,
+}
+
+/// Check a secret name: letters, digits, `.`, `_` and `-`
+pub fn validate_secret_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "invalid secret name '{}', expected letters, digits, '.', '_' and '-'",
+            name
+        )
+        .into()),
+    }
+}
+
+/// `text` with the values of `secrets` replaced by [`REDACTED`]
+pub fn redact(text: &str, secrets: &Params) -> String {
+    let mut values: Vec<&String> = secrets.values().filter(|v| !v.is_empty()).collect();
+    // Longest first, so a secret containing another is replaced whole
+    values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+
+    let mut text = text.to_string();
+    for value in values {
+        if text.contains(value.as_str()) {
+            text = text.replace(value.as_str(), REDACTED);
+        }
+    }
+    text
+}
+
+impl Secrets {
+    pub fn new() -> Self {
+        Secrets::default()
+    }
+
+    /// Add a provider, looked up after those already added
+    pub fn with(mut self, provider: impl SecretProvider + 'static) -> Self {
+        self.providers.push(Arc::new(provider));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Value of the secret `name` in the first provider having it
+    pub fn get(&self, name: &str) -> Result<String> {
+        for provider in &self.providers {
+            let value = provider
+                .get(name)
+                .map_err(|e| format!("failed to read secret '{}': {}", name, e))?;
+
+            if let Some(value) = value {
+                return Ok(value);
+            }
+        }
+
+        Err(format!("secret '{}' not found", name).into())
+    }
+
+    /// Values of the secrets a task refers to, by variable
+    pub fn resolve(&self, refs: &Params) -> Result<Params> {
+        refs.iter()
+            .map(|(var, name)| Ok((var.clone(), self.get(name)?)))
+            .collect()
+    }
+
+    /// Keep the data of every provider out of the environment of `cmd`
+    pub fn scrub(&self, cmd: &mut Command) {
+        for provider in &self.providers {
+            provider.scrub(cmd);
+        }
+    }
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.providers.iter().map(|p| p.describe()))
+            .finish()
+    }
+}
+
+impl DirProvider {
+    pub fn new(dir: PathBuf) -> Self {
+        DirProvider { dir }
+    }
+}
+
+impl SecretProvider for DirProvider {
+    fn get(&self, name: &str) -> Result<Option<String>> {
+        validate_secret_name(name)?;
+
+        match std::fs::read_to_string(self.dir.join(name)) {
+            Ok(mut value) => {
+                if value.ends_with('\n') {
+                    value.pop();
+                    if value.ends_with('\r') {
+                        value.pop();
+                    }
+                }
+                Ok(Some(value))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("dir:{}", self.dir.display())
+    }
+}
+
+impl EnvProvider {
+    pub fn new(prefix: &str) -> Self {
+        EnvProvider {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn var(&self, name: &str) -> String {
+        let name: String = name
+            .chars()
+            .map(|c| match c {
+                '.' | '-' => '_',
+                c => c.to_ascii_uppercase(),
+            })
+            .collect();
+
+        format!("{}{}", self.prefix, name)
+    }
+}
+
+impl SecretProvider for EnvProvider {
+    fn get(&self, name: &str) -> Result<Option<String>> {
+        match std::env::var(self.var(name)) {
+            Ok(value) => Ok(Some(value)),
+            Err(std::env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("env:{}", self.prefix)
+    }
+
+    fn scrub(&self, cmd: &mut Command) {
+        for (name, _) in std::env::vars_os() {
+            if name.to_string_lossy().starts_with(&self.prefix) {
+                cmd.env_remove(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Variables `cmd` sets, `None` for those it removes
+    fn envs(cmd: &Command) -> Vec<(String, Option<String>)> {
+        cmd.as_std()
+            .get_envs()
+            .map(|(k, v)| {
+                let string = |s: &std::ffi::OsStr| s.to_string_lossy().into_owned();
+                (string(k), v.map(string))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dir_provider() {
+        let dir = std::env::temp_dir().join(format!("chainz-secrets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("db_password"), "hunter2\n").unwrap();
+        std::fs::write(dir.join("api.key"), "line one\nline two\r\n").unwrap();
+
+        let provider = DirProvider::new(dir.clone());
+        assert_eq!(
+            provider.get("db_password").unwrap().as_deref(),
+            Some("hunter2")
+        );
+        assert_eq!(
+            provider.get("api.key").unwrap().as_deref(),
+            Some("line one\nline two")
+        );
+        assert_eq!(provider.get("missing").unwrap(), None);
+
+        // Names never leave the directory
+        for name in ["../db_password", "", ".hidden", "a/b"] {
+            assert!(provider.get(name).is_err(), "{}", name);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn env_provider() {
+        std::env::set_var("CHAINZ_TEST_PROVIDER_SECRET_DB_PASSWORD_2", "hunter2");
+
+        let provider = EnvProvider::new("CHAINZ_TEST_PROVIDER_SECRET_");
+        assert_eq!(
+            provider.get("db.password-2").unwrap().as_deref(),
+            Some("hunter2")
+        );
+        assert_eq!(provider.get("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn providers_in_order() {
+        std::env::set_var("CHAINZ_TEST_ORDER_SECRET_FIRST", "from env");
+        std::env::set_var("CHAINZ_TEST_ORDER_SECRET_SECOND", "from env");
+        let dir = std::env::temp_dir().join(format!("chainz-secrets-order-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("second"), "from dir").unwrap();
+
+        let secrets = Secrets::new()
+            .with(DirProvider::new(dir.clone()))
+            .with(EnvProvider::new("CHAINZ_TEST_ORDER_SECRET_"));
+
+        assert_eq!(secrets.get("first").unwrap(), "from env");
+        assert_eq!(secrets.get("second").unwrap(), "from dir");
+        assert_eq!(
+            secrets.get("third").unwrap_err().to_string(),
+            "secret 'third' not found"
+        );
+
+        let refs = Params::from([("FIRST".to_string(), "first".to_string())]);
+        assert_eq!(secrets.resolve(&refs).unwrap()["FIRST"], "from env");
+        assert!(secrets
+            .resolve(&Params::from([("X".to_string(), "third".to_string())]))
+            .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scrub() {
+        std::env::set_var("CHAINZ_TEST_SCRUB_SECRET_OTHER", "hunter2");
+        let secrets = Secrets::new().with(EnvProvider::new("CHAINZ_TEST_SCRUB_SECRET_"));
+
+        let mut cmd = Command::new("true");
+        secrets.scrub(&mut cmd);
+        assert!(envs(&cmd).contains(&("CHAINZ_TEST_SCRUB_SECRET_OTHER".to_string(), None)));
+
+        // Also when added back on purpose, like `inherit_env` does
+        let mut cmd = Command::new("true");
+        cmd.env_clear()
+            .env("CHAINZ_TEST_SCRUB_SECRET_OTHER", "hunter2")
+            .env("KEPT", "1");
+        secrets.scrub(&mut cmd);
+        let envs = envs(&cmd);
+        assert!(!envs
+            .iter()
+            .any(|(k, v)| k.starts_with("CHAINZ_TEST_SCRUB") && v.is_some()));
+        assert!(envs.contains(&("KEPT".to_string(), Some("1".to_string()))));
+    }
+
+    #[test]
+    fn redact_values() {
+        let secrets = Params::from([
+            ("A".to_string(), "pass".to_string()),
+            ("B".to_string(), "password".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]);
+
+        assert_eq!(
+            redact("psql password=password user=pass", &secrets),
+            "psql ***=*** user=***"
+        );
+        assert_eq!(redact("nothing to hide", &secrets), "nothing to hide");
+    }
+}
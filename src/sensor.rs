@@ -0,0 +1,456 @@
+//! File sensors, tasks that wait for files to arrive instead of running a command
+//!
+//! ```yaml
+//! task_id: wait_export
+//! type: sensor
+//! schedule: interval:1d
+//! path: /data/export/*.csv   # path or glob
+//! stable_for: 30s            # size unchanged for this long, optional
+//! poke_interval: 10s         # the default is 30s
+//! timeout: 6h                # fails the run when nothing matched in time
+//! downstream: [load_export]
+//! ```
+//! On Linux the directory the pattern starts in is watched with inotify to notice new files
+//! right away. The pattern is checked every `poke_interval` as well, which covers files in
+//! subdirectories and filesystems without inotify support.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use crate::task::TaskId;
+use crate::time::{format_duration, parse_duration};
+use crate::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSensor {
+    /// Path or glob pattern of the files waited for
+    pub path: String,
+    /// Only succeed once the size of every matched file did not change for this long
+    pub stable_for: Option<Duration>,
+    /// How often the pattern is checked without a change notification
+    pub poke_interval: Duration,
+    /// Tasks triggered once the sensor succeeds, with the matched files
+    pub downstream: Vec<TaskId>,
+}
+
+impl FileSensor {
+    pub const POKE_INTERVAL: Duration = Duration::from_secs(30);
+
+    /// Output of a successful run holding the matched files, one per line
+    pub const FILES_PARAM: &'static str = "sensor_files";
+
+    pub const KEYS: [&'static str; 4] = ["path", "stable_for", "poke_interval", "downstream"];
+
+    pub fn new(path: &str) -> Result<Self> {
+        glob::Pattern::new(path).map_err(|e| format!("invalid path pattern {}: {}", path, e))?;
+
+        Ok(FileSensor {
+            path: path.to_string(),
+            stable_for: None,
+            poke_interval: FileSensor::POKE_INTERVAL,
+            downstream: Vec::new(),
+        })
+    }
+
+    /// Parse the sensor keys of a task definition
+    pub fn from_yaml(doc: &Yaml) -> Result<Self> {
+        let mut sensor = FileSensor::new(doc["path"].as_str().ok_or("no path provided")?)?;
+
+        if let Some(d) = doc["stable_for"].as_str() {
+            sensor.stable_for = Some(parse_duration(d)?);
+        }
+
+        if let Some(d) = doc["poke_interval"].as_str() {
+            sensor.poke_interval = parse_duration(d)?;
+
+            if sensor.poke_interval.is_zero() {
+                return Err("poke_interval must be positive".into());
+            }
+        }
+
+        sensor.downstream = match &doc["downstream"] {
+            Yaml::BadValue => Vec::new(),
+            Yaml::String(id) => vec![id.clone()],
+            Yaml::Array(ids) => ids
+                .iter()
+                .map(|id| match id.as_str() {
+                    Some(id) => Ok(id.to_string()),
+                    None => Err(format!("invalid down-stream task: {:?}", id)),
+                })
+                .collect::<std::result::Result<_, _>>()?,
+            d => return Err(format!("invalid downstream: {:?}", d).into()),
+        };
+
+        Ok(sensor)
+    }
+
+    /// Add the sensor keys to a task definition
+    pub fn insert_yaml(&self, h: &mut Hash) {
+        h.insert(Yaml::String("path".into()), Yaml::String(self.path.clone()));
+        if let Some(stable_for) = self.stable_for {
+            h.insert(
+                Yaml::String("stable_for".into()),
+                Yaml::String(format_duration(stable_for)),
+            );
+        }
+        if self.poke_interval != FileSensor::POKE_INTERVAL {
+            h.insert(
+                Yaml::String("poke_interval".into()),
+                Yaml::String(format_duration(self.poke_interval)),
+            );
+        }
+        if !self.downstream.is_empty() {
+            h.insert(
+                Yaml::String("downstream".into()),
+                Yaml::Array(self.downstream.iter().cloned().map(Yaml::String).collect()),
+            );
+        }
+    }
+
+    /// Wait until files match the pattern and, with `stable_for`, stopped changing in size.
+    /// Returns the matched files, sorted.
+    pub async fn wait(&self) -> Vec<PathBuf> {
+        let dir = self.watched_dir();
+        let mut watch = None;
+
+        // Size of each matched file and since when it has it
+        let mut sizes: HashMap<PathBuf, (u64, Instant)> = HashMap::new();
+
+        loop {
+            // Set up once the directory exists
+            if watch.is_none() {
+                watch = Watch::new(&dir);
+            }
+
+            let files = self.matches();
+            let now = Instant::now();
+            let stable_for = self.stable_for.unwrap_or_default();
+            let mut wait = self.poke_interval;
+
+            sizes.retain(|path, _| files.iter().any(|(p, _)| p == path));
+
+            let mut stable = !files.is_empty();
+
+            for (path, size) in &files {
+                let since = match sizes.get(path) {
+                    Some((s, since)) if s == size => *since,
+                    _ => {
+                        sizes.insert(path.clone(), (*size, now));
+                        now
+                    }
+                };
+
+                let left = stable_for.saturating_sub(now - since);
+                if !left.is_zero() {
+                    stable = false;
+                    wait = wait.min(left);
+                }
+            }
+
+            if stable {
+                return files.into_iter().map(|(path, _)| path).collect();
+            }
+
+            match &watch {
+                Some(w) => w.changed(wait).await,
+                None => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    /// Files matching the pattern with their sizes, unreadable entries are left out
+    fn matches(&self) -> Vec<(PathBuf, u64)> {
+        let mut files: Vec<(PathBuf, u64)> = glob::glob(&self.path)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|path| {
+                let meta = std::fs::metadata(&path).ok()?;
+                meta.is_file().then_some((path, meta.len()))
+            })
+            .collect();
+
+        files.sort();
+        files
+    }
+
+    /// Deepest directory of the pattern without wildcards
+    fn watched_dir(&self) -> PathBuf {
+        let path = Path::new(&self.path);
+        let literal = |c: &Component| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']);
+
+        let mut dir: PathBuf = path.components().take_while(literal).collect();
+
+        // A plain path names the file itself
+        if path.components().all(|c| literal(&c)) {
+            dir.pop();
+        }
+
+        match dir.as_os_str().is_empty() {
+            true => PathBuf::from("."),
+            false => dir,
+        }
+    }
+}
+
+/// Notifications of changes in a directory
+#[cfg(target_os = "linux")]
+struct Watch(tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>);
+
+#[cfg(target_os = "linux")]
+impl Watch {
+    /// `None` where inotify is unavailable, e.g. out of watches or a missing directory
+    fn new(dir: &Path) -> Option<Self> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+
+        // SAFETY: inotify_init1 has no memory safety requirements
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return None;
+        }
+        // SAFETY: fd is a new descriptor owned by nothing else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mask = libc::IN_CREATE
+            | libc::IN_MOVED_TO
+            | libc::IN_MODIFY
+            | libc::IN_CLOSE_WRITE
+            | libc::IN_ATTRIB;
+
+        // SAFETY: dir is a valid C string for the duration of the call
+        if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) } < 0 {
+            return None;
+        }
+
+        tokio::io::unix::AsyncFd::new(fd).ok().map(Watch)
+    }
+
+    /// Wait for a change in the directory, at most `timeout`
+    async fn changed(&self, timeout: Duration) {
+        use std::os::fd::AsRawFd;
+
+        if let Ok(Ok(mut guard)) = tokio::time::timeout(timeout, self.0.readable()).await {
+            let mut buf = [0u8; 4096];
+
+            // Only the arrival of events matters, drop them
+            // SAFETY: buf is valid for writes of its length
+            while unsafe { libc::read(self.0.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) } > 0
+            {
+            }
+
+            guard.clear_ready();
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Watch;
+
+#[cfg(not(target_os = "linux"))]
+impl Watch {
+    fn new(_dir: &Path) -> Option<Self> {
+        None
+    }
+
+    async fn changed(&self, _timeout: Duration) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use yaml_rust::YamlLoader;
+
+    use crate::scheduler::{Scheduler, TriggerOptions};
+    use crate::task::Task;
+
+    /// Temporary directory, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("chainz-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn pattern(&self, name: &str) -> String {
+            self.0.join(name).display().to_string()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn sensor(definition: &str) -> Result<FileSensor> {
+        FileSensor::from_yaml(&YamlLoader::load_from_str(definition)?[0])
+    }
+
+    #[test]
+    fn from_yaml() {
+        let s = sensor(
+            "path: /data/export/*.csv\nstable_for: 30s\npoke_interval: 10s\ndownstream: [a, b]",
+        )
+        .unwrap();
+        assert_eq!(s.path, "/data/export/*.csv");
+        assert_eq!(s.stable_for, Some(Duration::from_secs(30)));
+        assert_eq!(s.poke_interval, Duration::from_secs(10));
+        assert_eq!(s.downstream, ["a", "b"]);
+
+        let mut h = Hash::new();
+        s.insert_yaml(&mut h);
+        assert_eq!(FileSensor::from_yaml(&Yaml::Hash(h)).unwrap(), s);
+
+        let s = sensor("path: /data/ready\ndownstream: load").unwrap();
+        assert_eq!(s.stable_for, None);
+        assert_eq!(s.poke_interval, FileSensor::POKE_INTERVAL);
+        assert_eq!(s.downstream, ["load"]);
+
+        for definition in [
+            "stable_for: 30s",
+            "path: /data/[",
+            "path: /data/x\npoke_interval: 0s",
+            "path: /data/x\nstable_for: soon",
+            "path: /data/x\ndownstream: [1]",
+            "path: /data/x\ndownstream: {a: b}",
+        ] {
+            assert!(sensor(definition).is_err(), "{}", definition);
+        }
+    }
+
+    #[test]
+    fn watched_dir() {
+        for (path, dir) in [
+            ("/data/export/*.csv", "/data/export"),
+            ("/data/export/ready", "/data/export"),
+            ("/data/*/ready", "/data"),
+            ("/data/export-[0-9]/ready", "/data"),
+            ("*.csv", "."),
+            ("ready", "."),
+        ] {
+            let sensor = FileSensor::new(path).unwrap();
+            assert_eq!(sensor.watched_dir(), PathBuf::from(dir), "{}", path);
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let dir = TempDir::new("sensor-matches");
+        std::fs::write(dir.0.join("b.csv"), "12345").unwrap();
+        std::fs::write(dir.0.join("a.csv"), "1").unwrap();
+        std::fs::write(dir.0.join("c.txt"), "").unwrap();
+        std::fs::create_dir(dir.0.join("d.csv")).unwrap();
+
+        let sensor = FileSensor::new(&dir.pattern("*.csv")).unwrap();
+        assert_eq!(
+            sensor.matches(),
+            [(dir.0.join("a.csv"), 1), (dir.0.join("b.csv"), 5)]
+        );
+
+        let sensor = FileSensor::new(&dir.pattern("*.json")).unwrap();
+        assert!(sensor.matches().is_empty());
+    }
+
+    #[tokio::test]
+    async fn wait_for_new_files() {
+        let dir = TempDir::new("sensor-wait");
+        let mut sensor = FileSensor::new(&dir.pattern("*.csv")).unwrap();
+        // Noticed well before the next poke where inotify works
+        sensor.poke_interval = Duration::from_secs(2);
+
+        let path = dir.0.join("orders.csv");
+        let writer = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                std::fs::write(dir.0.join("ignored.txt"), "").unwrap();
+                std::fs::write(&path, "id\n").unwrap();
+                dir
+            })
+        };
+
+        let files = tokio::time::timeout(Duration::from_secs(5), sensor.wait())
+            .await
+            .unwrap();
+        assert_eq!(files, [path]);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_for_stable_files() {
+        let dir = TempDir::new("sensor-stable");
+        let path = dir.0.join("orders.csv");
+        std::fs::write(&path, "id\n").unwrap();
+
+        let mut sensor = FileSensor::new(&dir.pattern("*.csv")).unwrap();
+        sensor.stable_for = Some(Duration::from_millis(500));
+        sensor.poke_interval = Duration::from_millis(50);
+
+        let started = Instant::now();
+        let grow = async {
+            for i in 0..4 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                std::fs::write(&path, format!("id\n{}\n", "x".repeat(i + 1))).unwrap();
+            }
+        };
+
+        let (files, ()) = tokio::join!(sensor.wait(), grow);
+        assert_eq!(files, [path]);
+        // Stable for 500ms after the last write, ~400ms in
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn trigger_downstream_with_files() {
+        let dir = TempDir::new("sensor-trigger");
+        std::fs::write(dir.0.join("orders.csv"), "id\n").unwrap();
+
+        let sched = Arc::new(Scheduler::new());
+        let sensor = Task::from_yaml_str(&format!(
+            "task_id: arrival\ntype: sensor\nschedule: interval:1d\npath: {}\n\
+             poke_interval: 1s\ndownstream: [load]",
+            dir.pattern("*.csv")
+        ))
+        .unwrap();
+        let load = Task::from_yaml_str(
+            "task_id: load\nschedule: interval:1d\ncmd: echo \"$CHAINZ_PARAM_sensor_files\"",
+        )
+        .unwrap();
+
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        sched.add_task(sensor, later).unwrap();
+        sched.add_task(load, later).unwrap();
+        sched
+            .trigger_task("arrival", TriggerOptions::default())
+            .unwrap();
+
+        for _ in 0..50 {
+            let _ = tokio::time::timeout(Duration::from_millis(100), sched.clone().run()).await;
+            if sched.history.lock().unwrap().runs(Some("load")).count() > 0 {
+                break;
+            }
+        }
+
+        let history = sched.history.lock().unwrap();
+        let sensed = history.runs(Some("arrival")).next().unwrap();
+        let files = dir.0.join("orders.csv").display().to_string();
+        assert_eq!(sensed.outputs[FileSensor::FILES_PARAM], files);
+
+        let load = history.runs(Some("load")).next().unwrap();
+        assert_eq!(load.logs, format!("{}\n", files));
+    }
+}
@@ -1,130 +1,810 @@
-use std::ffi::CStr;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 use tracing::{event, Level};
+use yaml_rust::{Yaml, YamlEmitter};
 
-use tokio::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 
+use crate::auth::{authorize, Auth, Principal, Role};
+use crate::calendar::Calendar;
 use crate::command::{ClientCommand, HELP};
+use crate::config::ServerConfig;
+use crate::protocol::{Request, Response};
 use crate::scheduler::Scheduler;
+use crate::secrets::{DirProvider, EnvProvider};
+use crate::task::{self, ScheduleType, Task};
+use crate::tls::ServerTlsConfig;
+use crate::webhook::{self, Hooks};
 use crate::Result;
 
+/// Prefix of listen addresses that are unix socket paths
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// How long clients may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections without a request for this long are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// How long connections may take to close on shutdown before they are dropped
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Server {
-    listener: TcpListener,
+    listeners: Vec<Listener>,
+    scheduler: Arc<Scheduler>,
+    auth: Option<Arc<Auth>>,
+    tls: Option<TlsAcceptor>,
+    hooks: Option<Arc<Hooks>>,
+    shutdown_grace: Duration,
+    state_file: Option<PathBuf>,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    /// Serves webhooks over HTTP
+    Hooks(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+/// Bound unix socket, the socket file is removed on drop
+#[cfg(unix)]
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+/// Accepted client connection
+enum Conn {
+    Tcp(TcpStream, String),
+    Hook(TcpStream, String),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// State shared by all connections
+#[derive(Clone)]
+struct Shared {
     scheduler: Arc<Scheduler>,
+    auth: Option<Arc<Auth>>,
+    tls: Option<TlsAcceptor>,
+    hooks: Option<Arc<Hooks>>,
+    /// Set to true when the server shuts down
+    shutdown: watch::Receiver<bool>,
 }
 
 impl Server {
-    pub async fn new<A>(address: A) -> Self
-    where
-        A: ToSocketAddrs + std::fmt::Display + tracing::Value,
-    {
-        Self {
-            listener: TcpListener::bind(address).await.unwrap(),
+    /// Permissions of unix sockets bound by [`Server::listen`]
+    pub const UNIX_SOCKET_MODE: u32 = 0o660;
+
+    /// How long running tasks may take to finish on shutdown before they are killed
+    pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+    /// Create a server listening on `address`, either `host:port` or `unix:<path>`
+    pub async fn new(address: &str) -> Result<Self> {
+        Server {
+            listeners: Vec::new(),
             scheduler: Arc::new(Scheduler::new()),
+            auth: None,
+            tls: None,
+            hooks: None,
+            shutdown_grace: Server::SHUTDOWN_GRACE,
+            state_file: None,
         }
+        .listen(address)
+        .await
     }
 
-    async fn handle_request(&self, mut stream: TcpStream) {
-        event!(Level::TRACE, "client connected");
+    /// Create a server as described by `config`, restoring the state saved on the last
+    /// shutdown and adding the tasks in its tasks directory.
+    /// The config is expected to be validated.
+    pub async fn from_config(config: &ServerConfig) -> Result<Self> {
+        let mut scheduler = config.scheduler.clone();
+        if let Some(dir) = &config.calendars_dir {
+            scheduler.calendars = Calendar::load_dir(dir)?;
+        }
+        if let Some(dir) = &config.secrets_dir {
+            scheduler.secrets = scheduler.secrets.with(DirProvider::new(dir.clone()));
+        }
+        if let Some(prefix) = &config.secrets_env_prefix {
+            scheduler.secrets = scheduler.secrets.with(EnvProvider::new(prefix));
+        }
 
-        let sched = self.scheduler.clone();
+        let mut server = Server {
+            listeners: Vec::new(),
+            scheduler: Arc::new(Scheduler::with_config(scheduler)),
+            auth: None,
+            tls: None,
+            hooks: None,
+            shutdown_grace: config.shutdown_grace,
+            state_file: config.state_file.clone(),
+        };
 
-        tokio::spawn(async move {
-            // let (stream_reader, mut stream_writer) = stream.split();
-            // let mut buf_stream_reader = BufReader::new(stream_reader);
+        if let Some(path) = &config.state_file {
+            if path.exists() {
+                server.scheduler.restore_state(path)?;
+            }
+        }
 
-            loop {
-                let mut buf = [0; 1024];
-                // buf.clear();
-                let _bytes = stream.read(&mut buf).await.unwrap();
-                // buf_stream_reader.read(buf )
+        for address in &config.listen {
+            server = server.listen_mode(address, config.unix_socket_mode).await?;
+        }
 
-                let c = CStr::from_bytes_until_nul(&buf).unwrap().to_str().unwrap();
+        if let Some(path) = &config.tokens_file {
+            server = server.with_auth(Auth::from_file(&path.to_string_lossy())?);
+        }
 
-                println!("{}", c == "TEST\r\n");
+        if let Some(tls) = &config.tls {
+            server = server.with_tls(tls)?;
+        }
 
-                event!(Level::INFO, c);
+        if let (Some(address), Some(path)) = (&config.hooks_listen, &config.hooks_file) {
+            let hooks = Hooks::from_file(&path.to_string_lossy())?;
+            server = server.with_hooks(address, hooks).await?;
+        }
 
-                let command = match ClientCommand::from_str(&c[0..c.len() - 2]) {
-                    Ok(c) => c,
-                    Err(e) => ClientCommand::Error(e.to_string()),
-                };
+        if let Some(dir) = &config.tasks_dir {
+            server.add_tasks_from(dir)?;
+        }
 
-                match command {
-                    ClientCommand::Add(task) => {
-                        let resp: String = match sched.add_task(task, SystemTime::now()) {
-                            Ok(()) => "task successfully added".to_string(),
-                            Err(e) => e.to_string(),
-                        };
+        Ok(server)
+    }
 
-                        stream.write(resp.as_bytes()).await.unwrap();
-                    }
+    /// Additionally listen on `address`, either `host:port` or `unix:<path>`
+    pub async fn listen(self, address: &str) -> Result<Self> {
+        self.listen_mode(address, Server::UNIX_SOCKET_MODE).await
+    }
 
-                    ClientCommand::Drain(_task_id) => {
-                        todo!();
-                    }
-                    ClientCommand::Kill(_task_id) => {
-                        todo!();
-                    }
-                    ClientCommand::List => {
-                        stream
-                            .write(format!("{:?}", sched.tasks.lock().unwrap()).as_bytes())
-                            .await
-                            .unwrap();
-                    }
-                    ClientCommand::Help => {
-                        stream.write(HELP.as_bytes()).await.unwrap();
-                    }
+    async fn listen_mode(mut self, address: &str, mode: u32) -> Result<Self> {
+        match address.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => return self.listen_unix(path, mode),
+            #[cfg(not(unix))]
+            Some(_) => return Err("unix sockets are not supported on this platform".into()),
+            None => {}
+        }
 
-                    ClientCommand::Noop => {
-                        stream.write(b"nothing happened").await.unwrap();
-                    }
-                    ClientCommand::Error(e) => {
-                        stream.write(e.to_string().as_bytes()).await.unwrap();
-                    }
-                    ClientCommand::Exit => {
-                        event!(Level::TRACE, "closing connection to client");
-                        stream.write(b"closing connection").await.unwrap();
-                        stream.shutdown().await.unwrap();
-                        stream.flush().await.unwrap();
-                        return;
-                    }
-                };
-                stream.flush().await.unwrap();
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| format!("failed to bind {}: {}", address, e))?;
+
+        self.listeners.push(Listener::Tcp(listener));
+        Ok(self)
+    }
+
+    /// Additionally listen on a unix socket at `path` with permissions `mode`.
+    /// Clients connecting through it are admins, access is controlled by the
+    /// permissions of the socket and its directory.
+    #[cfg(unix)]
+    pub fn listen_unix(mut self, path: &str, mode: u32) -> Result<Self> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let path = PathBuf::from(path);
+
+        // Left behind by a server that did not shut down cleanly
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                return Err(format!("{} exists and is not a socket", path.display()).into());
+            }
+            if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                return Err(format!("{} is in use by another server", path.display()).into());
+            }
+            std::fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)
+            .map_err(|e| format!("failed to bind {}: {}", path.display(), e))?;
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+
+        self.listeners
+            .push(Listener::Unix(UnixSocket { listener, path }));
+        Ok(self)
+    }
+
+    /// Require clients to authenticate with one of the tokens in `auth`
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Serve clients over TLS, optionally requiring client certificates.
+    /// Only applies to tcp listeners.
+    pub fn with_tls(mut self, config: &ServerTlsConfig) -> Result<Self> {
+        self.tls = Some(config.acceptor()?);
+        Ok(self)
+    }
+
+    /// Serve `hooks` on `address`, `host:port`, see [`crate::webhook`].
+    /// Requests are authenticated by their signature, tokens do not apply.
+    pub async fn with_hooks(mut self, address: &str, hooks: Hooks) -> Result<Self> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| format!("failed to bind {}: {}", address, e))?;
+
+        self.listeners.push(Listener::Hooks(listener));
+        self.hooks = Some(Arc::new(hooks));
+        Ok(self)
+    }
+
+    /// Add the task definitions (`*.yaml`, `*.yml`) in `dir`, in order of file name
+    fn add_tasks_from(&self, dir: &Path) -> Result<()> {
+        let mut files = Vec::new();
+
+        for entry in std::fs::read_dir(dir)
+            .map_err(|e| format!("failed to read tasks dir {}: {}", dir.display(), e))?
+        {
+            let path = entry?.path();
+
+            if path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|ext| ext == "yaml" || ext == "yml")
+            {
+                files.push(path);
+            }
+        }
+
+        files.sort();
+
+        for path in files {
+            let task = std::fs::read_to_string(&path)
+                .map_err(Into::into)
+                .and_then(|data| Task::from_yaml_str(&data))
+                .map_err(|e| format!("invalid task {}: {}", path.display(), e))?;
+
+            if self
+                .scheduler
+                .tasks
+                .lock()
+                .unwrap()
+                .contains_key(&task.task_id)
+            {
+                event!(
+                    Level::INFO,
+                    id = task.task_id,
+                    file = %path.display(),
+                    "task restored from state, skipped"
+                );
+                continue;
             }
-        });
+
+            let start_time = task.start_time.unwrap_or_else(SystemTime::now);
+
+            event!(Level::INFO, id = task.task_id, file = %path.display(), "loaded task");
+
+            self.scheduler
+                .add_task(task, start_time)
+                .map_err(|e| format!("failed to add task {}: {}", path.display(), e))?;
+        }
+
+        Ok(())
     }
 
+    /// Addresses listened on, with the ports chosen for `host:0` resolved
+    pub fn addresses(&self) -> Result<Vec<String>> {
+        self.listeners.iter().map(Listener::address).collect()
+    }
+
+    /// Serve clients until SIGTERM or SIGINT, then shut down gracefully
     pub async fn run(&mut self) -> Result<()> {
-        let addr = self.listener.local_addr()?.to_string(); // temp lazy solution, because im lazy
+        self.run_until(shutdown_signal()).await
+    }
 
-        event!(Level::INFO, address = addr, "starting server");
+    /// Serve clients until `signal` completes, then shut down gracefully:
+    /// - stop starting instances and accepting clients, close client connections
+    /// - wait for running instances up to the shutdown grace, kill the rest
+    /// - save the scheduler state, if a state file is configured
+    ///
+    /// Without authentication, tcp listeners must be on a loopback address.
+    pub async fn run_until<F>(&mut self, signal: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        if self.auth.is_none() {
+            for listener in &self.listeners {
+                if let Listener::Tcp(l) = listener {
+                    let addr = l.local_addr()?;
+                    if !addr.ip().is_loopback() {
+                        return Err(format!(
+                            "refusing to listen on {} without authentication, set a tokens file",
+                            addr
+                        )
+                        .into());
+                    }
+                }
+            }
+        }
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        let shared = Shared {
+            scheduler: self.scheduler.clone(),
+            auth: self.auth.clone(),
+            tls: self.tls.clone(),
+            hooks: self.hooks.clone(),
+            shutdown: shutdown_rx,
+        };
+
+        let mut accept_loops = JoinSet::new();
+
+        for listener in self.listeners.drain(..) {
+            let addr = listener.address()?;
+
+            match &listener {
+                Listener::Hooks(_) => event!(Level::INFO, address = addr, "serving hooks"),
+                _ => event!(Level::INFO, address = addr, "starting server"),
+            }
+
+            accept_loops.spawn(listener.run(shared.clone()));
+        }
 
         let sched = self.scheduler.clone();
 
-        tokio::join!(sched.run(), self.run_listener());
+        tokio::select! {
+            _ = sched.run() => {}
+            _ = signal => {}
+        }
+
+        event!(Level::INFO, "shutting down");
+
+        let _ = shutdown.send(true);
+        while accept_loops.join_next().await.is_some() {}
+
+        self.scheduler.shutdown(self.shutdown_grace).await;
+        task::remove_output_dir();
+
+        if let Some(path) = &self.state_file {
+            self.scheduler.save_state(path)?;
+        }
+
+        event!(Level::INFO, "shut down");
 
         Ok(())
     }
+}
+
+/// Completes on the first SIGTERM or SIGINT
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = term.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    err = e.to_string(),
+                    "failed to listen for SIGTERM"
+                );
+            }
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+impl Listener {
+    fn address(&self) -> Result<String> {
+        match self {
+            Listener::Tcp(l) | Listener::Hooks(l) => Ok(l.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(s) => Ok(format!("{}{}", UNIX_PREFIX, s.path.display())),
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<Conn> {
+        match self {
+            Listener::Tcp(l) => {
+                let (stream, addr) = l.accept().await?;
+                Ok(Conn::Tcp(stream, addr.to_string()))
+            }
+            Listener::Hooks(l) => {
+                let (stream, addr) = l.accept().await?;
+                Ok(Conn::Hook(stream, addr.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(s) => Ok(Conn::Unix(s.listener.accept().await?.0)),
+        }
+    }
+
+    /// Accept clients, each served in its own task, until shutdown.
+    /// Returns once all clients are disconnected, dropping the connections
+    /// still open after [`CLOSE_TIMEOUT`].
+    async fn run(self, shared: Shared) {
+        let mut shutdown = shared.shutdown.clone();
+        let mut conns = JoinSet::new();
 
-    async fn run_listener(&self) {
         loop {
-            match self.listener.accept().await {
-                Ok((stream, _addr)) => {
-                    self.handle_request(stream).await;
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                Some(_) = conns.join_next(), if !conns.is_empty() => {}
+                conn = self.accept() => match conn {
+                    Ok(conn) => {
+                        conns.spawn(conn.serve(shared.clone()));
+                    }
+                    Err(e) => {
+                        event!(
+                            Level::ERROR,
+                            err = format!("{}", e),
+                            "failed to accept request"
+                        );
+                    }
+                },
+            }
+        }
+
+        let drain = async { while conns.join_next().await.is_some() {} };
+
+        if timeout(CLOSE_TIMEOUT, drain).await.is_err() {
+            event!(
+                Level::WARN,
+                count = conns.len(),
+                "connections still open on shutdown, dropping them"
+            );
+            conns.shutdown().await;
+        }
+    }
+}
+
+impl Conn {
+    async fn serve(self, shared: Shared) {
+        match self {
+            Conn::Tcp(stream, peer) => handle_tcp(shared, stream, peer).await,
+            Conn::Hook(stream, peer) => handle_hook(shared, stream, peer).await,
+            #[cfg(unix)]
+            Conn::Unix(stream) => {
+                let peer = match stream.peer_cred() {
+                    Ok(cred) => format!("unix:uid={}", cred.uid()),
+                    Err(_) => "unix".to_string(),
+                };
+
+                event!(Level::TRACE, peer = peer, "client connected");
+
+                let principal = Principal {
+                    name: peer.clone(),
+                    role: Role::Admin,
+                };
+
+                serve(shared, stream, peer, Some(principal)).await
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+async fn handle_tcp(shared: Shared, stream: TcpStream, peer: String) {
+    event!(Level::TRACE, peer = peer, "client connected");
+
+    let principal = match shared.auth {
+        Some(_) => None,
+        None => Some(Principal::anonymous()),
+    };
+
+    match shared.tls.clone() {
+        Some(acceptor) => {
+            if let Some(stream) = accept_tls(acceptor, stream, &peer).await {
+                let certificate = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|c| c.first());
+                let principal = match (&shared.auth, certificate) {
+                    (Some(auth), Some(cert)) => match auth.authenticate_certificate(cert) {
+                        Some(p) => {
+                            event!(Level::INFO, peer = peer, user = p.name, "authenticated");
+                            Some(p)
+                        }
+                        None => principal,
+                    },
+                    _ => principal,
+                };
+
+                serve(shared, stream, peer, principal).await
+            }
+        }
+        None => serve(shared, stream, peer, principal).await,
+    }
+}
+
+async fn handle_hook(shared: Shared, stream: TcpStream, peer: String) {
+    let hooks = match &shared.hooks {
+        Some(hooks) => hooks,
+        None => return,
+    };
+
+    match shared.tls.clone() {
+        Some(acceptor) => {
+            if let Some(stream) = accept_tls(acceptor, stream, &peer).await {
+                webhook::serve(hooks, &shared.scheduler, stream, &peer).await
+            }
+        }
+        None => webhook::serve(hooks, &shared.scheduler, stream, &peer).await,
+    }
+}
+
+/// Complete the TLS handshake within [`HANDSHAKE_TIMEOUT`], `None` if it failed
+async fn accept_tls(
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+    peer: &str,
+) -> Option<TlsStream<TcpStream>> {
+    let err = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => return Some(stream),
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => "timed out".to_string(),
+    };
+
+    event!(Level::WARN, peer = peer, err = err, "tls handshake failed");
+    None
+}
+
+/// Answer requests of a connected client until it exits or disconnects
+/// `principal` is the client before it sends `AUTH`, if any
+async fn serve<S>(shared: Shared, stream: S, peer: String, mut principal: Option<Principal>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Shared {
+        scheduler: sched,
+        auth,
+        mut shutdown,
+        ..
+    } = shared;
+
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        if *shutdown.borrow_and_update() {
+            event!(
+                Level::TRACE,
+                peer = peer,
+                "closing connection, shutting down"
+            );
+            return;
+        }
+
+        let read = tokio::select! {
+            read = timeout(IDLE_TIMEOUT, Request::read_from(&mut stream)) => match read {
+                Ok(read) => read.map_err(|e| e.to_string()),
+                Err(_) => {
+                    event!(Level::TRACE, peer = peer, "closing idle connection");
+                    return;
                 }
-                Err(e) => {
-                    event!(
-                        Level::ERROR,
-                        err = format!("{}", e),
-                        "failed to accept request"
-                    );
+            },
+            _ = shutdown.changed() => continue,
+        };
+
+        let request = match read {
+            Ok(Some(r)) => r,
+            Ok(None) => {
+                event!(Level::TRACE, peer = peer, "client disconnected");
+                return;
+            }
+            Err(e) => {
+                event!(Level::WARN, err = e, "failed to read request");
+                return;
+            }
+        };
+
+        let command = match ClientCommand::parse(&request.line, request.body.as_deref()) {
+            Ok(c) => c,
+            Err(e) => ClientCommand::Error(e.to_string()),
+        };
+
+        let exit = matches!(command, ClientCommand::Exit);
+
+        let response = match (&command, &auth) {
+            (ClientCommand::Auth(token), Some(auth)) => match auth.authenticate(token) {
+                Some(p) => {
+                    event!(Level::INFO, peer = peer, user = p.name, "authenticated");
+                    let resp = Response::Ok(format!("authenticated as {}", p.name));
+                    principal = Some(p);
+                    resp
+                }
+                None => {
+                    event!(Level::WARN, peer = peer, "authentication failed");
+                    principal = None;
+                    Response::Err("authentication failed".to_string())
+                }
+            },
+            (ClientCommand::Auth(_), None) => {
+                Response::Ok("authentication is disabled".to_string())
+            }
+            _ => {
+                let user = principal.as_ref().map_or("-", |p| p.name.as_str());
+
+                event!(Level::INFO, peer = peer, user = user, request.line);
+
+                match authorize(principal.as_ref(), &command) {
+                    Ok(()) => execute(&sched, command),
+                    Err(e) => {
+                        event!(
+                            Level::WARN,
+                            peer = peer,
+                            user = user,
+                            cmd = request.line,
+                            err = e.to_string(),
+                            "denied"
+                        );
+                        Response::Err(e.to_string())
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = response.write_to(&mut stream).await {
+            event!(Level::WARN, err = e.to_string(), "failed to write response");
+            return;
+        }
+
+        if exit {
+            event!(Level::TRACE, peer = peer, "closing connection to client");
+            return;
+        }
+    }
+}
+
+/// Run a [`ClientCommand`] against the scheduler
+/// Listings are returned as yaml documents
+fn execute(sched: &Scheduler, command: ClientCommand) -> Response {
+    let result: Result<String> = match command {
+        ClientCommand::Add(task) => {
+            let start_time = task.start_time.unwrap_or_else(SystemTime::now);
+
+            sched
+                .add_task(task, start_time)
+                .map(|()| "task successfully added".to_string())
+        }
+        ClientCommand::Validate(task) => Ok(format!("task '{}' is valid", task.task_id)),
+        ClientCommand::Drain(task_id) => sched
+            .drain_task(task_id)
+            .map(|()| "task draining".to_string()),
+        ClientCommand::Kill(task_id) => {
+            sched.kill_task(task_id).map(|()| "task killed".to_string())
+        }
+        ClientCommand::Pause(task_id) => sched
+            .pause_task(&task_id)
+            .map(|()| "task paused".to_string()),
+        ClientCommand::Resume(task_id, policy) => sched
+            .resume_task(&task_id, policy)
+            .map(|()| "task resumed".to_string()),
+        ClientCommand::Backfill(task_id, options) => sched
+            .backfill_task(&task_id, options)
+            .map(|(id, runs)| format!("started {} of {} runs", id, runs)),
+        ClientCommand::Trigger(task_id, options) => sched
+            .trigger_task(&task_id, options)
+            .map(|inst_id| format!("triggered {}", inst_id)),
+        ClientCommand::List => {
+            let mut tasks: Vec<Yaml> = sched
+                .tasks
+                .lock()
+                .unwrap()
+                .values()
+                .map(|t| {
+                    let mut doc = t.to_yaml();
+                    if let Yaml::Hash(h) = &mut doc {
+                        h.insert(
+                            Yaml::String("paused".into()),
+                            Yaml::Boolean(sched.is_paused(&t.task_id)),
+                        );
+                    }
+                    doc
+                })
+                .collect();
+
+            tasks.sort_by(|a, b| a["task_id"].as_str().cmp(&b["task_id"].as_str()));
+
+            emit(&Yaml::Array(tasks))
+        }
+        ClientCommand::Show(task_id) => {
+            let task = sched.tasks.lock().unwrap().get(&task_id).cloned();
+
+            match task {
+                Some(task) => {
+                    let mut doc = task.to_yaml();
+                    let queued = sched
+                        .queued(&task_id)
+                        .iter()
+                        .map(|ti| ti.to_yaml())
+                        .collect();
+
+                    let backfills = sched
+                        .backfills(&task_id)
+                        .iter()
+                        .map(|b| b.to_yaml())
+                        .collect();
+
+                    let held = sched.held(&task_id).iter().map(|ti| ti.to_yaml()).collect();
+
+                    if let Yaml::Hash(h) = &mut doc {
+                        h.insert(
+                            Yaml::String("paused".into()),
+                            Yaml::Boolean(sched.is_paused(&task_id)),
+                        );
+                        if task.max_runs.is_some() {
+                            h.insert(
+                                Yaml::String("runs".into()),
+                                Yaml::Integer(sched.runs(&task_id) as i64),
+                            );
+                        }
+                        if let ScheduleType::Datasets(_) = task.schedule {
+                            let updated = sched.datasets.lock().unwrap().pending(&task_id);
+                            h.insert(
+                                Yaml::String("updated_datasets".into()),
+                                Yaml::Array(updated.into_iter().map(Yaml::String).collect()),
+                            );
+                        }
+                        h.insert(Yaml::String("queued".into()), Yaml::Array(queued));
+                        h.insert(Yaml::String("held".into()), Yaml::Array(held));
+                        h.insert(Yaml::String("backfills".into()), Yaml::Array(backfills));
+                    }
+
+                    emit(&doc)
                 }
+                None => Err(format!("task '{}' does not exist", task_id).into()),
             }
         }
+        ClientCommand::Logs(instance_id) => match sched.history.lock().unwrap().get(&instance_id) {
+            Some(record) => Ok(record.logs.clone()),
+            None => Err(format!("no run with instance id '{}'", instance_id).into()),
+        },
+        ClientCommand::History(task_id, limit) => {
+            let runs = sched
+                .history
+                .lock()
+                .unwrap()
+                .runs(task_id.as_deref())
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|r| r.to_yaml())
+                .collect();
+
+            emit(&Yaml::Array(runs))
+        }
+        ClientCommand::Datasets => {
+            let tasks = sched.tasks.lock().unwrap();
+            let datasets = sched.datasets.lock().unwrap().to_yaml(tasks.values());
+            emit(&datasets)
+        }
+        ClientCommand::Help => Ok(HELP.to_string()),
+        ClientCommand::Noop => Ok("nothing happened".to_string()),
+        ClientCommand::Error(e) => Err(e.into()),
+        ClientCommand::Exit => Ok("closing connection".to_string()),
+        ClientCommand::Auth(_) => Err("already handled by connection".into()),
+    };
+
+    match result {
+        Ok(body) => Response::Ok(body),
+        Err(e) => Response::Err(e.to_string()),
     }
 }
+
+fn emit(doc: &Yaml) -> Result<String> {
+    let mut out = String::new();
+    YamlEmitter::new(&mut out).dump(doc)?;
+    out.push('\n');
+    Ok(out)
+}
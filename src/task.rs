@@ -1,32 +1,171 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::Stdio;
+use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::process::Command;
 use tokio::time::Duration;
 
+use chrono::{DateTime, Datelike, Months, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
 use tracing::{event, Level};
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlLoader};
 
+use crate::calendar::Calendar;
+use crate::cron::{resolve_local, stable_hash, Cron};
+use crate::dataset::{validate_dataset_name, DatasetId};
+use crate::errors::ExecError;
+use crate::history::FailureReason;
+use crate::limits::ResourceLimits;
+use crate::process::ProcessOptions;
+use crate::sandbox::Sandbox;
+use crate::scheduler::SchedulerConfig;
+use crate::secrets::{redact, validate_secret_name};
+use crate::sensor::FileSensor;
+use crate::template::{self, Template, Variables};
+use crate::time::{format_duration, format_time, parse_duration, parse_time};
 use crate::Result;
 
 pub type TaskId = String;
 
+/// Named parameters of a task, passed to its command as environment variables, see
+/// [`PARAM_ENV_PREFIX`]
+pub type Params = BTreeMap<String, String>;
+
+/// Prefix of the environment variables of parameters, which can't override others like
+/// `PATH` or `LD_PRELOAD`
+pub const PARAM_ENV_PREFIX: &str = "CHAINZ_PARAM_";
+
+/// What created a [`TaskInstance`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// Its schedule, or the completion of an up-stream task
+    Scheduled,
+    /// An operator, see [`crate::scheduler::Scheduler::trigger_task`]
+    Manual,
+    /// A backfill, see [`crate::scheduler::Scheduler::backfill_task`]
+    Backfill,
+    /// A request to a hook, see [`crate::webhook`]
+    Webhook,
+}
+
+/// What to do with a scheduled run that starts later than the misfire tolerance of its task,
+/// e.g. after downtime or while waiting for a free slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MisfirePolicy {
+    /// Run once for the latest missed point of the schedule
+    #[default]
+    FireOnce,
+    /// Run for every missed point, oldest first
+    FireAll,
+    /// Do not run, continue with the next point after now
+    Skip,
+    /// Record the run as failed, continue with the next point after now
+    Fail,
+}
+
+/// What happens to a run of a task with a calendar that falls on a day that is not a
+/// business day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CalendarPolicy {
+    /// Do not run, continue with the next point of the schedule
+    #[default]
+    Skip,
+    /// Run at the same time on the following business day
+    Following,
+    /// Run at the same time on the preceding business day.
+    /// Shifting suits schedules with at most a run per business day, like monthly ones.
+    Preceding,
+}
+
+/// What a run of a task does
+#[derive(Debug, Clone, Default)]
+pub enum TaskType {
+    /// Run `cmd` in a shell
+    #[default]
+    Shell,
+    /// Wait for files, see [`crate::sensor`]
+    Sensor(FileSensor),
+}
+
+/// Successful run of a [`TaskInstance`]
+#[derive(Debug, Clone, Default)]
+pub struct Outcome {
+    /// Combined output of the run
+    pub logs: String,
+    /// Values passed on to the down-stream runs it triggers, see [`TaskInstance::context`]
+    pub outputs: Params,
+}
+
 /// Describes how a task is scheduled:
 /// - `Interval`: task is executed every `Duration`
 /// - `DownStream` [`TaskId`]: task has a down-stream dependency, and that task is executed on completion
 /// - `Once`: task is scheduled and executed once (with retries)
+/// - `Cron`: task is executed at the local times matching a [`Cron`] expression,
+///   in the time zone of the task
+/// - `BusinessDay`: task is executed on the nth business day of every month at a local time,
+///   counting from the end of the month for negative n
+/// - `Datasets`: task is executed once all the datasets were updated since its last run,
+///   see [`crate::dataset`]
 #[derive(Debug, Clone)]
 pub enum ScheduleType {
     Interval(Duration),
     DownStream(TaskId),
     Once,
+    Cron(Cron),
+    BusinessDay(i32, NaiveTime),
+    Datasets(Vec<DatasetId>),
 }
 
 /// Task configuration
 /// Describes how to schedule and execute a task
 #[derive(Debug, Clone)]
 pub struct Task {
+    pub task_type: TaskType,
     pub schedule: ScheduleType,
     pub cmd: String,
-    pub retries: u16,
+    /// Defaults to the scheduler's default retries when added
+    pub retries: Option<u16>,
     pub task_id: TaskId,
+    pub start_time: Option<SystemTime>,
+    /// Runs exceeding the timeout are killed and failed
+    pub timeout: Option<Duration>,
+    /// Defaults, overridden by the parameters of an instance
+    pub params: Params,
+    /// Handling of runs starting later than `misfire_tolerance`
+    pub misfire: MisfirePolicy,
+    pub misfire_tolerance: Duration,
+    /// Interval schedules only: the points of the schedule are `origin + n * interval`,
+    /// defaults to `start_time`
+    pub origin: Option<SystemTime>,
+    /// IANA time zone cron and business day schedules and calendars are evaluated in
+    pub timezone: Tz,
+    /// Name of the calendar of business days of the task
+    pub calendar: Option<String>,
+    pub calendar_policy: CalendarPolicy,
+    /// Business days of `calendar`, resolved when the task is added.
+    /// Monday to friday for tasks without a calendar.
+    pub business_days: Arc<Calendar>,
+    /// No runs are scheduled after it, the task is retired once its last one finished
+    pub end_time: Option<SystemTime>,
+    /// Successful scheduled runs after which the task is retired
+    pub max_runs: Option<u32>,
+    /// Periodic schedules only: random delay of each run, up to this long
+    pub jitter: Option<Duration>,
+    /// Periodic schedules only: delay of every run derived from a hash of the task id, below
+    /// this long, so tasks sharing a schedule run at different but stable times
+    pub spread: Option<Duration>,
+    /// Datasets updated by the successful runs of the task
+    pub produces: Vec<DatasetId>,
+    /// Environment, working directory and user of the command
+    pub process: ProcessOptions,
+    /// Secrets passed to the command, by variable, see [`crate::secrets`]
+    pub secrets: Params,
+    /// CPU, memory and process limits of the command
+    pub limits: ResourceLimits,
+    /// Namespaces isolating the command from the host, see [`crate::sandbox`]
+    pub sandbox: Option<Sandbox>,
 }
 
 /// Actual scheduled instance of a task
@@ -39,25 +178,601 @@ pub struct TaskInstance {
     pub retry_num: u16,
     #[allow(dead_code)]
     pub(crate) kill: bool,
+    pub trigger: Trigger,
+    /// Overrides of the task parameters
+    pub params: Params,
+    /// Manual runs only: trigger the down-stream task on success, as a manual run
+    pub downstream: bool,
+    /// Point of the schedule the instance runs for, see [`TaskInstance::data_interval`]
+    pub logical_date: SystemTime,
+    /// Backfill the instance is part of
+    pub backfill: Option<String>,
+    /// Outputs of the up-stream runs of its chain, passed to the command like parameters
+    pub context: Params,
 }
 
 impl Task {
-    pub fn new(task_id: &str, schedule: ScheduleType, cmd: &str, retries: u16) -> Self {
-        // Validate configs
+    /// Task running `cmd` on `schedule`, with the defaults of the other keys. Fails if
+    /// the id is not valid or `cmd` is not a valid template.
+    pub fn new(task_id: &str, schedule: ScheduleType, cmd: &str, retries: u16) -> Result<Self> {
+        // Ids end up in paths, of output files and cgroups
+        validate_task_id(task_id)?;
+        Template::parse(cmd).map_err(|e| format!("invalid cmd: {}", e))?;
 
-        // Check task_id is unique
+        Ok(Task {
+            task_type: TaskType::default(),
+            task_id: task_id.to_string(),
+            schedule,
+            cmd: cmd.to_string(),
+            retries: Some(retries),
+            start_time: None,
+            timeout: None,
+            params: Params::new(),
+            misfire: MisfirePolicy::default(),
+            misfire_tolerance: Task::MISFIRE_TOLERANCE,
+            origin: None,
+            timezone: Tz::UTC,
+            calendar: None,
+            calendar_policy: CalendarPolicy::default(),
+            business_days: Arc::new(Calendar::default()),
+            end_time: None,
+            max_runs: None,
+            jitter: None,
+            spread: None,
+            produces: Vec::new(),
+            process: ProcessOptions::default(),
+            secrets: Params::new(),
+            limits: ResourceLimits::default(),
+            sandbox: None,
+        })
+    }
 
-        // Check task_type Binary exists
+    /// Default delay after which a run is handled according to the misfire policy
+    pub const MISFIRE_TOLERANCE: Duration = Duration::from_secs(60);
 
-        // Return error immedately before creating anything if any check fails
+    const KEYS: [&'static str;
+        22 + FileSensor::KEYS.len()
+            + ProcessOptions::KEYS.len()
+            + ResourceLimits::KEYS.len()
+            + Sandbox::KEYS.len()] = [
+        "task_id",
+        "type",
+        "schedule",
+        "cmd",
+        "code",
+        "retries",
+        "start_time",
+        "timeout",
+        "params",
+        "catchup",
+        "misfire",
+        "misfire_tolerance",
+        "origin",
+        "timezone",
+        "end_time",
+        "max_runs",
+        "calendar",
+        "calendar_policy",
+        "jitter",
+        "spread",
+        "produces",
+        "secrets",
+        "path",
+        "stable_for",
+        "poke_interval",
+        "downstream",
+        "env",
+        "inherit_env",
+        "cwd",
+        "umask",
+        "user",
+        "group",
+        "memory_limit",
+        "cpu_limit",
+        "pids_limit",
+        "sandbox",
+    ];
 
-        Task {
-            task_id: task_id.to_string(),
-            schedule: schedule,
-            cmd: cmd.to_string(),
-            retries: retries,
+    /// Parse a task definition from a YAML document, see `tasks/` for examples
+    pub fn from_yaml_str(data: &str) -> Result<Self> {
+        let docs = YamlLoader::load_from_str(data)?;
+
+        match docs.as_slice() {
+            [doc] => Task::from_yaml(doc),
+            [] => Err("empty task definition".into()),
+            _ => Err("expected a single task definition".into()),
         }
     }
+
+    /// Parse a task definition from a YAML mapping
+    pub fn from_yaml(doc: &Yaml) -> Result<Self> {
+        let hash = match doc.as_hash() {
+            Some(h) => h,
+            None => return Err("task definition must be a mapping".into()),
+        };
+
+        for key in hash.keys() {
+            match key.as_str() {
+                Some(k) if Task::KEYS.contains(&k) => {}
+                _ => return Err(format!("unknown task key: {:?}", key).into()),
+            }
+        }
+
+        let task_id = doc["task_id"].as_str().ok_or("no task_id provided")?;
+
+        let task_type = match doc["type"].as_str() {
+            None | Some("shell") => {
+                if let Some(k) = FileSensor::KEYS.iter().find(|k| !doc[**k].is_badvalue()) {
+                    return Err(format!("{} requires a sensor task", k).into());
+                }
+                TaskType::Shell
+            }
+            Some("sensor") => {
+                let mut shell_keys = ProcessOptions::KEYS
+                    .iter()
+                    .chain(&ResourceLimits::KEYS)
+                    .chain(&Sandbox::KEYS);
+                if let Some(k) = shell_keys.find(|k| !doc[**k].is_badvalue()) {
+                    return Err(format!("{} requires a shell task", k).into());
+                }
+                TaskType::Sensor(FileSensor::from_yaml(doc)?)
+            }
+            Some(t) => return Err(format!("unsupported task type: {}", t).into()),
+        };
+
+        let schedule = match doc["schedule"].as_str() {
+            Some(s) => match ScheduleType::from_str(s)? {
+                ScheduleType::Cron(cron) => ScheduleType::Cron(cron.hashed(task_id)?),
+                schedule => schedule,
+            },
+            None => return Err("no schedule provided".into()),
+        };
+
+        let cmd = match (doc["cmd"].as_str().or(doc["code"].as_str()), &task_type) {
+            (Some(cmd), TaskType::Shell) => cmd,
+            (None, TaskType::Shell) => return Err("no cmd provided".into()),
+            (Some(_), TaskType::Sensor(_)) => return Err("sensor tasks have no cmd".into()),
+            (None, TaskType::Sensor(_)) => "",
+        };
+
+        let mut task = Task::new(task_id, schedule, cmd, 0)?;
+        task.task_type = task_type;
+        task.process = ProcessOptions::from_yaml(doc)?;
+        task.limits = ResourceLimits::from_yaml(doc)?;
+        task.sandbox = Sandbox::from_yaml(doc)?;
+        if task.sandbox.is_some() && matches!(task.process.user.as_deref(), Some("root" | "0")) {
+            return Err("sandbox requires a user other than root".into());
+        }
+
+        task.retries = match &doc["retries"] {
+            Yaml::BadValue => None,
+            Yaml::Integer(n) => Some(u16::try_from(*n)?),
+            r => return Err(format!("invalid retries: {:?}", r).into()),
+        };
+
+        task.timeout = match doc["timeout"].as_str() {
+            Some(t) => Some(parse_duration(t)?),
+            None => None,
+        };
+
+        task.start_time = match doc["start_time"].as_str() {
+            Some(s) => Some(parse_time(s)?),
+            None => None,
+        };
+
+        task.misfire = match (doc["misfire"].as_str(), &doc["catchup"]) {
+            (Some(m), Yaml::BadValue) => MisfirePolicy::from_str(m)?,
+            (None, Yaml::BadValue | Yaml::Boolean(false)) => MisfirePolicy::default(),
+            // `catchup: true` predates misfire policies
+            (None, Yaml::Boolean(true)) => MisfirePolicy::FireAll,
+            (Some(_), _) => return Err("misfire and catchup are mutually exclusive".into()),
+            (None, c) => return Err(format!("invalid catchup: {:?}", c).into()),
+        };
+
+        if let Some(t) = doc["misfire_tolerance"].as_str() {
+            task.misfire_tolerance = parse_duration(t)?;
+        }
+
+        task.origin = match (doc["origin"].as_str(), &task.schedule) {
+            (Some(s), ScheduleType::Interval(_)) => Some(parse_time(s)?),
+            (Some(_), _) => return Err("origin requires an interval schedule".into()),
+            (None, _) => None,
+        };
+
+        task.calendar = doc["calendar"].as_str().map(str::to_string);
+
+        if let Some(p) = doc["calendar_policy"].as_str() {
+            task.calendar_policy = CalendarPolicy::from_str(p)?;
+        }
+
+        task.timezone = match doc["timezone"].as_str() {
+            Some(tz) if task.calendar.is_some() || task.schedule.is_local() => tz
+                .parse()
+                .map_err(|_| format!("unknown time zone: {}", tz))?,
+            Some(_) => {
+                return Err(
+                    "timezone requires a cron or business day schedule, or a calendar".into(),
+                )
+            }
+            None => Tz::UTC,
+        };
+
+        task.end_time = match doc["end_time"].as_str() {
+            Some(s) => Some(parse_time(s)?),
+            None => None,
+        };
+
+        task.max_runs = match &doc["max_runs"] {
+            Yaml::BadValue => None,
+            Yaml::Integer(n) if *n > 0 => Some(u32::try_from(*n)?),
+            m => return Err(format!("invalid max_runs: {:?}", m).into()),
+        };
+
+        for (key, delay) in [("jitter", &mut task.jitter), ("spread", &mut task.spread)] {
+            *delay = match (doc[key].as_str(), task.schedule.is_periodic()) {
+                (Some(d), true) => Some(parse_duration(d)?),
+                (Some(_), false) => {
+                    return Err(format!("{} requires a periodic schedule", key).into())
+                }
+                (None, _) => None,
+            };
+        }
+
+        task.produces = match &doc["produces"] {
+            Yaml::BadValue => Vec::new(),
+            Yaml::String(d) => vec![d.clone()],
+            Yaml::Array(datasets) => datasets
+                .iter()
+                .map(|d| match d.as_str() {
+                    Some(d) => Ok(d.to_string()),
+                    None => Err(format!("invalid dataset: {:?}", d)),
+                })
+                .collect::<std::result::Result<_, _>>()?,
+            p => return Err(format!("invalid produces: {:?}", p).into()),
+        };
+        for dataset in &task.produces {
+            validate_dataset_name(dataset)?;
+        }
+
+        task.params = match &doc["params"] {
+            Yaml::BadValue => Params::new(),
+            p => params_from_yaml(p)?,
+        };
+
+        task.secrets = match &doc["secrets"] {
+            Yaml::BadValue => Params::new(),
+            // Variables named after their secret
+            Yaml::Array(names) => names
+                .iter()
+                .map(|n| match n.as_str() {
+                    Some(n) => Ok((n.to_string(), n.to_string())),
+                    None => Err(format!("invalid secret: {:?}", n)),
+                })
+                .collect::<std::result::Result<_, _>>()?,
+            s => params_from_yaml(s).map_err(|e| format!("invalid secrets: {}", e))?,
+        };
+        for (var, name) in &task.secrets {
+            validate_param_name(var)?;
+            validate_secret_name(name)?;
+        }
+
+        for (name, value) in &task.params {
+            Template::parse(value).map_err(|e| format!("invalid parameter {}: {}", name, e))?;
+        }
+
+        Ok(task)
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut h = Hash::new();
+
+        h.insert(
+            Yaml::String("task_id".into()),
+            Yaml::String(self.task_id.clone()),
+        );
+        h.insert(
+            Yaml::String("schedule".into()),
+            Yaml::String(self.schedule.to_string()),
+        );
+        match &self.task_type {
+            TaskType::Shell => {
+                h.insert(Yaml::String("cmd".into()), Yaml::String(self.cmd.clone()));
+                self.process.insert_yaml(&mut h);
+                self.limits.insert_yaml(&mut h);
+                if let Some(sandbox) = &self.sandbox {
+                    sandbox.insert_yaml(&mut h);
+                }
+            }
+            TaskType::Sensor(sensor) => {
+                h.insert(Yaml::String("type".into()), Yaml::String("sensor".into()));
+                sensor.insert_yaml(&mut h);
+            }
+        }
+        if let Some(retries) = self.retries {
+            h.insert(
+                Yaml::String("retries".into()),
+                Yaml::Integer(retries as i64),
+            );
+        }
+        if let Some(timeout) = self.timeout {
+            h.insert(
+                Yaml::String("timeout".into()),
+                Yaml::String(format_duration(timeout)),
+            );
+        }
+        if let Some(start_time) = self.start_time {
+            h.insert(
+                Yaml::String("start_time".into()),
+                Yaml::String(format_time(start_time)),
+            );
+        }
+        if !self.params.is_empty() {
+            h.insert(Yaml::String("params".into()), params_to_yaml(&self.params));
+        }
+        if self.misfire != MisfirePolicy::default() {
+            h.insert(
+                Yaml::String("misfire".into()),
+                Yaml::String(self.misfire.to_string()),
+            );
+        }
+        if self.misfire_tolerance != Task::MISFIRE_TOLERANCE {
+            h.insert(
+                Yaml::String("misfire_tolerance".into()),
+                Yaml::String(format_duration(self.misfire_tolerance)),
+            );
+        }
+        if let Some(origin) = self.origin {
+            h.insert(
+                Yaml::String("origin".into()),
+                Yaml::String(format_time(origin)),
+            );
+        }
+        if self.timezone != Tz::UTC {
+            h.insert(
+                Yaml::String("timezone".into()),
+                Yaml::String(self.timezone.name().into()),
+            );
+        }
+        if let Some(end_time) = self.end_time {
+            h.insert(
+                Yaml::String("end_time".into()),
+                Yaml::String(format_time(end_time)),
+            );
+        }
+        if let Some(max_runs) = self.max_runs {
+            h.insert(
+                Yaml::String("max_runs".into()),
+                Yaml::Integer(max_runs as i64),
+            );
+        }
+        if let Some(calendar) = &self.calendar {
+            h.insert(
+                Yaml::String("calendar".into()),
+                Yaml::String(calendar.clone()),
+            );
+        }
+        if self.calendar_policy != CalendarPolicy::default() {
+            h.insert(
+                Yaml::String("calendar_policy".into()),
+                Yaml::String(self.calendar_policy.to_string()),
+            );
+        }
+        if let Some(jitter) = self.jitter {
+            h.insert(
+                Yaml::String("jitter".into()),
+                Yaml::String(format_duration(jitter)),
+            );
+        }
+        if let Some(spread) = self.spread {
+            h.insert(
+                Yaml::String("spread".into()),
+                Yaml::String(format_duration(spread)),
+            );
+        }
+        if !self.produces.is_empty() {
+            h.insert(
+                Yaml::String("produces".into()),
+                Yaml::Array(self.produces.iter().cloned().map(Yaml::String).collect()),
+            );
+        }
+        if !self.secrets.is_empty() {
+            h.insert(
+                Yaml::String("secrets".into()),
+                params_to_yaml(&self.secrets),
+            );
+        }
+
+        Yaml::Hash(h)
+    }
+
+    /// First point of the schedule at or after `time`, `None` if it is after `end_time`.
+    /// Interval schedules without an `origin` start at `time`, others may have no point.
+    pub fn align(&self, time: SystemTime) -> Option<SystemTime> {
+        let before = time - Duration::from_nanos(1);
+
+        let point = match (&self.schedule, self.origin) {
+            (ScheduleType::Interval(d), Some(origin)) if !d.is_zero() => {
+                match time.duration_since(origin) {
+                    Ok(after) => origin + *d * periods(after, *d, true),
+                    Err(e) => origin - *d * periods(e.duration(), *d, false),
+                }
+            }
+            (ScheduleType::Cron(_) | ScheduleType::BusinessDay(..), _) => {
+                return self.next_point(before, before)
+            }
+            _ => time,
+        };
+
+        match self.skips(point) {
+            true => self.next_point(point, point),
+            false => Some(point).filter(|p| self.before_end(*p)),
+        }
+    }
+
+    /// First point of a periodic schedule after `after`, `None` once the schedule ended.
+    /// Interval schedules keep the phase of `point`, a previous point of the schedule.
+    /// Points on days skipped by the calendar of the task are left out.
+    pub fn next_point(&self, point: SystemTime, after: SystemTime) -> Option<SystemTime> {
+        let mut after = after;
+
+        for _ in 0..=MAX_SKIPPED_DAYS {
+            let next = match &self.schedule {
+                ScheduleType::Interval(d) if d.is_zero() => after,
+                ScheduleType::Interval(d) => match after.duration_since(point) {
+                    Ok(behind) => point + *d * (periods(behind, *d, false) + 1),
+                    Err(_) => point,
+                },
+                ScheduleType::Cron(cron) => cron.next_after(after, self.timezone)?,
+                ScheduleType::BusinessDay(n, time) => self.business_day(*n, *time, after, true)?,
+                _ => return None,
+            };
+
+            if !self.before_end(next) {
+                return None;
+            }
+            if !self.skips(next) {
+                return Some(next);
+            }
+
+            // Last instant of the skipped day
+            let date = self.local_date(next).succ_opt()?;
+            after = resolve_local(date.into(), self.timezone)? - Duration::from_nanos(1);
+        }
+
+        None
+    }
+
+    /// Latest point of a periodic schedule at or before `now` and `end_time`, not before `point`
+    pub fn latest_point(&self, point: SystemTime, now: SystemTime) -> Option<SystemTime> {
+        let mut now = self.end_time.map_or(now, |end| end.min(now));
+
+        for _ in 0..=MAX_SKIPPED_DAYS {
+            let latest = match &self.schedule {
+                ScheduleType::Interval(d) if d.is_zero() => point,
+                ScheduleType::Interval(d) => {
+                    let behind = now.duration_since(point).ok()?;
+                    point + *d * periods(behind, *d, false)
+                }
+                ScheduleType::Cron(cron) => {
+                    cron.prev_before(now + Duration::from_nanos(1), self.timezone)?
+                }
+                ScheduleType::BusinessDay(n, time) => {
+                    self.business_day(*n, *time, now + Duration::from_nanos(1), false)?
+                }
+                _ => return None,
+            };
+
+            if latest < point {
+                return None;
+            }
+            if !self.skips(latest) {
+                return Some(latest);
+            }
+
+            // Instant before the skipped day
+            let date = self.local_date(latest);
+            now = resolve_local(date.into(), self.timezone)? - Duration::from_nanos(1);
+        }
+
+        None
+    }
+
+    /// When the run for `point` executes: on the closest business day if the calendar of the
+    /// task shifts runs, otherwise at the point itself, delayed by `spread` and `jitter`
+    pub fn exec_time(&self, point: SystemTime) -> SystemTime {
+        let mut delay = Duration::ZERO;
+
+        if let Some(spread) = self.spread.filter(|s| s.as_secs() > 0) {
+            delay += Duration::from_secs(stable_hash(&self.task_id) % spread.as_secs());
+        }
+        if let Some(jitter) = self.jitter {
+            delay += Duration::from_secs(fastrand::u64(0..=jitter.as_secs()));
+        }
+
+        self.business_time(point) + delay
+    }
+
+    fn business_time(&self, point: SystemTime) -> SystemTime {
+        let forward = match (&self.calendar, self.calendar_policy) {
+            (Some(_), CalendarPolicy::Following) => true,
+            (Some(_), CalendarPolicy::Preceding) => false,
+            _ => return point,
+        };
+
+        let local = DateTime::<Utc>::from(point)
+            .with_timezone(&self.timezone)
+            .naive_local();
+
+        if self.business_days.is_business_day(local.date()) {
+            return point;
+        }
+
+        self.business_days
+            .shift(local.date(), forward)
+            .and_then(|d| resolve_local(d.and_time(local.time()), self.timezone))
+            .unwrap_or(point)
+    }
+
+    /// Whether the calendar of the task skips a run at `time`
+    pub fn skips(&self, time: SystemTime) -> bool {
+        self.calendar.is_some()
+            && self.calendar_policy == CalendarPolicy::Skip
+            && !self.business_days.is_business_day(self.local_date(time))
+    }
+
+    fn local_date(&self, time: SystemTime) -> NaiveDate {
+        DateTime::<Utc>::from(time)
+            .with_timezone(&self.timezone)
+            .date_naive()
+    }
+
+    /// First run of a business day schedule after `time`, or last one before it
+    fn business_day(
+        &self,
+        n: i32,
+        at: NaiveTime,
+        time: SystemTime,
+        forward: bool,
+    ) -> Option<SystemTime> {
+        let date = self.local_date(time);
+        let mut month = NaiveDate::from_ymd_opt(date.year(), date.month(), 1)?;
+
+        for _ in 0..MAX_SKIPPED_DAYS {
+            let day = self
+                .business_days
+                .nth_business_day(month.year(), month.month(), n);
+
+            if let Some(p) = day.and_then(|d| resolve_local(d.and_time(at), self.timezone)) {
+                if (forward && p > time) || (!forward && p < time) {
+                    return Some(p);
+                }
+            }
+
+            month = match forward {
+                true => month.checked_add_months(Months::new(1))?,
+                false => month.checked_sub_months(Months::new(1))?,
+            };
+        }
+
+        None
+    }
+
+    /// Whether a run at `time` is within the schedule's `end_time`
+    pub fn before_end(&self, time: SystemTime) -> bool {
+        self.end_time.is_none_or(|end| time <= end)
+    }
+}
+
+/// Bound of consecutive days or months searched for a point of a schedule
+const MAX_SKIPPED_DAYS: usize = 366;
+
+/// Whole periods `d` in `elapsed`, saturating
+fn periods(elapsed: Duration, d: Duration, round_up: bool) -> u32 {
+    let n = match round_up {
+        true => elapsed.as_nanos().div_ceil(d.as_nanos()),
+        false => elapsed.as_nanos() / d.as_nanos(),
+    };
+    u32::try_from(n).unwrap_or(u32::MAX)
 }
 
 impl TaskInstance {
@@ -72,127 +787,769 @@ impl TaskInstance {
                     .unwrap()
                     .as_nanos()
             ),
-            task: task,
-            exec_at: exec_at,
+            task,
+            exec_at,
             logs: String::new(),
-            retry_num: retry_num,
+            retry_num,
             kill: false,
+            trigger: Trigger::Scheduled,
+            params: Params::new(),
+            downstream: false,
+            logical_date: exec_at,
+            backfill: None,
+            context: Params::new(),
+        }
+    }
+
+    /// Parse an instance of `task` as written by [`TaskInstance::to_yaml`]
+    pub fn from_yaml(doc: &Yaml, task: &Task) -> Result<Self> {
+        let exec_at = match doc["exec_at"].as_str() {
+            Some(t) => parse_time(t)?,
+            None => return Err(format!("instance without exec_at: {:?}", doc).into()),
+        };
+
+        let retry_num = match &doc["retry_num"] {
+            Yaml::BadValue => 0,
+            Yaml::Integer(n) => u16::try_from(*n)?,
+            r => return Err(format!("invalid retry_num: {:?}", r).into()),
+        };
+
+        let mut ti = TaskInstance::new(task.clone(), exec_at, retry_num);
+
+        if let Some(id) = doc["instance_id"].as_str() {
+            if !is_valid_id(id) {
+                return Err(format!("invalid instance_id '{}'", id).into());
+            }
+            ti.instance_id = id.to_string();
+        }
+        if let Some(t) = doc["trigger"].as_str() {
+            ti.trigger = Trigger::from_str(t)?;
+        }
+        if !doc["params"].is_badvalue() {
+            ti.params = params_from_yaml(&doc["params"])?;
+        }
+        ti.downstream = doc["downstream"].as_bool().unwrap_or(false);
+        if let Some(t) = doc["logical_date"].as_str() {
+            ti.logical_date = parse_time(t)?;
+        }
+        ti.backfill = doc["backfill"].as_str().map(str::to_string);
+        if !doc["context"].is_badvalue() {
+            ti.context = params_from_yaml(&doc["context"])?;
+        }
+
+        Ok(ti)
+    }
+
+    /// Next attempt of this instance, run at `exec_at`
+    pub fn retry(&self, exec_at: SystemTime) -> Self {
+        let mut ti = TaskInstance::new(self.task.clone(), exec_at, self.retry_num + 1);
+        ti.trigger = self.trigger;
+        ti.params = self.params.clone();
+        ti.downstream = self.downstream;
+        ti.logical_date = self.logical_date;
+        ti.backfill = self.backfill.clone();
+        ti.context = self.context.clone();
+        ti
+    }
+
+    /// Instance of the down-stream `task` of this one, with the outputs of this run added to
+    /// the context of the chain
+    pub fn downstream(&self, task: Task, outputs: &Params) -> Self {
+        let mut ti = TaskInstance::new(task, SystemTime::now(), 0);
+        ti.trigger = self.trigger;
+        ti.downstream = self.downstream;
+        ti.logical_date = self.logical_date;
+        ti.context = self.context.clone();
+        ti.context.extend(outputs.clone());
+        ti
+    }
+
+    /// Data covered by the instance: the period of its schedule ending at its logical date.
+    /// `None` for schedules without a period.
+    pub fn data_interval(&self) -> Option<(SystemTime, SystemTime)> {
+        match &self.task.schedule {
+            ScheduleType::Interval(d) => Some((self.logical_date - *d, self.logical_date)),
+            ScheduleType::Cron(_) | ScheduleType::BusinessDay(..) => self
+                .task
+                .latest_point(
+                    SystemTime::UNIX_EPOCH,
+                    self.logical_date - Duration::from_nanos(1),
+                )
+                .map(|start| (start, self.logical_date)),
+            _ => None,
+        }
+    }
+
+    /// Task parameters with the context and then the overrides of this instance applied
+    pub fn params(&self) -> Params {
+        let mut params = self.task.params.clone();
+        params.extend(self.context.clone());
+        params.extend(self.params.clone());
+        params
+    }
+
+    /// Copy of the instance with the templates of the command and parameter defaults of its
+    /// task rendered, see [`crate::template`]. Parameter overrides are used as is.
+    /// `secrets` are the values of the secrets of the task, by variable.
+    pub fn render(&self, secrets: &Params) -> Result<TaskInstance> {
+        let mut vars = Variables::new(self.task.timezone);
+
+        vars.insert("task_id", self.task.task_id.clone());
+        vars.insert("instance_id", self.instance_id.clone());
+        vars.insert("retry_num", self.retry_num.to_string());
+        vars.insert("logical_date", format_time(self.logical_date));
+        vars.insert(
+            "ds",
+            DateTime::<Utc>::from(self.logical_date)
+                .with_timezone(&self.task.timezone)
+                .format("%Y-%m-%d")
+                .to_string(),
+        );
+        if let Some((start, end)) = self.data_interval() {
+            vars.insert("data_interval_start", format_time(start));
+            vars.insert("data_interval_end", format_time(end));
         }
+        vars.insert_all("outputs", &self.context);
+        vars.insert_all("secrets", secrets);
+
+        let mut ti = self.clone();
+
+        for (name, value) in ti.task.params.iter_mut() {
+            *value = template::render(value, &vars)
+                .map_err(|e| format!("failed to render parameter {}: {}", name, e))?;
+        }
+
+        vars.insert_all("params", &ti.params());
+        ti.task.cmd = template::render_shell(&ti.task.cmd, &vars)
+            .map_err(|e| format!("failed to render cmd: {}", e))?;
+
+        Ok(ti)
     }
 
-    /// Execute the task as described in task ([`Task`]).
-    pub async fn exec(&self) -> Result<()> {
+    /// Execute the task as described in task ([`Task`]), with the values of its `secrets`.
+    /// Returns the combined output of the task, on failure as the error.
+    pub async fn exec(&self, config: &SchedulerConfig, secrets: &Params) -> Result<Outcome> {
+        if let TaskType::Sensor(sensor) = &self.task.task_type {
+            return self.sense(sensor).await;
+        }
+
         event!(
             Level::TRACE,
             id = self.instance_id,
-            cmd = self.task.cmd,
+            cmd = redact(&self.task.cmd, secrets),
             "exec"
         );
 
-        let cmd = if cfg!(target_os = "windows") {
-            Command::new("cmd")
-                .arg("/C")
-                .arg(&self.task.cmd)
-                .output()
-                .await
-                .unwrap()
+        let mut cmd = if cfg!(target_os = "windows") {
+            let mut cmd = Command::new("cmd");
+            cmd.arg("/C").arg(&self.task.cmd);
+            cmd
         } else {
-            Command::new("sh")
-                .arg("-c")
-                .arg(&self.task.cmd)
-                .arg("2>&1")
-                .output()
-                .await
-                .unwrap()
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(&self.task.cmd);
+            cmd
         };
 
-        match &cmd.status.success() {
-            true => {
-                event!(Level::TRACE, id = self.instance_id, "success");
-                return Ok(());
+        // Before the process options, joining the cgroup needs the rights of the server
+        let cgroup =
+            self.task
+                .limits
+                .apply(&mut cmd, config.cgroup_root.as_deref(), &self.instance_id)?;
+        let owner = self.task.process.apply(&mut cmd).await?;
+        // After the process options, `inherit_env` may name variables of the providers
+        config.secrets.scrub(&mut cmd);
+        cmd.envs(
+            self.params()
+                .iter()
+                .map(|(name, value)| (format!("{}{}", PARAM_ENV_PREFIX, name), value)),
+        );
+        cmd.envs(secrets);
+
+        // Removed when dropped, whatever happens to the run
+        let output_file = OutputFile::create(&self.instance_id, owner)?;
+        cmd.env("CHAINZ_OUTPUT", &output_file.0);
+        // After the process options, the sandbox keeps the user of the task
+        if let Some(sandbox) = &self.task.sandbox {
+            #[cfg(unix)]
+            // SAFETY: geteuid has no memory safety requirements
+            if self.task.process.user.is_none() && unsafe { libc::geteuid() } == 0 {
+                return Err("sandbox requires a user when the server runs as root".into());
             }
-            false => {
-                let err: String =
-                    String::from_utf8(cmd.stdout).expect("failed to unpack vec utf-8");
+            sandbox.apply(&mut cmd, &output_file.0)?;
+        }
+
+        cmd.env("CHAINZ_LOGICAL_DATE", format_time(self.logical_date));
+
+        if let Some((start, end)) = self.data_interval() {
+            cmd.env("CHAINZ_DATA_INTERVAL_START", format_time(start));
+            cmd.env("CHAINZ_DATA_INTERVAL_END", format_time(end));
+        }
+
+        if !config.plugin_dirs.is_empty() {
+            let mut path = config.plugin_dirs.clone();
+            let inherited = match self.task.process.env.get("PATH") {
+                Some(p) => Some(p.into()),
+                None => std::env::var_os("PATH"),
+            };
+            if let Some(p) = inherited {
+                path.extend(std::env::split_paths(&p));
+            }
+            cmd.env("PATH", std::env::join_paths(path)?);
+        }
+
+        // Killed with all its children if the future is dropped, by a timeout or shutdown
+        cmd.kill_on_drop(true)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        #[cfg(unix)]
+        cmd.process_group(0);
+
+        let child = match &self.task.sandbox {
+            Some(_) => cmd
+                .spawn()
+                .map_err(|e| format!("failed to start sandbox: {}", e))?,
+            None => cmd.spawn()?,
+        };
+        let mut group = ProcessGroup(child.id());
+
+        let output = match self.task.timeout {
+            Some(t) => match tokio::time::timeout(t, child.wait_with_output()).await {
+                Ok(output) => output?,
+                Err(_) => {
+                    let message = format!("timed out after {}", format_duration(t));
+                    event!(Level::WARN, id = self.instance_id, err = message, "failed");
+                    return Err(ExecError {
+                        message,
+                        reason: FailureReason::Timeout,
+                    }
+                    .into());
+                }
+            },
+            None => child.wait_with_output().await?,
+        };
+
+        group.0 = None;
+
+        let mut logs = String::from_utf8_lossy(&output.stdout).into_owned();
+        logs.push_str(&String::from_utf8_lossy(&output.stderr));
+
+        if output.status.success() {
+            let outputs = outputs(&output.stdout, &output_file.0).map_err(|e| ExecError {
+                message: format!("{}invalid outputs: {}", logs, e),
+                reason: FailureReason::Error,
+            })?;
+
+            event!(Level::TRACE, id = self.instance_id, "success");
+            Ok(Outcome { logs, outputs })
+        } else {
+            event!(
+                Level::WARN,
+                id = self.instance_id,
+                err = redact(&logs, secrets),
+                "failed"
+            );
+            let reason = match cgroup.as_ref().is_some_and(|c| c.oom_killed()) {
+                true => {
+                    logs.push_str("killed: out of memory\n");
+                    FailureReason::OutOfMemory
+                }
+                false => FailureReason::Error,
+            };
 
-                event!(Level::WARN, id = self.instance_id, err = err, "failed");
-                return Err(err.into());
+            Err(ExecError {
+                message: logs,
+                reason,
             }
+            .into())
+        }
+    }
+
+    /// Wait for the files of a sensor, passing them on to the down-stream runs
+    async fn sense(&self, sensor: &FileSensor) -> Result<Outcome> {
+        event!(
+            Level::TRACE,
+            id = self.instance_id,
+            path = sensor.path,
+            "sense"
+        );
+
+        let files = match self.task.timeout {
+            Some(t) => match tokio::time::timeout(t, sensor.wait()).await {
+                Ok(files) => files,
+                Err(_) => {
+                    let message = format!(
+                        "no files matching {} within {}",
+                        sensor.path,
+                        format_duration(t)
+                    );
+                    event!(Level::WARN, id = self.instance_id, err = message, "failed");
+                    return Err(ExecError {
+                        message,
+                        reason: FailureReason::Timeout,
+                    }
+                    .into());
+                }
+            },
+            None => sensor.wait().await,
+        };
+
+        let files: Vec<String> = files.iter().map(|f| f.display().to_string()).collect();
+        let logs = files.iter().map(|f| format!("matched {}\n", f)).collect();
+
+        Ok(Outcome {
+            logs,
+            outputs: Params::from([(FileSensor::FILES_PARAM.to_string(), files.join("\n"))]),
+        })
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut h = Hash::new();
+
+        h.insert(
+            Yaml::String("instance_id".into()),
+            Yaml::String(self.instance_id.clone()),
+        );
+        h.insert(
+            Yaml::String("task_id".into()),
+            Yaml::String(self.task.task_id.clone()),
+        );
+        h.insert(
+            Yaml::String("exec_at".into()),
+            Yaml::String(format_time(self.exec_at)),
+        );
+        h.insert(
+            Yaml::String("retry_num".into()),
+            Yaml::Integer(self.retry_num as i64),
+        );
+        h.insert(
+            Yaml::String("trigger".into()),
+            Yaml::String(self.trigger.to_string()),
+        );
+        if !self.params.is_empty() {
+            h.insert(Yaml::String("params".into()), params_to_yaml(&self.params));
+        }
+        if self.downstream {
+            h.insert(Yaml::String("downstream".into()), Yaml::Boolean(true));
+        }
+        h.insert(
+            Yaml::String("logical_date".into()),
+            Yaml::String(format_time(self.logical_date)),
+        );
+        if let Some((start, end)) = self.data_interval() {
+            h.insert(
+                Yaml::String("data_interval".into()),
+                Yaml::Array(vec![
+                    Yaml::String(format_time(start)),
+                    Yaml::String(format_time(end)),
+                ]),
+            );
+        }
+        if let Some(id) = &self.backfill {
+            h.insert(Yaml::String("backfill".into()), Yaml::String(id.clone()));
+        }
+        if !self.context.is_empty() {
+            h.insert(
+                Yaml::String("context".into()),
+                params_to_yaml(&self.context),
+            );
+        }
+
+        Yaml::Hash(h)
+    }
+}
+
+impl Trigger {
+    pub(crate) fn from_str(data: &str) -> Result<Self> {
+        match data {
+            "scheduled" => Ok(Trigger::Scheduled),
+            "manual" => Ok(Trigger::Manual),
+            "backfill" => Ok(Trigger::Backfill),
+            "webhook" => Ok(Trigger::Webhook),
+            t => Err(format!("invalid trigger: {}", t).into()),
         }
     }
 }
 
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Scheduled => write!(f, "scheduled"),
+            Trigger::Manual => write!(f, "manual"),
+            Trigger::Backfill => write!(f, "backfill"),
+            Trigger::Webhook => write!(f, "webhook"),
+        }
+    }
+}
+
+impl CalendarPolicy {
+    pub(crate) fn from_str(data: &str) -> Result<Self> {
+        match data {
+            "skip" => Ok(CalendarPolicy::Skip),
+            "following" => Ok(CalendarPolicy::Following),
+            "preceding" => Ok(CalendarPolicy::Preceding),
+            p => Err(format!(
+                "invalid calendar policy: {} (skip | following | preceding)",
+                p
+            )
+            .into()),
+        }
+    }
+}
+
+impl fmt::Display for CalendarPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalendarPolicy::Skip => write!(f, "skip"),
+            CalendarPolicy::Following => write!(f, "following"),
+            CalendarPolicy::Preceding => write!(f, "preceding"),
+        }
+    }
+}
+
+impl MisfirePolicy {
+    pub(crate) fn from_str(data: &str) -> Result<Self> {
+        match data {
+            "fire_once" => Ok(MisfirePolicy::FireOnce),
+            "fire_all" => Ok(MisfirePolicy::FireAll),
+            "skip" => Ok(MisfirePolicy::Skip),
+            "fail" => Ok(MisfirePolicy::Fail),
+            m => Err(format!(
+                "invalid misfire policy: {} (fire_once | fire_all | skip | fail)",
+                m
+            )
+            .into()),
+        }
+    }
+}
+
+impl fmt::Display for MisfirePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MisfirePolicy::FireOnce => write!(f, "fire_once"),
+            MisfirePolicy::FireAll => write!(f, "fire_all"),
+            MisfirePolicy::Skip => write!(f, "skip"),
+            MisfirePolicy::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// Check a task id: letters, digits, `.`, `_` and `-`. Ids end up in paths, of output files
+/// and cgroups, and must not lead out of their directory.
+pub fn validate_task_id(id: &str) -> Result<()> {
+    match is_valid_id(id) {
+        true => Ok(()),
+        false => Err(format!(
+            "invalid task_id '{}', expected letters, digits, '.', '_' and '-'",
+            id
+        )
+        .into()),
+    }
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !matches!(id, "" | "." | "..")
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// Check `name` can be used as a parameter, that is in the name of an environment variable
+pub fn validate_param_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "invalid parameter name '{}', expected letters, digits and '_'",
+            name
+        )
+        .into()),
+    }
+}
+
+/// Parse a mapping of parameter names to scalar values
+pub(crate) fn params_from_yaml(doc: &Yaml) -> Result<Params> {
+    let hash = doc.as_hash().ok_or("params must be a mapping")?;
+    let mut params = Params::new();
+
+    for (k, v) in hash {
+        let name = k
+            .as_str()
+            .ok_or_else(|| format!("invalid parameter name: {:?}", k))?;
+        validate_param_name(name)?;
+
+        let value = match v {
+            Yaml::String(s) => s.clone(),
+            Yaml::Integer(i) => i.to_string(),
+            Yaml::Real(r) => r.clone(),
+            Yaml::Boolean(b) => b.to_string(),
+            Yaml::Null => String::new(),
+            v => return Err(format!("invalid value of parameter {}: {:?}", name, v).into()),
+        };
+
+        params.insert(name.to_string(), value);
+    }
+
+    Ok(params)
+}
+
+pub(crate) fn params_to_yaml(params: &Params) -> Yaml {
+    Yaml::Hash(
+        params
+            .iter()
+            .map(|(k, v)| (Yaml::String(k.clone()), Yaml::String(v.clone())))
+            .collect(),
+    )
+}
+
 impl ScheduleType {
-    const HELP: &'static str = "once | dstream:<task_id> | interval:<Xn|s|m|h>";
+    const HELP: &'static str = "once | dstream:<task_id> | interval:<Xn|s|m|h|d> | \
+        [cron:]<min hour day month weekday> | bday:<n>@<HH:MM> | datasets:<name>[,<name>...]";
+
+    /// Schedules with points after each other, rescheduled after every run
+    pub fn is_periodic(&self) -> bool {
+        matches!(
+            self,
+            ScheduleType::Interval(_) | ScheduleType::Cron(_) | ScheduleType::BusinessDay(..)
+        )
+    }
+
+    /// Schedules evaluated in the time zone of the task
+    pub fn is_local(&self) -> bool {
+        matches!(self, ScheduleType::Cron(_) | ScheduleType::BusinessDay(..))
+    }
 
     pub(crate) fn from_str(data: &str) -> Result<Self> {
         match data.trim() {
             "once" => Ok(Self::Once),
 
+            s if s.starts_with("cron:") => Ok(Self::Cron(Cron::parse(&s["cron:".len()..])?)),
+            s if s.starts_with("bday:") => {
+                let invalid = || {
+                    format!("invalid business day schedule: {}, expected bday:<n>@<HH:MM>, e.g. bday:1@06:00 or bday:-1@18:00", s)
+                };
+
+                let (n, time) = s["bday:".len()..].split_once('@').ok_or_else(invalid)?;
+                let n: i32 = n.parse().map_err(|_| invalid())?;
+                let time = NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| invalid())?;
+
+                match n != 0 && n.abs() <= 23 {
+                    true => Ok(Self::BusinessDay(n, time)),
+                    false => Err(format!(
+                        "business day out of range: {} (1 to 23, or -1 to -23)",
+                        n
+                    )
+                    .into()),
+                }
+            }
+            s if s.starts_with("datasets:") => {
+                let mut datasets: Vec<DatasetId> = Vec::new();
+
+                for name in s["datasets:".len()..].split(',').map(str::trim) {
+                    validate_dataset_name(name)?;
+                    if !datasets.iter().any(|d| d == name) {
+                        datasets.push(name.to_string());
+                    }
+                }
+
+                Ok(Self::Datasets(datasets))
+            }
+            s if s.starts_with('@') || s.split_whitespace().count() == 5 => {
+                Ok(Self::Cron(Cron::parse(s)?))
+            }
+
             s => {
-                let mut parts = s.split(":");
+                let mut parts = s.split(':');
 
                 match parts.next() {
-                    Some(st) => match st {
-                        "dstream" => {
-                            if let Some(s) = parts.next() {
-                                return Ok(Self::DownStream(s.to_string()));
-                            } else {
-                                return Err(
-                                    format!("no task_id provided\n{}", ScheduleType::HELP).into()
-                                );
-                            };
-                        }
-                        "interval" => {
-                            if let Some(d) = parts.next() {
-                                let value: u64 = match d[0..d.len() - 1].parse() {
-                                    Ok(n) => n,
-                                    Err(e) => return Err(e.into()),
-                                };
-
-                                let duration = match d.chars().last().unwrap() {
-                                    'n' => Duration::from_nanos(value),
-                                    's' => Duration::from_secs(value),
-                                    'm' => Duration::from_secs(value * 60),
-                                    'h' => Duration::from_secs(value * 60 * 60),
-                                    _ => {
-                                        return Err(format!("invalid time specifier: {}", d).into())
-                                    }
-                                };
-
-                                return Ok(Self::Interval(duration));
-                            } else {
-                                return Err(format!(
-                                    "invalid ScheduleType provided\n {}",
-                                    ScheduleType::HELP
-                                )
-                                .into());
-                            }
-                        }
-                        _ => Err(
+                    Some("dstream") => match parts.next() {
+                        Some(s) if !s.is_empty() => Ok(Self::DownStream(s.to_string())),
+                        _ => Err(format!("no task_id provided\n{}", ScheduleType::HELP).into()),
+                    },
+                    Some("interval") => match parts.next() {
+                        Some(d) => Ok(Self::Interval(parse_duration(d)?)),
+                        None => Err(format!(
+                            "invalid ScheduleType provided\n {}",
+                            ScheduleType::HELP
+                        )
+                        .into()),
+                    },
+                    Some(st) => {
+                        Err(
                             format!("incorrect scheduletype: {}\n{}", st, ScheduleType::HELP)
                                 .into(),
-                        ),
-                    },
-
-                    None => {
-                        return Err(
-                            format!("invalid syntax for command\n{}", ScheduleType::HELP).into(),
                         )
                     }
+                    None => {
+                        Err(format!("invalid syntax for command\n{}", ScheduleType::HELP).into())
+                    }
                 }
             }
         }
     }
 }
 
+impl fmt::Display for ScheduleType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleType::Once => write!(f, "once"),
+            ScheduleType::DownStream(task_id) => write!(f, "dstream:{}", task_id),
+            ScheduleType::Interval(d) => write!(f, "interval:{}", format_duration(*d)),
+            ScheduleType::Cron(cron) => write!(f, "cron:{}", cron),
+            ScheduleType::BusinessDay(n, time) => write!(f, "bday:{}@{}", n, time.format("%H:%M")),
+            ScheduleType::Datasets(datasets) => write!(f, "datasets:{}", datasets.join(",")),
+        }
+    }
+}
+
+/// Largest total size of the names and values of the outputs of a run
+pub const MAX_OUTPUTS: usize = 64 * 1024;
+
+/// Prefix of the lines a command prints to stdout to set an output, `::output name=value`
+pub const OUTPUT_MARKER: &str = "::output ";
+
+/// Outputs set by a command: lines of its stdout starting with [`OUTPUT_MARKER`], then the
+/// JSON object it wrote to `$CHAINZ_OUTPUT`, if any
+fn outputs(stdout: &[u8], file: &std::path::Path) -> Result<Params> {
+    let mut outputs = Params::new();
+
+    for line in String::from_utf8_lossy(stdout).lines() {
+        if let Some(output) = line.strip_prefix(OUTPUT_MARKER) {
+            let (name, value) = output
+                .split_once('=')
+                .ok_or_else(|| format!("expected {}name=value: {}", OUTPUT_MARKER, line))?;
+            validate_param_name(name)?;
+            outputs.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    match std::fs::read_to_string(file) {
+        Ok(data) if !data.trim().is_empty() => {
+            // A JSON object is a YAML mapping
+            let docs = YamlLoader::load_from_str(&data)
+                .map_err(|e| format!("invalid $CHAINZ_OUTPUT: {}", e))?;
+
+            match docs.as_slice() {
+                [doc @ Yaml::Hash(_)] => outputs.extend(params_from_yaml(doc)?),
+                _ => return Err("$CHAINZ_OUTPUT must hold a JSON object".into()),
+            }
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("failed to read $CHAINZ_OUTPUT: {}", e).into()),
+    }
+
+    let size: usize = outputs.iter().map(|(k, v)| k.len() + v.len()).sum();
+    if size > MAX_OUTPUTS {
+        return Err(format!("{} bytes, at most {} allowed", size, MAX_OUTPUTS).into());
+    }
+
+    Ok(outputs)
+}
+
+/// File a command may write its outputs to, removed on drop
+struct OutputFile(std::path::PathBuf);
+
+impl OutputFile {
+    /// Create the empty file of `instance_id` in [`output_dir`], only accessible to `owner`,
+    /// the uid and gid the command runs as, or else the server
+    #[cfg(unix)]
+    fn create(instance_id: &str, owner: Option<(u32, u32)>) -> Result<Self> {
+        use std::os::unix::fs::{fchown, OpenOptionsExt};
+
+        let path = output_dir()?.join(format!("{}.out", instance_id));
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+        let output = OutputFile(path);
+
+        if let Some((uid, gid)) = owner {
+            fchown(&file, Some(uid), Some(gid))?;
+        }
+
+        Ok(output)
+    }
+
+    #[cfg(not(unix))]
+    fn create(instance_id: &str, _owner: Option<(u32, u32)>) -> Result<Self> {
+        Ok(OutputFile(
+            std::env::temp_dir().join(format!("chainz-{}.out", instance_id)),
+        ))
+    }
+}
+
+/// Remove the directory of output files, once no commands run anymore
+pub(crate) fn remove_output_dir() {
+    #[cfg(unix)]
+    if let Some(path) = OUTPUT_DIR.lock().unwrap().take() {
+        let _ = std::fs::remove_dir(path);
+    }
+}
+
+#[cfg(unix)]
+static OUTPUT_DIR: std::sync::Mutex<Option<std::path::PathBuf>> = std::sync::Mutex::new(None);
+
+/// Directory of the output files of the server, created on first use. Only the server may
+/// list it or add files to it, its random name keeps others from preparing it.
+#[cfg(unix)]
+fn output_dir() -> Result<std::path::PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let mut dir = OUTPUT_DIR.lock().unwrap();
+    if let Some(path) = &*dir {
+        return Ok(path.clone());
+    }
+
+    loop {
+        let path = std::env::temp_dir().join(format!("chainz-{:016x}", fastrand::u64(..)));
+
+        match std::fs::DirBuilder::new().mode(0o700).create(&path) {
+            Ok(()) => {
+                // Users of tasks reach their own files, whatever the umask
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o711))?;
+                *dir = Some(path.clone());
+                return Ok(path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("failed to create {}: {}", path.display(), e).into()),
+        }
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Process group of a running task, killed on drop unless cleared
+struct ProcessGroup(Option<u32>);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0 {
+            // SAFETY: kill has no memory safety requirements
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+            }
+        }
+    }
+}
+
 impl PartialEq for TaskInstance {
     fn eq(&self, other: &Self) -> bool {
         self.instance_id == other.instance_id
     }
-    fn ne(&self, other: &Self) -> bool {
-        !self.eq(other)
-    }
 }
 
 impl Eq for TaskInstance {}
@@ -205,6 +1562,192 @@ impl Ord for TaskInstance {
 
 impl PartialOrd for TaskInstance {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(other.exec_at.cmp(&self.exec_at))
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::{EnvProvider, Secrets};
+
+    fn instance(definition: &str, logical_date: &str) -> TaskInstance {
+        let task = Task::from_yaml_str(definition).unwrap();
+        let mut ti = TaskInstance::new(task, SystemTime::now(), 0);
+        ti.logical_date = parse_time(logical_date).unwrap();
+        ti
+    }
+
+    #[test]
+    fn render_interval_variables() {
+        let ti = instance(
+            "task_id: load\n\
+             schedule: interval:1h\n\
+             cmd: load {{ data_interval_start }} {{ data_interval_end }} {{ ds }} {{ retry_num }}",
+            "2024-03-10T06:00:00Z",
+        );
+
+        assert_eq!(
+            ti.render(&Params::new()).unwrap().task.cmd,
+            "load 2024-03-10T05:00:00Z 2024-03-10T06:00:00Z 2024-03-10 0"
+        );
+    }
+
+    #[test]
+    fn render_cron_variables_in_task_time_zone() {
+        let ti = instance(
+            "task_id: load\n\
+             schedule: cron:0 0 * * *\n\
+             timezone: America/New_York\n\
+             cmd: load {{ data_interval_start }} {{ ds }} {{ logical_date | date('%H:%M %Z') | raw }}",
+            "2024-03-10T05:00:00Z",
+        );
+
+        assert_eq!(
+            ti.render(&Params::new()).unwrap().task.cmd,
+            "load 2024-03-09T05:00:00Z 2024-03-10 00:00 EST"
+        );
+    }
+
+    #[test]
+    fn render_without_data_interval() {
+        let ti = instance(
+            "task_id: load\nschedule: once\ncmd: load {{ data_interval_start }}",
+            "2024-03-10T06:00:00Z",
+        );
+        assert!(ti.render(&Params::new()).is_err());
+    }
+
+    #[test]
+    fn render_params_outputs_and_overrides() {
+        let mut ti = instance(
+            "task_id: load\n\
+             schedule: interval:1d\n\
+             cmd: load {{ params.path }} {{ params.table }} {{ outputs.rows }}\n\
+             params:\n  path: /data/{{ ds }}\n  table: orders",
+            "2024-03-10T00:00:00Z",
+        );
+        ti.context.insert("rows".into(), "42".into());
+        ti.params.insert("table".into(), "x; rm -rf ~".into());
+
+        let rendered = ti.render(&Params::new()).unwrap();
+        assert_eq!(rendered.task.cmd, "load /data/2024-03-10 'x; rm -rf ~' 42");
+        assert_eq!(rendered.params()["path"], "/data/2024-03-10");
+    }
+
+    #[test]
+    fn task_ids() {
+        for id in ["load_orders", "load-orders.v2", "..a", "A1"] {
+            assert!(validate_task_id(id).is_ok(), "{}", id);
+        }
+        for id in ["", ".", "..", "../etc", "a/b", "a b", "a\0"] {
+            assert!(validate_task_id(id).is_err(), "{:?}", id);
+        }
+
+        let definition = "task_id: ../cgroup\nschedule: once\ncmd: 'true'";
+        assert!(Task::from_yaml_str(definition).is_err());
+        assert!(Task::new("../cgroup", ScheduleType::Once, "true", 0).is_err());
+    }
+
+    #[test]
+    fn spread_is_stable() {
+        let task = |id: &str, cmd: &str| {
+            Task::from_yaml_str(&format!(
+                "task_id: {}\nschedule: cron:0 2 * * *\nspread: 1h\ncmd: {}",
+                id, cmd
+            ))
+            .unwrap()
+        };
+        let point = parse_time("2024-03-10T02:00:00Z").unwrap();
+        let offset = |task: &Task| task.exec_time(point).duration_since(point).unwrap();
+
+        let load = offset(&task("load", "load"));
+        assert_eq!(load, Duration::from_secs(stable_hash("load") % 3600));
+        assert!(load < Duration::from_secs(3600));
+
+        // Keyed on the id only, the same in every instance of the task
+        assert_eq!(offset(&task("load", "load")), load);
+        assert_eq!(offset(&task("load", "other")), load);
+
+        let offsets: std::collections::BTreeSet<Duration> =
+            ["load", "load_orders", "export", "report"]
+                .iter()
+                .map(|id| offset(&task(id, "run")))
+                .collect();
+        assert_eq!(offsets.len(), 4);
+    }
+
+    /// Task with a calendar where Good Friday, 2024-03-29, is a holiday
+    fn with_holiday(definition: &str) -> Task {
+        let mut task = Task::from_yaml_str(definition).unwrap();
+        task.business_days =
+            Arc::new(Calendar::from_yaml_str("easter", "holidays: [2024-03-29]").unwrap());
+        task
+    }
+
+    #[test]
+    fn calendar_policies() {
+        let t = |data| parse_time(data).unwrap();
+        let daily = "task_id: load\nschedule: cron:0 6 * * *\ncalendar: easter\ncmd: load";
+
+        let task = with_holiday(daily);
+        assert!(task.skips(t("2024-03-29T06:00:00Z")));
+        assert_eq!(
+            task.next_point(t("2024-03-28T06:00:00Z"), t("2024-03-28T06:00:00Z")),
+            Some(t("2024-04-01T06:00:00Z"))
+        );
+
+        let task = with_holiday(&format!("{}\ncalendar_policy: following", daily));
+        assert!(!task.skips(t("2024-03-29T06:00:00Z")));
+        assert_eq!(
+            task.exec_time(t("2024-03-29T06:00:00Z")),
+            t("2024-04-01T06:00:00Z")
+        );
+
+        let task = with_holiday(&format!("{}\ncalendar_policy: preceding", daily));
+        assert_eq!(
+            task.exec_time(t("2024-03-30T06:00:00Z")),
+            t("2024-03-28T06:00:00Z")
+        );
+        assert_eq!(
+            task.exec_time(t("2024-03-27T06:00:00Z")),
+            t("2024-03-27T06:00:00Z")
+        );
+
+        // Last business day of the month, before the holiday
+        let task =
+            with_holiday("task_id: close\nschedule: bday:-1@18:00\ncalendar: easter\ncmd: close");
+        assert_eq!(
+            task.next_point(t("2024-02-29T18:00:00Z"), t("2024-03-01T00:00:00Z")),
+            Some(t("2024-03-28T18:00:00Z"))
+        );
+    }
+
+    #[tokio::test]
+    async fn secrets_of_the_server_are_not_inherited() {
+        std::env::set_var("CHAINZ_TEST_INHERIT_SECRET_OTHER", "hunter2");
+        let config = SchedulerConfig {
+            secrets: Secrets::new().with(EnvProvider::new("CHAINZ_TEST_INHERIT_SECRET_")),
+            ..SchedulerConfig::default()
+        };
+
+        for inherit_env in ["true", "[CHAINZ_TEST_INHERIT_SECRET_OTHER, PATH]"] {
+            let ti = instance(
+                &format!(
+                    "task_id: leak
+\
+                     schedule: once
+\
+                     inherit_env: {}
+\
+                     cmd: echo \"[$CHAINZ_TEST_INHERIT_SECRET_OTHER]\"",
+                    inherit_env
+                ),
+                "2024-03-10T06:00:00Z",
+            );
+
+            let outcome = ti.exec(&config, &Params::new()).await.unwrap();
+            assert_eq!(outcome.logs, "[]\n", "inherit_env: {}", inherit_env);
+        }
     }
 }
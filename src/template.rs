@@ -0,0 +1,527 @@
+//! Jinja-like templates of commands and parameters, rendered just before a run
+//!
+//! ```yaml
+//! cmd: load --table {{ params.table }} --day {{ ds }} --rows {{ outputs.rows | default("0") }}
+//! params:
+//!   table: orders
+//!   path: /data/{{ logical_date | date("%Y/%m/%d") }}
+//! ```
+//! `{{ expr }}` is replaced by the value of `expr`, a variable or a quoted string, passed
+//! through `| filter` calls. `{# ... #}` is a comment, statements (`{% ... %}`) are not
+//! supported.
+//!
+//! In commands each value is a single shell word, quoted if it holds anything but letters,
+//! digits and `-_./:=@,+%`, so parameters set by operators or webhooks can't inject
+//! commands. The `raw` filter inserts a trusted value as is, to build a word in a quoted
+//! string or several words.
+//!
+//! Variables of a run:
+//! - `task_id`, `instance_id`, `retry_num`
+//! - `logical_date`, `data_interval_start` and `data_interval_end` in RFC 3339, the data
+//!   interval is only defined for periodic schedules
+//! - `ds`, the logical date as `YYYY-MM-DD` in the time zone of the task
+//! - `params.<name>`, the parameters of the run
+//! - `outputs.<name>`, the outputs of the up-stream runs of its chain
+//!
+//! Filters: `default(value)` for undefined variables, `date(format)` to format a time with
+//! strftime in the time zone of the task, `upper`, `lower`, `trim`, `replace(from, to)`,
+//! `quote` for use as a single shell word outside of commands and `raw`.
+
+use std::collections::HashMap;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+use crate::time::parse_time;
+use crate::Result;
+
+/// Parsed template
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone)]
+struct Expr {
+    value: Value,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Var(String),
+    Str(String),
+}
+
+#[derive(Debug, Clone)]
+enum Filter {
+    Default(String),
+    Date(String),
+    Upper,
+    Lower,
+    Trim,
+    Replace(String, String),
+    Quote,
+    Raw,
+}
+
+/// Values of the variables of templates
+#[derive(Debug, Clone)]
+pub struct Variables {
+    /// By name, `params.table` for the parameter `table`
+    values: HashMap<String, String>,
+    /// Zone the `date` filter formats times in
+    timezone: Tz,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Pipe,
+    Open,
+    Close,
+    Comma,
+}
+
+impl Template {
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = data;
+
+        while let Some(i) = rest.find('{') {
+            let (tag, end) = match rest[i..].get(..2) {
+                Some("{{") => ("{{", "}}"),
+                Some("{#") => ("{#", "#}"),
+                Some("{%") => return Err("statements ({% ... %}) are not supported".into()),
+                _ => {
+                    push_text(&mut parts, &rest[..=i]);
+                    rest = &rest[i + 1..];
+                    continue;
+                }
+            };
+
+            push_text(&mut parts, &rest[..i]);
+            let inner = &rest[i + 2..];
+
+            let len = match tag {
+                "{{" => expr_len(inner),
+                _ => inner.find(end),
+            }
+            .ok_or_else(|| format!("unclosed {}", tag))?;
+
+            if tag == "{{" {
+                parts.push(Part::Expr(Expr::parse(&inner[..len])?));
+            }
+
+            rest = &inner[len + 2..];
+        }
+
+        push_text(&mut parts, rest);
+        Ok(Template { parts })
+    }
+
+    pub fn render(&self, vars: &Variables) -> Result<String> {
+        self.render_with(vars, false)
+    }
+
+    /// Render a shell command, values are shell quoted unless they are `raw`
+    pub fn render_shell(&self, vars: &Variables) -> Result<String> {
+        self.render_with(vars, true)
+    }
+
+    fn render_with(&self, vars: &Variables, shell: bool) -> Result<String> {
+        let mut out = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Expr(expr) => {
+                    let value = expr.eval(vars)?;
+                    let quoted = expr
+                        .filters
+                        .iter()
+                        .any(|f| matches!(f, Filter::Raw | Filter::Quote));
+
+                    match shell && !quoted {
+                        true => out.push_str(&shell_quote(&value)),
+                        false => out.push_str(&value),
+                    }
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Render a template in one go
+pub fn render(data: &str, vars: &Variables) -> Result<String> {
+    Template::parse(data)?.render(vars)
+}
+
+/// Render a shell command in one go, see [`Template::render_shell`]
+pub fn render_shell(data: &str, vars: &Variables) -> Result<String> {
+    Template::parse(data)?.render_shell(vars)
+}
+
+/// `value` as a single shell word, as is if the shell gives none of its characters a
+/// meaning
+fn shell_quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@,+%".contains(c));
+
+    match plain {
+        true => value.to_string(),
+        false => format!("'{}'", value.replace('\'', r"'\''")),
+    }
+}
+
+fn push_text(parts: &mut Vec<Part>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+
+    match parts.last_mut() {
+        Some(Part::Text(t)) => t.push_str(text),
+        _ => parts.push(Part::Text(text.to_string())),
+    }
+}
+
+/// Length of the expression up to its `}}`, skipping string literals
+fn expr_len(data: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in data.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if data[i..].starts_with("}}") => return Some(i),
+            None => {}
+        }
+    }
+
+    None
+}
+
+fn tokenize(data: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = data.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '|' => tokens.push(Token::Pipe),
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            ',' => tokens.push(Token::Comma),
+            '"' | '\'' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(e) => s.push(e),
+                            None => return Err("unterminated string".into()),
+                        },
+                        Some(q) if q == c => break,
+                        Some(ch) => s.push(ch),
+                        None => return Err("unterminated string".into()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(&ch) = chars.peek() {
+                    if !(ch.is_ascii_alphanumeric() || ch == '_' || ch == '.') {
+                        break;
+                    }
+                    ident.push(ch);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            c => return Err(format!("unexpected '{}'", c).into()),
+        }
+    }
+
+    Ok(tokens)
+}
+
+impl Expr {
+    fn parse(data: &str) -> Result<Self> {
+        let invalid = |e: Box<dyn std::error::Error>| format!("invalid {{{{{}}}}}: {}", data, e);
+
+        Expr::parse_tokens(tokenize(data).map_err(invalid)?).map_err(|e| invalid(e).into())
+    }
+
+    fn parse_tokens(tokens: Vec<Token>) -> Result<Self> {
+        let mut tokens = tokens.into_iter().peekable();
+
+        let value = match tokens.next() {
+            // Numbers are strings like everything else
+            Some(Token::Ident(s)) if s.starts_with(|c: char| c.is_ascii_digit()) => Value::Str(s),
+            Some(Token::Ident(name)) => {
+                if name.split('.').any(str::is_empty) {
+                    return Err(format!("invalid variable name '{}'", name).into());
+                }
+                Value::Var(name)
+            }
+            Some(Token::Str(s)) => Value::Str(s),
+            Some(t) => return Err(format!("unexpected {:?}", t).into()),
+            None => return Err("empty expression".into()),
+        };
+
+        let mut filters = Vec::new();
+
+        while let Some(token) = tokens.next() {
+            let name = match (token, tokens.next()) {
+                (Token::Pipe, Some(Token::Ident(name))) => name,
+                (Token::Pipe, _) => return Err("expected a filter after '|'".into()),
+                (t, _) => return Err(format!("unexpected {:?}", t).into()),
+            };
+
+            let mut args = Vec::new();
+
+            if tokens.next_if_eq(&Token::Open).is_some() {
+                loop {
+                    match tokens.next() {
+                        Some(Token::Close) if args.is_empty() => break,
+                        Some(Token::Str(s)) => args.push(s),
+                        Some(Token::Ident(s)) if s.starts_with(|c: char| c.is_ascii_digit()) => {
+                            args.push(s)
+                        }
+                        _ => return Err(format!("arguments of {} must be literals", name).into()),
+                    }
+                    match tokens.next() {
+                        Some(Token::Comma) => {}
+                        Some(Token::Close) => break,
+                        _ => return Err(format!("unclosed arguments of {}", name).into()),
+                    }
+                }
+            }
+
+            filters.push(Filter::new(&name, args)?);
+        }
+
+        Ok(Expr { value, filters })
+    }
+
+    fn eval(&self, vars: &Variables) -> Result<String> {
+        let mut value = match &self.value {
+            Value::Var(name) => vars.values.get(name).cloned(),
+            Value::Str(s) => Some(s.clone()),
+        };
+
+        for filter in &self.filters {
+            value = match (filter, value) {
+                (Filter::Default(d), None) => Some(d.clone()),
+                (_, None) => None,
+                (f, Some(v)) => Some(f.apply(v, vars.timezone)?),
+            };
+        }
+
+        match (value, &self.value) {
+            (Some(v), _) => Ok(v),
+            (None, Value::Var(name)) => Err(format!("undefined variable '{}'", name).into()),
+            (None, Value::Str(_)) => unreachable!("literals are always defined"),
+        }
+    }
+}
+
+impl Filter {
+    fn new(name: &str, args: Vec<String>) -> Result<Self> {
+        let filter = match (name, args.as_slice()) {
+            ("default", [d]) => Filter::Default(d.clone()),
+            ("date", [format]) => {
+                if StrftimeItems::new(format).any(|i| i == Item::Error) {
+                    return Err(format!("invalid date format '{}'", format).into());
+                }
+                Filter::Date(format.clone())
+            }
+            ("upper", []) => Filter::Upper,
+            ("lower", []) => Filter::Lower,
+            ("trim", []) => Filter::Trim,
+            ("replace", [from, to]) => Filter::Replace(from.clone(), to.clone()),
+            ("quote", []) => Filter::Quote,
+            ("raw", []) => Filter::Raw,
+            ("default" | "date" | "upper" | "lower" | "trim" | "replace" | "quote" | "raw", _) => {
+                return Err(format!("wrong number of arguments for {}", name).into())
+            }
+            _ => return Err(format!("unknown filter '{}'", name).into()),
+        };
+
+        Ok(filter)
+    }
+
+    fn apply(&self, value: String, timezone: Tz) -> Result<String> {
+        let value = match self {
+            Filter::Default(_) | Filter::Raw => value,
+            Filter::Date(format) => {
+                let time = parse_time(&value)?;
+                DateTime::<Utc>::from(time)
+                    .with_timezone(&timezone)
+                    .format(format)
+                    .to_string()
+            }
+            Filter::Upper => value.to_uppercase(),
+            Filter::Lower => value.to_lowercase(),
+            Filter::Trim => value.trim().to_string(),
+            Filter::Replace(from, to) => value.replace(from.as_str(), to),
+            Filter::Quote => shell_quote(&value),
+        };
+
+        Ok(value)
+    }
+}
+
+impl Variables {
+    pub fn new(timezone: Tz) -> Self {
+        Variables {
+            values: HashMap::new(),
+            timezone,
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, value: String) {
+        self.values.insert(name.to_string(), value);
+    }
+
+    /// Insert each value of `values` as `<prefix>.<name>`
+    pub fn insert_all<'a>(
+        &mut self,
+        prefix: &str,
+        values: impl IntoIterator<Item = (&'a String, &'a String)>,
+    ) {
+        for (name, value) in values {
+            self.values
+                .insert(format!("{}.{}", prefix, name), value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn vars() -> Variables {
+        let mut vars = Variables::new(chrono_tz::Europe::Berlin);
+        vars.insert("ds", "2024-03-10".into());
+        vars.insert("logical_date", "2024-03-10T23:30:00Z".into());
+        vars.insert_all(
+            "params",
+            &BTreeMap::from([
+                ("table".to_string(), "orders".to_string()),
+                ("name".to_string(), "  Mixed Case  ".to_string()),
+                ("evil".to_string(), "x'; rm -rf ~; echo '".to_string()),
+            ]),
+        );
+        vars
+    }
+
+    #[test]
+    fn variables_and_literals() {
+        let vars = vars();
+        assert_eq!(
+            render("load {{ params.table }}", &vars).unwrap(),
+            "load orders"
+        );
+        assert_eq!(render("{{ 'a}}b' }}{{ 42 }}", &vars).unwrap(), "a}}b42");
+        assert_eq!(render("a{# note #}b {c}", &vars).unwrap(), "ab {c}");
+        assert_eq!(render("no templates", &vars).unwrap(), "no templates");
+    }
+
+    #[test]
+    fn filters() {
+        let vars = vars();
+        let cases = [
+            ("{{ params.name | trim | upper }}", "MIXED CASE"),
+            ("{{ params.name | lower }}", "  mixed case  "),
+            ("{{ params.table | replace('o', '0') }}", "0rders"),
+            ("{{ params.missing | default('none') }}", "none"),
+            ("{{ params.table | default('none') }}", "orders"),
+            ("{{ params.missing | default(7) | upper }}", "7"),
+            // In the time zone of the task, a day after the date in UTC
+            (
+                "{{ logical_date | date('%Y/%m/%d %H:%M') }}",
+                "2024/03/11 00:30",
+            ),
+            ("{{ params.name | quote }}", "'  Mixed Case  '"),
+            ("{{ params.table | quote }}", "orders"),
+        ];
+
+        for (template, expected) in cases {
+            assert_eq!(render(template, &vars).unwrap(), expected, "{}", template);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let vars = vars();
+        assert!(render("{{ params.missing }}", &vars)
+            .unwrap_err()
+            .to_string()
+            .contains("undefined variable 'params.missing'"));
+        assert!(render(
+            "{{ ds | date('%Y') }}{{ params.table | date('%Q') }}",
+            &vars
+        )
+        .is_err());
+
+        for template in [
+            "{{ ds",
+            "{# note",
+            "{% if x %}",
+            "{{ }}",
+            "{{ ds | nope }}",
+            "{{ ds | default }}",
+            "{{ ds | replace('a') }}",
+            "{{ ds | default(ds) }}",
+            "{{ ds ds }}",
+            "{{ params..x }}",
+        ] {
+            assert!(Template::parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn commands_quote_values() {
+        let vars = vars();
+        let cmd = "psql -c {{ params.evil }} --table {{ params.table }} --day {{ ds }}";
+        assert_eq!(
+            render_shell(cmd, &vars).unwrap(),
+            r"psql -c 'x'\''; rm -rf ~; echo '\''' --table orders --day 2024-03-10"
+        );
+
+        // Already quoted ones are not quoted again
+        assert_eq!(
+            render_shell("echo {{ params.name | quote }}", &vars).unwrap(),
+            "echo '  Mixed Case  '"
+        );
+        assert_eq!(
+            render_shell("echo \"{{ params.name | raw }}\"", &vars).unwrap(),
+            "echo \"  Mixed Case  \""
+        );
+        assert_eq!(
+            render_shell("echo {{ params.missing | default('') }}", &vars).unwrap(),
+            "echo ''"
+        );
+    }
+}
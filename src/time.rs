@@ -0,0 +1,66 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use std::time::{Duration, SystemTime};
+
+use crate::Result;
+
+/// Parse a point in time, accepted formats:
+/// - RFC 3339: `2023-01-01T06:00:00+01:00`
+/// - naive date and time in UTC: `2023-01-01T06:00:00` or `2023-01-01 06:00:00`
+/// - date in UTC, at midnight: `2023-01-01`
+pub fn parse_time(data: &str) -> Result<SystemTime> {
+    let data = data.trim();
+
+    if let Ok(dt) = DateTime::parse_from_rfc3339(data) {
+        return Ok(dt.with_timezone(&Utc).into());
+    }
+
+    for fmt in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(data, fmt) {
+            return Ok(dt.and_utc().into());
+        }
+    }
+
+    match NaiveDate::parse_from_str(data, "%Y-%m-%d") {
+        Ok(d) => Ok(d.and_hms_opt(0, 0, 0).unwrap().and_utc().into()),
+        Err(_) => Err(format!("invalid time: {}", data).into()),
+    }
+}
+
+/// Format a point in time as RFC 3339 in UTC
+pub fn format_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Parse a duration as `<n><unit>`, unit is one of `n`(anos), `s`, `m`, `h` or `d`
+pub fn parse_duration(data: &str) -> Result<Duration> {
+    let data = data.trim();
+
+    if data.len() < 2 || !data.is_char_boundary(data.len() - 1) {
+        return Err(format!("invalid duration: {}", data).into());
+    }
+
+    let (value, unit) = data.split_at(data.len() - 1);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", data))?;
+
+    match unit {
+        "n" => Ok(Duration::from_nanos(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 60 * 60)),
+        "d" => Ok(Duration::from_secs(value * 60 * 60 * 24)),
+        _ => Err(format!("invalid time specifier: {}", data).into()),
+    }
+}
+
+/// Format a duration in the largest unit accepted by [`parse_duration`] that represents it exactly
+pub fn format_duration(d: Duration) -> String {
+    match d.as_secs() {
+        _ if d.subsec_nanos() != 0 => format!("{}n", d.as_nanos()),
+        s if s != 0 && s % 86400 == 0 => format!("{}d", s / 86400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
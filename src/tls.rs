@@ -0,0 +1,109 @@
+//! TLS for connections between [`crate::server::Server`] and [`crate::client::Client`]
+//!
+//! Certificates and keys are read from PEM files. Self-signed certificates
+//! work by passing the certificate itself as the trusted ca, as long as the
+//! certificate is not marked as a ca (`basicConstraints=CA:FALSE`).
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::Result;
+
+/// TLS settings of the server
+#[derive(Debug, Clone)]
+pub struct ServerTlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Require clients to present a certificate signed by this ca
+    pub client_ca: Option<PathBuf>,
+}
+
+/// TLS settings of a client
+#[derive(Debug, Clone)]
+pub struct ClientTlsConfig {
+    /// Ca the server certificate must be signed by
+    pub ca: PathBuf,
+    /// Certificate and key presented to servers verifying clients
+    pub identity: Option<(PathBuf, PathBuf)>,
+    /// Name to verify the server certificate against, defaults to the host of the address
+    pub server_name: Option<String>,
+}
+
+impl ServerTlsConfig {
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let builder = match &self.client_ca {
+            Some(ca) => {
+                let verifier = WebPkiClientVerifier::builder(Arc::new(root_store(ca)?)).build()?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+
+        let config = builder.with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+impl ClientTlsConfig {
+    /// Connector and name of the server at `address`
+    pub fn connector(&self, address: &str) -> Result<(TlsConnector, ServerName<'static>)> {
+        let builder = ClientConfig::builder().with_root_certificates(root_store(&self.ca)?);
+
+        let config = match &self.identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let name = match &self.server_name {
+            Some(name) => name.clone(),
+            None => host(address).to_string(),
+        };
+
+        Ok((
+            TlsConnector::from(Arc::new(config)),
+            ServerName::try_from(name)?,
+        ))
+    }
+}
+
+/// Host part of `host:port`, `[::1]:port` included
+fn host(address: &str) -> &str {
+    let host = address.rsplit_once(':').map_or(address, |(h, _)| h);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| format!("failed to read certificates {}: {}", path.display(), e))?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", path.display()).into());
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| format!("failed to read private key {}: {}", path.display(), e).into())
+}
+
+fn root_store(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+
+    Ok(roots)
+}
@@ -0,0 +1,450 @@
+//! Triggering tasks from other systems over HTTP
+//!
+//! The server answers `POST /hooks/<name>` on its hooks address. Hooks are read from a yaml
+//! file:
+//! ```yaml
+//! hooks:
+//!   - name: export_ready
+//!     secret: "s3cr3t-s3cr3t-s3cr3t"
+//!     tasks: [load_export, notify]
+//!     downstream: true   # also run their down-stream chains, optional
+//! ```
+//! Requests are signed with the secret of their hook: the `X-Chainz-Signature` header,
+//! or GitHub's `X-Hub-Signature-256`, holds `sha256=` and the hex encoded HMAC-SHA256 of
+//! the body. The top-level scalar values of a JSON object body set the parameters of the
+//! runs that their tasks declare, other values are ignored.
+//!
+//! Signatures do not protect against replays: the body signed holds no time or delivery id
+//! the server could check, so anyone who captured a request can trigger its tasks again
+//! with the same parameters. Serve hooks over TLS and only trigger tasks that can safely
+//! run more than once.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ring::hmac;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{event, Level};
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::scheduler::{Scheduler, TriggerOptions};
+use crate::task::{validate_param_name, Params, TaskId, Trigger};
+use crate::Result;
+
+/// Endpoint triggering tasks
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub name: String,
+    secret: String,
+    pub tasks: Vec<TaskId>,
+    /// Also trigger the down-stream chains of the tasks
+    pub downstream: bool,
+}
+
+/// Known hooks, by name
+#[derive(Debug, Default)]
+pub struct Hooks {
+    hooks: HashMap<String, Hook>,
+}
+
+/// Request read by [`serve`]
+struct HttpRequest {
+    method: String,
+    path: String,
+    /// Names are lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Longest request line and headers accepted
+const MAX_HEAD: u64 = 8 * 1024;
+/// Largest body accepted
+const MAX_BODY: usize = 1024 * 1024;
+/// Time a client has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SIGNATURE_HEADERS: [&str; 2] = ["x-chainz-signature", "x-hub-signature-256"];
+
+impl Hooks {
+    const KEYS: [&'static str; 4] = ["name", "secret", "tasks", "downstream"];
+
+    /// Load hooks from a yaml file, see module docs for the format
+    pub fn from_file(path: &str) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read hooks file {}: {}", path, e))?;
+
+        Hooks::from_yaml_str(&data)
+            .map_err(|e| format!("invalid hooks file {}: {}", path, e).into())
+    }
+
+    pub fn from_yaml_str(data: &str) -> Result<Self> {
+        let docs = YamlLoader::load_from_str(data)?;
+
+        let entries = match docs.first().map(|d| &d["hooks"]) {
+            Some(h) => h.as_vec().ok_or("hooks must be a list")?,
+            None => return Err("no hooks provided".into()),
+        };
+
+        let mut hooks = HashMap::new();
+
+        for entry in entries {
+            for key in entry.as_hash().ok_or("hook must be a mapping")?.keys() {
+                match key.as_str() {
+                    Some(k) if Hooks::KEYS.contains(&k) => {}
+                    _ => return Err(format!("unknown hook key: {:?}", key).into()),
+                }
+            }
+
+            let name = entry["name"].as_str().ok_or("hook without name")?;
+            let secret = entry["secret"]
+                .as_str()
+                .ok_or(format!("no secret provided for hook '{}'", name))?;
+
+            if name.is_empty() || name.contains('/') {
+                return Err(format!("invalid hook name: {}", name).into());
+            }
+
+            if secret.len() < 16 {
+                return Err(
+                    format!("secret of hook '{}' is shorter than 16 characters", name).into(),
+                );
+            }
+
+            let tasks = match &entry["tasks"] {
+                Yaml::String(id) => vec![id.clone()],
+                Yaml::Array(ids) if !ids.is_empty() => ids
+                    .iter()
+                    .map(|id| match id.as_str() {
+                        Some(id) => Ok(id.to_string()),
+                        None => Err(format!("invalid task of hook '{}': {:?}", name, id)),
+                    })
+                    .collect::<std::result::Result<_, _>>()?,
+                _ => return Err(format!("no tasks provided for hook '{}'", name).into()),
+            };
+
+            let hook = Hook {
+                name: name.to_string(),
+                secret: secret.to_string(),
+                tasks,
+                downstream: entry["downstream"].as_bool().unwrap_or(false),
+            };
+
+            if hooks.insert(name.to_string(), hook).is_some() {
+                return Err(format!("duplicate hook '{}'", name).into());
+            }
+        }
+
+        Ok(Hooks { hooks })
+    }
+
+    /// Answer a request, returns the status code and body of the response
+    fn handle(&self, sched: &Scheduler, request: &HttpRequest, peer: &str) -> (u16, String) {
+        let path = request.path.split('?').next().unwrap_or_default();
+
+        let hook = match path.strip_prefix("/hooks/").and_then(|n| self.hooks.get(n)) {
+            Some(hook) => hook,
+            None => return (404, "not found".to_string()),
+        };
+
+        if request.method != "POST" {
+            return (405, "only POST is allowed".to_string());
+        }
+
+        let signature = request
+            .headers
+            .iter()
+            .find(|(name, _)| SIGNATURE_HEADERS.contains(&name.as_str()))
+            .map(|(_, value)| value.as_str());
+
+        if !signature.is_some_and(|s| hook.verify(&request.body, s)) {
+            event!(
+                Level::WARN,
+                peer = peer,
+                hook = hook.name,
+                "invalid signature"
+            );
+            return (401, "invalid signature".to_string());
+        }
+
+        let params = match payload_params(&request.body) {
+            Ok(params) => params,
+            Err(e) => return (400, e.to_string()),
+        };
+
+        event!(Level::INFO, peer = peer, hook = hook.name, "webhook");
+
+        let mut status = 202;
+        let mut body = String::new();
+
+        for task_id in &hook.tasks {
+            // Payloads carry much more than parameters, like GitHub events
+            let declared = match sched.tasks.lock().unwrap().get(task_id) {
+                Some(task) => task.params.clone(),
+                None => Params::new(),
+            };
+            let options = TriggerOptions {
+                params: params
+                    .iter()
+                    .filter(|(name, _)| declared.contains_key(*name))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+                downstream: hook.downstream,
+            };
+
+            match sched.trigger_as(task_id, options, Trigger::Webhook) {
+                Ok(inst_id) => body.push_str(&format!("triggered {}\n", inst_id)),
+                Err(e) => {
+                    event!(
+                        Level::ERROR,
+                        hook = hook.name,
+                        id = task_id,
+                        err = e.to_string(),
+                        "failed to trigger task"
+                    );
+                    status = 500;
+                    body.push_str(&format!("failed to trigger {}: {}\n", task_id, e));
+                }
+            }
+        }
+
+        (status, body)
+    }
+}
+
+impl Hook {
+    /// Check `signature`, `sha256=<hex>`, is the HMAC of `body`
+    fn verify(&self, body: &[u8], signature: &str) -> bool {
+        let tag = match signature.strip_prefix("sha256=").and_then(hex_decode) {
+            Some(tag) => tag,
+            None => return false,
+        };
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.secret.as_bytes());
+        hmac::verify(&key, body, &tag).is_ok()
+    }
+}
+
+/// Top-level scalars of a JSON object with names parameters can have, an empty body has
+/// none
+fn payload_params(body: &[u8]) -> Result<Params> {
+    let data = std::str::from_utf8(body).map_err(|_| "payload is not valid UTF-8")?;
+
+    if data.trim().is_empty() {
+        return Ok(Params::new());
+    }
+
+    // A JSON object is a YAML mapping
+    let docs = YamlLoader::load_from_str(data).map_err(|e| format!("invalid payload: {}", e))?;
+
+    let hash = match docs.as_slice() {
+        [Yaml::Hash(hash)] => hash,
+        _ => return Err("payload must be a JSON object".into()),
+    };
+
+    let params = hash
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = name.as_str().filter(|n| validate_param_name(n).is_ok())?;
+            let value = match value {
+                Yaml::String(s) => s.clone(),
+                Yaml::Integer(i) => i.to_string(),
+                Yaml::Real(r) => r.clone(),
+                Yaml::Boolean(b) => b.to_string(),
+                Yaml::Null => String::new(),
+                _ => return None,
+            };
+            Some((name.to_string(), value))
+        })
+        .collect();
+
+    Ok(params)
+}
+
+/// `None` unless `data` is pairs of hex digits
+fn hex_decode(data: &str) -> Option<Vec<u8>> {
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Answer a single request of a client connected to the hooks address
+pub(crate) async fn serve<S>(hooks: &Hooks, sched: &Scheduler, stream: S, peer: &str)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+
+    let (status, body) = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => hooks.handle(sched, &request, peer),
+        Ok(Err(response)) => response,
+        Err(_) => (408, "request timed out".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    );
+
+    let stream = stream.get_mut();
+    if let Err(e) = async {
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+    .await
+    {
+        event!(
+            Level::WARN,
+            peer = peer,
+            err = e.to_string(),
+            "failed to write response"
+        );
+    }
+}
+
+/// Read a request, on failure the response to send
+async fn read_request<S>(
+    stream: &mut BufReader<S>,
+) -> std::result::Result<HttpRequest, (u16, String)>
+where
+    S: AsyncRead + Unpin,
+{
+    let bad_request = |message: &str| (400, message.to_string());
+
+    let mut head = (&mut *stream).take(MAX_HEAD);
+    let mut line = String::new();
+
+    let read = |e: std::io::Error| (400, format!("failed to read request: {}", e));
+
+    head.read_line(&mut line).await.map_err(read)?;
+
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), path.to_string())
+        }
+        _ => return Err(bad_request("invalid request line")),
+    };
+
+    let mut headers = Vec::new();
+
+    loop {
+        line.clear();
+        if head.read_line(&mut line).await.map_err(read)? == 0 {
+            return Err(match head.limit() {
+                0 => (431, "request headers too large".to_string()),
+                _ => bad_request("incomplete request"),
+            });
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        match line.split_once(':') {
+            Some((name, value)) => {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            }
+            None => return Err(bad_request("invalid header")),
+        }
+    }
+
+    let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+
+    if header("transfer-encoding").is_some() {
+        return Err((411, "Content-Length required".to_string()));
+    }
+
+    let length = match header("content-length") {
+        Some(l) => l
+            .parse::<usize>()
+            .map_err(|_| bad_request("invalid Content-Length"))?,
+        None => 0,
+    };
+
+    if length > MAX_BODY {
+        return Err((413, "payload too large".to_string()));
+    }
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.map_err(read)?;
+
+    Ok(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_payloads_keep_top_level_scalars() {
+        let body = br#"{
+            "ref": "refs/heads/main",
+            "created": false,
+            "size": 3,
+            "repository": {"full_name": "acme/etl", "private": true},
+            "commits": [{"id": "abc"}],
+            "bad-name": "x"
+        }"#;
+
+        let params = payload_params(body).unwrap();
+        assert_eq!(
+            params,
+            Params::from([
+                ("created".to_string(), "false".to_string()),
+                ("ref".to_string(), "refs/heads/main".to_string()),
+                ("size".to_string(), "3".to_string()),
+            ])
+        );
+
+        assert!(payload_params(b"").unwrap().is_empty());
+        assert!(payload_params(b"[1, 2]").is_err());
+    }
+
+    #[test]
+    fn signatures() {
+        let hook = Hook {
+            name: "export_ready".into(),
+            secret: "s3cr3t-s3cr3t-s3cr3t".into(),
+            tasks: vec!["load_export".into()],
+            downstream: false,
+        };
+        let body = br#"{"table": "orders"}"#;
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, hook.secret.as_bytes());
+        let tag: String = hmac::sign(&key, body)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        assert!(hook.verify(body, &format!("sha256={}", tag)));
+        assert!(!hook.verify(b"{}", &format!("sha256={}", tag)));
+        assert!(!hook.verify(body, &tag));
+        assert!(!hook.verify(body, "sha256=zz"));
+    }
+}
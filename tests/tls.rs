@@ -0,0 +1,258 @@
+//! Clients and servers talking over TLS, with certificates made by the `openssl` CLI
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use chainz::auth::Auth;
+use chainz::client::{Client, ClientConfig};
+use chainz::protocol::{Request, Response};
+use chainz::server::Server;
+use chainz::tls::{ClientTlsConfig, ServerTlsConfig};
+use chainz::Result;
+use tokio::sync::oneshot;
+
+/// Directory of throwaway certificates, removed on drop
+struct Certs {
+    dir: PathBuf,
+}
+
+impl Certs {
+    /// A trusted ca signing the server and a client, and an untrusted ca signing another
+    /// client. `None` if openssl is not installed.
+    fn generate(name: &str) -> Option<Certs> {
+        if Command::new("openssl").arg("version").output().is_err() {
+            eprintln!("openssl not found, skipping");
+            return None;
+        }
+
+        let dir = std::env::temp_dir().join(format!("chainz-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(
+            dir.join("leaf.ext"),
+            "basicConstraints=CA:FALSE\n\
+             subjectAltName=DNS:localhost,IP:127.0.0.1\n\
+             extendedKeyUsage=serverAuth,clientAuth\n",
+        )
+        .unwrap();
+
+        let certs = Certs { dir };
+        certs.ca("ca");
+        certs.ca("rogue-ca");
+        certs.leaf("server", "ca");
+        certs.leaf("client", "ca");
+        certs.leaf("rogue", "rogue-ca");
+        Some(certs)
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    fn ca(&self, name: &str) {
+        openssl(
+            &self.dir,
+            &format!(
+                "req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 1 \
+                 -subj /CN={name} -keyout {name}.key -out {name}.pem"
+            ),
+        );
+    }
+
+    fn leaf(&self, name: &str, ca: &str) {
+        openssl(
+            &self.dir,
+            &format!(
+                "req -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+                 -subj /CN={name} -keyout {name}.key -out {name}.csr"
+            ),
+        );
+        openssl(
+            &self.dir,
+            &format!(
+                "x509 -req -in {name}.csr -days 1 -CA {ca}.pem -CAkey {ca}.key \
+                 -CAcreateserial -extfile leaf.ext -out {name}.pem"
+            ),
+        );
+    }
+
+    fn server(&self, verify_clients: bool) -> ServerTlsConfig {
+        ServerTlsConfig {
+            cert: self.path("server.pem"),
+            key: self.path("server.key"),
+            client_ca: verify_clients.then(|| self.path("ca.pem")),
+        }
+    }
+
+    fn client(&self, identity: Option<&str>) -> ClientTlsConfig {
+        ClientTlsConfig {
+            ca: self.path("ca.pem"),
+            identity: identity.map(|name| {
+                (
+                    self.path(&format!("{}.pem", name)),
+                    self.path(&format!("{}.key", name)),
+                )
+            }),
+            server_name: Some("localhost".to_string()),
+        }
+    }
+
+    /// SHA-256 fingerprint of the certificate `name`
+    fn fingerprint(&self, name: &str) -> String {
+        let output = Command::new("openssl")
+            .args(["x509", "-noout", "-fingerprint", "-sha256", "-in"])
+            .arg(self.path(&format!("{}.pem", name)))
+            .output()
+            .unwrap();
+
+        let output = String::from_utf8(output.stdout).unwrap();
+        output.trim().rsplit('=').next().unwrap().to_string()
+    }
+}
+
+impl Drop for Certs {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn openssl(dir: &Path, args: &str) {
+    let output = Command::new("openssl")
+        .args(args.split_whitespace())
+        .current_dir(dir)
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "openssl {}: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Send a request to a server using `tls`, from a client using `client`
+async fn with_server(tls: ServerTlsConfig, client: ClientTlsConfig) -> Result<Response> {
+    with_auth(None, tls, client).await
+}
+
+/// Send a request to a server using `tls` and `auth`, from a client using `client`
+async fn with_auth(
+    auth: Option<Auth>,
+    tls: ServerTlsConfig,
+    client: ClientTlsConfig,
+) -> Result<Response> {
+    let mut server = Server::new("127.0.0.1:0").await?.with_tls(&tls)?;
+    if let Some(auth) = auth {
+        server = server.with_auth(auth);
+    }
+    let address = server.addresses()?.remove(0);
+    let (stop, stopped) = oneshot::channel::<()>();
+
+    let talk = async move {
+        let mut config = ClientConfig::new(&address);
+        config.tls = Some(client);
+
+        let response = async {
+            let mut client = Client::connect(config).await?;
+            client.request(&Request::new("LIST")).await
+        }
+        .await;
+
+        let _ = stop.send(());
+        response
+    };
+
+    let (served, response) = tokio::join!(
+        server.run_until(async {
+            let _ = stopped.await;
+        }),
+        talk
+    );
+
+    served?;
+    response
+}
+
+#[tokio::test]
+async fn round_trip() {
+    let Some(certs) = Certs::generate("tls-round-trip") else {
+        return;
+    };
+
+    let response = with_server(certs.server(false), certs.client(None))
+        .await
+        .unwrap();
+    assert!(response.is_ok(), "{:?}", response);
+}
+
+#[tokio::test]
+async fn client_certificates() {
+    let Some(certs) = Certs::generate("tls-client-certs") else {
+        return;
+    };
+
+    let response = with_server(certs.server(true), certs.client(Some("client")))
+        .await
+        .unwrap();
+    assert!(response.is_ok(), "{:?}", response);
+
+    // Rejected by the server after the client finished its side of the handshake,
+    // the error shows on the first request
+    let err = with_server(certs.server(true), certs.client(Some("rogue")))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("UnknownCA"), "{}", err);
+
+    let err = with_server(certs.server(true), certs.client(None))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("CertificateRequired"), "{}", err);
+}
+
+#[tokio::test]
+async fn client_certificate_authentication() {
+    let Some(certs) = Certs::generate("tls-client-auth") else {
+        return;
+    };
+
+    let auth = |name: &str| {
+        Auth::from_yaml_str(&format!(
+            "tokens:\n- name: ci\n  certificate: \"{}\"\n  role: viewer",
+            certs.fingerprint(name)
+        ))
+        .unwrap()
+    };
+
+    let response = with_auth(
+        Some(auth("client")),
+        certs.server(true),
+        certs.client(Some("client")),
+    )
+    .await
+    .unwrap();
+    assert!(response.is_ok(), "{:?}", response);
+
+    // A certificate signed by the ca, but not known to the server
+    let response = with_auth(
+        Some(auth("server")),
+        certs.server(true),
+        certs.client(Some("client")),
+    )
+    .await
+    .unwrap();
+    assert!(!response.is_ok(), "{:?}", response);
+}
+
+#[tokio::test]
+async fn untrusted_server() {
+    let Some(certs) = Certs::generate("tls-untrusted-server") else {
+        return;
+    };
+
+    let mut client = certs.client(None);
+    client.ca = certs.path("rogue-ca.pem");
+
+    assert!(with_server(certs.server(false), client).await.is_err());
+}